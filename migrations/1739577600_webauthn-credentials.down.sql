@@ -0,0 +1,4 @@
+-- Write your down sql migration here
+DROP INDEX IF EXISTS "webauthn_credentials_user_id";
+
+DROP TABLE IF EXISTS "webauthn_credentials";
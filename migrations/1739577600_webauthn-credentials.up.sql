@@ -0,0 +1,15 @@
+-- Write your up sql migration here
+CREATE TABLE IF NOT EXISTS "webauthn_credentials" (
+    "id" text NOT NULL,
+    "user_id" integer NOT NULL,
+    "public_key" blob NOT NULL,
+    "sign_count" integer NOT NULL DEFAULT 0,
+    "name" text DEFAULT NULL,
+    --
+    "created_at" datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "last_used_at" datetime DEFAULT NULL,
+    PRIMARY KEY (id),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS "webauthn_credentials_user_id" ON "webauthn_credentials" (user_id);
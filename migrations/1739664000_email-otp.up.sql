@@ -0,0 +1,10 @@
+-- Write your up sql migration here
+CREATE TABLE IF NOT EXISTS "email_otp" (
+    "user_id" integer NOT NULL,
+    "code_hash" text NOT NULL,
+    "attempts" integer NOT NULL DEFAULT 0,
+    "expires_at" datetime NOT NULL,
+    "sent_at" datetime NOT NULL,
+    PRIMARY KEY (user_id),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON UPDATE CASCADE ON DELETE CASCADE
+);
@@ -0,0 +1,10 @@
+-- Write your up sql migration here
+CREATE TABLE IF NOT EXISTS "magic_link_token" (
+    "token" text NOT NULL,
+    "user_id" integer NOT NULL,
+    "expires_at" datetime NOT NULL,
+    "used_at" datetime DEFAULT NULL,
+    "nonce_hash" text DEFAULT NULL,
+    PRIMARY KEY (token),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON UPDATE CASCADE ON DELETE CASCADE
+);
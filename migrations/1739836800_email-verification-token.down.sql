@@ -0,0 +1,4 @@
+-- Write your down sql migration here
+DROP INDEX IF EXISTS "email_verification_token_user_id";
+
+DROP TABLE IF EXISTS "email_verification_token";
@@ -0,0 +1,13 @@
+-- Write your up sql migration here
+CREATE TABLE IF NOT EXISTS "email_verification_token" (
+    "token" text NOT NULL,
+    "user_id" integer NOT NULL,
+    "email" text NOT NULL,
+    "expires_at" datetime NOT NULL,
+    "used_at" datetime DEFAULT NULL,
+    "created_at" datetime NOT NULL,
+    PRIMARY KEY (token),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS "email_verification_token_user_id" ON "email_verification_token" (user_id);
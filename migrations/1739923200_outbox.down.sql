@@ -0,0 +1,4 @@
+-- Write your down sql migration here
+DROP INDEX IF EXISTS "outbox_status_next_attempt_at";
+
+DROP TABLE IF EXISTS "outbox";
@@ -0,0 +1,14 @@
+-- Write your up sql migration here
+CREATE TABLE IF NOT EXISTS "outbox" (
+    "id" integer PRIMARY KEY AUTOINCREMENT,
+    "kind" text NOT NULL,
+    "payload" text NOT NULL,
+    "status" text NOT NULL DEFAULT 'pending',
+    "attempts" integer NOT NULL DEFAULT 0,
+    "next_attempt_at" datetime NOT NULL,
+    "last_error" text DEFAULT NULL,
+    "created_at" datetime NOT NULL,
+    "delivered_at" datetime DEFAULT NULL
+);
+
+CREATE INDEX IF NOT EXISTS "outbox_status_next_attempt_at" ON "outbox" (status, next_attempt_at);
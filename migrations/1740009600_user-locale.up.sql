@@ -0,0 +1,2 @@
+-- Write your up sql migration here
+ALTER TABLE "users" ADD COLUMN "locale" text DEFAULT NULL;
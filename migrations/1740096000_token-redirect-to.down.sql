@@ -0,0 +1,6 @@
+-- Write your down sql migration here
+ALTER TABLE "email_verification_token" DROP COLUMN "redirect_to";
+
+ALTER TABLE "magic_link_token" DROP COLUMN "redirect_to";
+
+ALTER TABLE "forgot_password_token" DROP COLUMN "redirect_to";
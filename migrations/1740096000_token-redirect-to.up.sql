@@ -0,0 +1,6 @@
+-- Write your up sql migration here
+ALTER TABLE "forgot_password_token" ADD COLUMN "redirect_to" text DEFAULT NULL;
+
+ALTER TABLE "magic_link_token" ADD COLUMN "redirect_to" text DEFAULT NULL;
+
+ALTER TABLE "email_verification_token" ADD COLUMN "redirect_to" text DEFAULT NULL;
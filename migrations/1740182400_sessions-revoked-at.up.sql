@@ -0,0 +1,2 @@
+-- Write your up sql migration here
+ALTER TABLE "users" ADD COLUMN "sessions_revoked_at" integer DEFAULT NULL;
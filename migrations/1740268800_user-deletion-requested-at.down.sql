@@ -0,0 +1,2 @@
+-- Write your down sql migration here
+ALTER TABLE "users" DROP COLUMN "deletion_requested_at";
@@ -0,0 +1,2 @@
+-- Write your up sql migration here
+ALTER TABLE "users" ADD COLUMN "deletion_requested_at" integer DEFAULT NULL;
@@ -0,0 +1,2 @@
+-- Write your up sql migration here
+ALTER TABLE "users" ADD COLUMN "password_pepper" text DEFAULT NULL;
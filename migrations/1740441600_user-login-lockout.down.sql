@@ -0,0 +1,3 @@
+-- Write your down sql migration here
+ALTER TABLE "users" DROP COLUMN "locked_until";
+ALTER TABLE "users" DROP COLUMN "failed_login_attempts";
@@ -0,0 +1,3 @@
+-- Write your up sql migration here
+ALTER TABLE "users" ADD COLUMN "failed_login_attempts" integer NOT NULL DEFAULT 0;
+ALTER TABLE "users" ADD COLUMN "locked_until" integer DEFAULT NULL;
@@ -0,0 +1,16 @@
+-- Write your up sql migration here
+-- No foreign key on `user_id` - events outlive the users they are about
+CREATE TABLE IF NOT EXISTS "auth_events" (
+    "id" integer PRIMARY KEY AUTOINCREMENT,
+    "user_id" integer DEFAULT NULL,
+    "event" text NOT NULL,
+    "outcome" text NOT NULL,
+    "reason" text DEFAULT NULL,
+    "ip" text DEFAULT NULL,
+    "user_agent" text DEFAULT NULL,
+    "request_id" text DEFAULT NULL,
+    "created_at" datetime NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS "auth_events_user_id" ON "auth_events" (user_id);
+CREATE INDEX IF NOT EXISTS "auth_events_created_at" ON "auth_events" (created_at);
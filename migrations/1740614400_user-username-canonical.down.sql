@@ -0,0 +1,6 @@
+-- Write your down sql migration here
+DROP INDEX IF EXISTS "users_username_skeleton";
+DROP INDEX IF EXISTS "users_username_canonical";
+
+ALTER TABLE "users" DROP COLUMN "username_skeleton";
+ALTER TABLE "users" DROP COLUMN "username_canonical";
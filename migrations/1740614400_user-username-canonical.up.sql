@@ -0,0 +1,8 @@
+-- Write your up sql migration here
+-- Skeletons are computed by picoauth on startup
+ALTER TABLE "users" ADD COLUMN "username_canonical" text DEFAULT NULL;
+ALTER TABLE "users" ADD COLUMN "username_skeleton" text DEFAULT NULL;
+UPDATE "users" SET username_canonical = lower(username);
+
+CREATE UNIQUE INDEX IF NOT EXISTS "users_username_canonical" ON "users" (username_canonical);
+CREATE INDEX IF NOT EXISTS "users_username_skeleton" ON "users" (username_skeleton);
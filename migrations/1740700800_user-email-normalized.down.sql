@@ -0,0 +1,5 @@
+-- Write your down sql migration here
+DROP INDEX IF EXISTS "users_email_normalized";
+DROP INDEX IF EXISTS "users_email_verified";
+
+ALTER TABLE "users" DROP COLUMN "email_normalized";
@@ -0,0 +1,17 @@
+-- Write your up sql migration here
+-- SQLite only lowercases ASCII - non-ASCII emails are matched again once re-entered
+ALTER TABLE "users" ADD COLUMN "email_normalized" text DEFAULT NULL;
+UPDATE "users" SET email_normalized = lower(trim(email)) WHERE email IS NOT NULL;
+
+-- Only verified emails are unique, so that claiming an email without verifying it does not lock its owner out
+-- An email verified by several users is only kept verified for the oldest one
+UPDATE "users" SET email_verified_at = NULL
+WHERE email_verified_at IS NOT NULL AND EXISTS (
+    SELECT 1 FROM "users" other
+    WHERE other.email_normalized = "users".email_normalized
+        AND other.email_verified_at IS NOT NULL
+        AND (other.created_at < "users".created_at OR (other.created_at = "users".created_at AND other.id < "users".id))
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS "users_email_verified" ON "users" (email_normalized) WHERE email_verified_at IS NOT NULL;
+CREATE INDEX IF NOT EXISTS "users_email_normalized" ON "users" (email_normalized);
@@ -0,0 +1,9 @@
+-- Write your up sql migration here
+CREATE TABLE IF NOT EXISTS "recovery_codes" (
+    "user_id" integer NOT NULL,
+    "code_hash" text NOT NULL,
+    "used_at" datetime DEFAULT NULL,
+    "created_at" datetime NOT NULL,
+    PRIMARY KEY (user_id, code_hash),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON UPDATE CASCADE ON DELETE CASCADE
+);
@@ -7,9 +7,13 @@ CREATE TABLE schema_migrations (id VARCHAR(255) PRIMARY KEY);
 CREATE TABLE "users" (
     "id" integer,
     "username" text NOT NULL,
+    "username_canonical" text DEFAULT NULL,
+    "username_skeleton" text DEFAULT NULL,
     "password" text NOT NULL,
+    "password_pepper" text DEFAULT NULL,
     "display_name" text DEFAULT NULL,
     "email" text DEFAULT NULL,
+    "email_normalized" text DEFAULT NULL,
     --
     "totp_secret" text DEFAULT NULL,
     "totp_active_at" datetime DEFAULT NULL,
@@ -17,10 +21,19 @@ CREATE TABLE "users" (
     "requires_password_reset" integer NOT NULL DEFAULT 0,
     "requires_second_factor" integer NOT NULL DEFAULT 0,
     "email_verified_at" datetime DEFAULT NULL,
+    "locale" text DEFAULT NULL,
+    "sessions_revoked_at" integer DEFAULT NULL,
+    "deletion_requested_at" integer DEFAULT NULL,
+    "failed_login_attempts" integer NOT NULL DEFAULT 0,
+    "locked_until" integer DEFAULT NULL,
     --
     "created_at" datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
     PRIMARY KEY (id)
 ) RANDOM ROWID;
+CREATE UNIQUE INDEX "users_username_canonical" ON "users" (username_canonical);
+CREATE INDEX "users_username_skeleton" ON "users" (username_skeleton);
+CREATE UNIQUE INDEX "users_email_verified" ON "users" (email_normalized) WHERE email_verified_at IS NOT NULL;
+CREATE INDEX "users_email_normalized" ON "users" (email_normalized);
 CREATE TABLE "revoked_jwt" (
     "token" text NOT NULL,
     "revoked_at" datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
@@ -31,6 +44,84 @@ CREATE TABLE "forgot_password_token" (
     "user_id" integer NOT NULL,
     "expires_at" datetime NOT NULL,
     "used_at" datetime DEFAULT NULL,
+    "redirect_to" text DEFAULT NULL,
     PRIMARY KEY (token),
     FOREIGN KEY (user_id) REFERENCES users (id) ON UPDATE CASCADE ON DELETE CASCADE
-);
\ No newline at end of file
+);
+CREATE TABLE "webauthn_credentials" (
+    "id" text NOT NULL,
+    "user_id" integer NOT NULL,
+    "public_key" blob NOT NULL,
+    "sign_count" integer NOT NULL DEFAULT 0,
+    "name" text DEFAULT NULL,
+    --
+    "created_at" datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "last_used_at" datetime DEFAULT NULL,
+    PRIMARY KEY (id),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+CREATE INDEX "webauthn_credentials_user_id" ON "webauthn_credentials" (user_id);
+CREATE TABLE "email_otp" (
+    "user_id" integer NOT NULL,
+    "code_hash" text NOT NULL,
+    "attempts" integer NOT NULL DEFAULT 0,
+    "expires_at" datetime NOT NULL,
+    "sent_at" datetime NOT NULL,
+    PRIMARY KEY (user_id),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+CREATE TABLE "magic_link_token" (
+    "token" text NOT NULL,
+    "user_id" integer NOT NULL,
+    "expires_at" datetime NOT NULL,
+    "used_at" datetime DEFAULT NULL,
+    "nonce_hash" text DEFAULT NULL,
+    "redirect_to" text DEFAULT NULL,
+    PRIMARY KEY (token),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+CREATE TABLE "email_verification_token" (
+    "token" text NOT NULL,
+    "user_id" integer NOT NULL,
+    "email" text NOT NULL,
+    "expires_at" datetime NOT NULL,
+    "used_at" datetime DEFAULT NULL,
+    "created_at" datetime NOT NULL,
+    "redirect_to" text DEFAULT NULL,
+    PRIMARY KEY (token),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+CREATE INDEX "email_verification_token_user_id" ON "email_verification_token" (user_id);
+CREATE TABLE "outbox" (
+    "id" integer PRIMARY KEY AUTOINCREMENT,
+    "kind" text NOT NULL,
+    "payload" text NOT NULL,
+    "status" text NOT NULL DEFAULT 'pending',
+    "attempts" integer NOT NULL DEFAULT 0,
+    "next_attempt_at" datetime NOT NULL,
+    "last_error" text DEFAULT NULL,
+    "created_at" datetime NOT NULL,
+    "delivered_at" datetime DEFAULT NULL
+);
+CREATE INDEX "outbox_status_next_attempt_at" ON "outbox" (status, next_attempt_at);
+CREATE TABLE "auth_events" (
+    "id" integer PRIMARY KEY AUTOINCREMENT,
+    "user_id" integer DEFAULT NULL,
+    "event" text NOT NULL,
+    "outcome" text NOT NULL,
+    "reason" text DEFAULT NULL,
+    "ip" text DEFAULT NULL,
+    "user_agent" text DEFAULT NULL,
+    "request_id" text DEFAULT NULL,
+    "created_at" datetime NOT NULL
+);
+CREATE INDEX "auth_events_user_id" ON "auth_events" (user_id);
+CREATE INDEX "auth_events_created_at" ON "auth_events" (created_at);
+CREATE TABLE "recovery_codes" (
+    "user_id" integer NOT NULL,
+    "code_hash" text NOT NULL,
+    "used_at" datetime DEFAULT NULL,
+    "created_at" datetime NOT NULL,
+    PRIMARY KEY (user_id, code_hash),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON UPDATE CASCADE ON DELETE CASCADE
+);
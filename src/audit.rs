@@ -0,0 +1,162 @@
+// Authentication audit log
+// * Every auth handler records what happened in `auth_events` - who, what, whether it succeeded & from where
+//   * `event` names follow the webhook events, e.g. `login`, `password.reset`, `webauthn.registered`
+//   * `reason` tells failures apart, e.g. `invalid_password` or `locked` - it is never sent to the client
+//   * IP, user agent & request ID (`x-request-id`, see `SetRequestIdLayer`) come from the request
+// * Recording is best-effort - a failure to write the log is logged, but does not fail the request
+// * Events are queried through `/admin/auth_events` & kept for `AUTH_EVENTS_RETENTION`
+
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::LazyLock,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts},
+};
+use libsql::{Connection, params};
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+use tower_http::request_id::RequestId;
+use tracing::{info, warn};
+
+use crate::AppState;
+
+/// The amount of time auth events are kept for, in seconds.
+/// Configured with the `AUTH_EVENTS_RETENTION` environment variable.
+///
+/// Defaults to 90 days
+pub static RETENTION: LazyLock<Duration> = LazyLock::new(|| {
+    std::env::var("AUTH_EVENTS_RETENTION").map_or(Duration::from_secs(90 * 24 * 3600), |t| {
+        Duration::from_secs(t.parse().expect("Invalid AUTH_EVENTS_RETENTION"))
+    })
+});
+
+/// The amount of time in-between purging expired events.
+///
+/// Defaults to 1 hour
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Maximum length of a stored user agent
+const USER_AGENT_MAX_LENGTH: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+impl Outcome {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::Failure => "failure",
+        }
+    }
+}
+
+/// Where a request came from
+#[derive(Debug, Clone, Default)]
+pub struct RequestMeta {
+    /// Peer IP - absent on the Unix socket
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for RequestMeta {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string());
+        let user_agent = parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.chars().take(USER_AGENT_MAX_LENGTH).collect());
+        let request_id = parts
+            .extensions
+            .get::<RequestId>()
+            .and_then(|id| id.header_value().to_str().ok())
+            .map(str::to_string);
+
+        Ok(Self {
+            ip,
+            user_agent,
+            request_id,
+        })
+    }
+}
+
+/// Records an auth event, logging rather than failing if it cannot be written
+pub async fn record(
+    conn: &Connection,
+    meta: &RequestMeta,
+    user_id: Option<u64>,
+    event: &str,
+    outcome: Outcome,
+    reason: Option<&str>,
+) {
+    if let Err(e) = conn
+        .execute(
+            "INSERT INTO \"auth_events\" (user_id, event, outcome, reason, ip, user_agent, request_id, created_at) VALUES (CASE WHEN ? THEN ? END, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                user_id.is_some(),
+                user_id.unwrap_or_default(),
+                event,
+                outcome.as_str(),
+                reason,
+                meta.ip.clone(),
+                meta.user_agent.clone(),
+                meta.request_id.clone(),
+                UNIX_EPOCH.elapsed().unwrap().as_secs()
+            ],
+        )
+        .await
+    {
+        warn!(event, "Unable to record auth event, {e}");
+    }
+}
+
+/// Deletes the events older than the retention
+async fn purge(state: &AppState) -> Result<(), libsql::Error> {
+    let conn = state.db.connect()?;
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
+
+    let purged = conn
+        .execute(
+            "DELETE FROM \"auth_events\" WHERE created_at < ?",
+            params![current_time.saturating_sub(RETENTION.as_secs())],
+        )
+        .await?;
+
+    if purged > 0 {
+        info!("Purged {purged} expired auth event(s)");
+    }
+    Ok(())
+}
+
+/// Runs the retention worker until the cancellation token is cancelled
+pub async fn run(state: AppState, ct: CancellationToken) {
+    info!("Auth event retention worker started");
+
+    loop {
+        if let Err(e) = purge(&state).await {
+            warn!("Unable to purge expired auth events, {e}");
+        }
+
+        select! {
+            () = ct.cancelled() => {
+                info!("Caught exit signal - Shutting down auth event retention worker");
+                return;
+            }
+            () = tokio::time::sleep(PURGE_INTERVAL) => {}
+        }
+    }
+}
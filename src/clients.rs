@@ -0,0 +1,174 @@
+// Frontend clients
+// * picoauth is headless - links sent to users point at a frontend, built from per-client URL templates
+//   * `{token}` is replaced with the token, `{redirect_to}` with the (URL-encoded) redirect target
+// * The default client is configured with `FORGOT_PASSWORD_URL`, `VERIFY_EMAIL_URL`, `MAGIC_LINK_URL` & `REDIRECT_ORIGINS`
+// * Additional clients are listed in `CLIENTS` (comma-separated), e.g. `CLIENTS=admin` is configured with
+//   `CLIENT_ADMIN_FORGOT_PASSWORD_URL`, `CLIENT_ADMIN_VERIFY_EMAIL_URL`, `CLIENT_ADMIN_MAGIC_LINK_URL` & `CLIENT_ADMIN_REDIRECT_ORIGINS`
+//   * Missing URL templates fall back to the ones of the default client
+// * `redirect_to` must be an absolute URL whose origin is allowlisted for the client; it is stored alongside the
+//   token & handed back to the frontend redeeming it through the `X-Picoauth-Redirect-To` header
+
+use std::{collections::HashMap, sync::LazyLock};
+
+use axum::{
+    Json,
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::info;
+
+use crate::common::url_encode;
+
+/// Response header carrying the `redirect_to` stored with a token
+pub const REDIRECT_TO_HEADER: HeaderName = HeaderName::from_static("x-picoauth-redirect-to");
+
+/// Actions that send a link to the user
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    ForgotPassword,
+    VerifyEmail,
+    MagicLink,
+}
+
+impl Action {
+    const fn env_name(self) -> &'static str {
+        match self {
+            Self::ForgotPassword => "FORGOT_PASSWORD_URL",
+            Self::VerifyEmail => "VERIFY_EMAIL_URL",
+            Self::MagicLink => "MAGIC_LINK_URL",
+        }
+    }
+}
+
+pub struct Client {
+    forgot_password_url: String,
+    verify_email_url: String,
+    magic_link_url: String,
+    /// Origins `redirect_to` may point at, e.g. `https://app.example`
+    redirect_origins: Vec<String>,
+}
+
+impl Client {
+    fn from_env(prefix: &str, default: Option<&Self>) -> Self {
+        let url = |action: Action| {
+            std::env::var(format!("{prefix}{}", action.env_name())).unwrap_or_else(|_| {
+                default.map_or_else(
+                    || "{token}".to_string(),
+                    |client| client.template(action).to_string(),
+                )
+            })
+        };
+
+        Self {
+            forgot_password_url: url(Action::ForgotPassword),
+            verify_email_url: url(Action::VerifyEmail),
+            magic_link_url: url(Action::MagicLink),
+            redirect_origins: std::env::var(format!("{prefix}REDIRECT_ORIGINS"))
+                .unwrap_or_default()
+                .split(',')
+                .map(|origin| origin.trim().trim_end_matches('/').to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect(),
+        }
+    }
+
+    fn template(&self, action: Action) -> &str {
+        match action {
+            Action::ForgotPassword => &self.forgot_password_url,
+            Action::VerifyEmail => &self.verify_email_url,
+            Action::MagicLink => &self.magic_link_url,
+        }
+    }
+
+    /// Builds the link to be sent to the user
+    pub fn url(&self, action: Action, token: &str, redirect_to: Option<&str>) -> String {
+        self.template(action).replace("{token}", token).replace(
+            "{redirect_to}",
+            &url_encode(redirect_to.unwrap_or_default()),
+        )
+    }
+
+    /// Whether `redirect_to` is an absolute URL pointing at one of the allowed origins
+    pub fn is_allowed_redirect(&self, redirect_to: &str) -> bool {
+        let Ok(url) = Url::parse(redirect_to) else {
+            return false;
+        };
+        if !matches!(url.scheme(), "http" | "https") {
+            return false;
+        }
+
+        let origin = url.origin().ascii_serialization();
+        self.redirect_origins.contains(&origin)
+    }
+}
+
+static DEFAULT_CLIENT: LazyLock<Client> = LazyLock::new(|| Client::from_env("", None));
+static CLIENTS: LazyLock<HashMap<String, Client>> = LazyLock::new(|| {
+    let clients: HashMap<String, Client> = std::env::var("CLIENTS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let prefix = format!("CLIENT_{}_", name.to_uppercase().replace('-', "_"));
+            (
+                name.to_string(),
+                Client::from_env(&prefix, Some(&DEFAULT_CLIENT)),
+            )
+        })
+        .collect();
+
+    if !clients.is_empty() {
+        info!("Configured {} additional client(s)", clients.len());
+    }
+    clients
+});
+
+/// Optional fields accepted by requests that send a link to the user
+#[derive(Debug, Default, Deserialize)]
+pub struct LinkOptionsDto {
+    /// Name of the client the link should point at, defaults to the default client
+    pub client: Option<String>,
+    /// Where the frontend should send the user to once the link has been used
+    pub redirect_to: Option<String>,
+}
+
+impl LinkOptionsDto {
+    /// Resolves the client & validates `redirect_to` against its allowlist
+    pub fn resolve(&self) -> Result<(&'static Client, Option<String>), Response> {
+        let client = match &self.client {
+            Some(name) => CLIENTS.get(name).ok_or_else(|| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({ "error": "Unknown client" })),
+                )
+                    .into_response()
+            })?,
+            None => &DEFAULT_CLIENT,
+        };
+
+        if let Some(redirect_to) = &self.redirect_to
+            && !client.is_allowed_redirect(redirect_to)
+        {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "Invalid redirect_to - Origin is not allowed" })),
+            )
+                .into_response());
+        }
+
+        Ok((client, self.redirect_to.clone()))
+    }
+}
+
+/// Headers handing the stored `redirect_to` back to the frontend, if there is one
+pub fn redirect_header(redirect_to: Option<String>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(value) = redirect_to.and_then(|r| HeaderValue::try_from(r).ok()) {
+        headers.insert(REDIRECT_TO_HEADER, value);
+    }
+    headers
+}
@@ -1,11 +1,15 @@
-use std::sync::{
-    Arc, LazyLock,
-    atomic::{AtomicU64, Ordering},
+use std::{
+    fmt::Write,
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
 };
 
 use axum::{Json, extract::Request, http::StatusCode};
-use regex::Regex;
+use rand::{distr, prelude::*};
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use tower_http::request_id::{MakeRequestId, RequestId};
 
 #[derive(Clone, Default)]
@@ -26,8 +30,60 @@ impl MakeRequestId for RequestIdCounter {
     }
 }
 
-pub static USERNAME_REGEX: LazyLock<Regex> =
-    LazyLock::new(|| Regex::new(r"^[a-zA-Z0-9_]{3,32}$").unwrap());
+/// Parses the given environment variable, falling back to `default` if it is not set
+///
+/// Panics if the variable is set but cannot be parsed
+pub fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name).map_or(default, |value| {
+        value.parse().unwrap_or_else(|_| panic!("Invalid {name}"))
+    })
+}
+
+/// Generates a long, crypto-safe token to be used in URLs (password reset, magic links, etc.)
+pub fn generate_url_token() -> String {
+    // Initialize new RNG every time function gets called
+    // This hopefully ensures that forward secrecy is maintained
+    let rng = rand::rngs::StdRng::from_os_rng();
+    rng.sample_iter(distr::Alphanumeric)
+        .take(64)
+        .map(char::from)
+        .collect()
+}
+
+/// Lowercase hex encoding of the given bytes
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+            write!(hex, "{byte:02x}").unwrap();
+            hex
+        })
+}
+
+/// Hex-encoded SHA-256 digest of the given data
+pub fn sha256_hex(data: impl AsRef<[u8]>) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
+/// Percent-encodes everything but the unreserved characters of RFC 3986
+pub fn url_encode(data: &str) -> String {
+    data.bytes().fold(String::new(), |mut encoded, byte| {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            write!(encoded, "%{byte:02X}").unwrap();
+        }
+        encoded
+    })
+}
+
+/// SQLite extended result code of a violated `UNIQUE` constraint
+const SQLITE_CONSTRAINT_UNIQUE: i32 = 2067;
+
+/// Whether the error comes from a violated unique index, e.g. when a concurrent request took the same username
+pub fn is_unique_violation(e: &libsql::Error) -> bool {
+    matches!(e, libsql::Error::SqliteFailure(code, _) if *code == SQLITE_CONSTRAINT_UNIQUE)
+}
 
 pub static DATABASE_BUSY_RESPONSE: LazyLock<(
     axum::http::StatusCode,
@@ -50,3 +106,86 @@ pub static INVALID_USERNAME_PASSWORD_RESPONSE: LazyLock<(
         Json(json!({ "error": "Invalid username or password" })),
     )
 });
+
+pub static BREACHED_PASSWORD_RESPONSE: LazyLock<(
+    axum::http::StatusCode,
+    axum::Json<serde_json::Value>,
+)> = LazyLock::new(|| {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({
+            "error": "Breached password - Password has appeared in a data breach, please choose another one",
+            "code": "password_breached",
+        })),
+    )
+});
+
+/// Application state backed by a fresh database, for handler tests
+#[cfg(test)]
+pub async fn test_state() -> crate::AppState {
+    use std::sync::Arc;
+
+    // SAFETY: every test sets the same value
+    unsafe {
+        std::env::set_var(
+            "JWT_SECRET",
+            "dGVzdC1zZWNyZXQtdGVzdC1zZWNyZXQtdGVzdC1zZWNyZXQ=",
+        );
+    }
+
+    // Every connection to `:memory:` opens a database of its own, a file is shared by the connections
+    static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "picoauth-test-{}-{}.db",
+        std::process::id(),
+        COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+    ));
+    std::fs::remove_file(&path).ok();
+
+    let db = libsql::Builder::new_local(path).build().await.unwrap();
+    db.connect()
+        .unwrap()
+        .execute_batch(include_str!("../migrations/schema.sql"))
+        .await
+        .unwrap();
+
+    crate::AppState {
+        db: Arc::new(db),
+        notifier: Arc::new(crate::notification::stdout::StdoutNotifier),
+        webhooks: Arc::new(crate::webhook::Webhooks::from_env()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn detects_unique_violations() {
+        let db = libsql::Builder::new_local(":memory:")
+            .build()
+            .await
+            .unwrap();
+        let conn = db.connect().unwrap();
+        conn.execute("CREATE TABLE t (v text UNIQUE)", ())
+            .await
+            .unwrap();
+        conn.execute("INSERT INTO t (v) VALUES ('a')", ())
+            .await
+            .unwrap();
+
+        // Reported by the first step with `RETURNING`, not by the query itself
+        let mut rows = conn
+            .query("INSERT INTO t (v) VALUES ('a') RETURNING v", ())
+            .await
+            .unwrap();
+        let e = rows.next().await.unwrap_err();
+        assert!(is_unique_violation(&e), "{e:?}");
+
+        let e = conn
+            .execute("INSERT INTO missing (v) VALUES ('a')", ())
+            .await
+            .unwrap_err();
+        assert!(!is_unique_violation(&e));
+    }
+}
@@ -0,0 +1,101 @@
+// Self-service account deletion
+// * Deleting an account only marks it as pending deletion & revokes all of its sessions
+// * The account is kept around for `ACCOUNT_DELETION_GRACE_PERIOD`, during which the user may log in & cancel it
+// * A background worker hard-deletes accounts once their grace period is over
+//   * Rows referencing the user (tokens, credentials, ...) are removed by `ON DELETE CASCADE`
+//   * A `user.deleted` event is queued for every purged account
+
+use std::{
+    sync::LazyLock,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use libsql::params;
+use serde_json::json;
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{AppState, outbox, webhook::Event};
+
+/// The amount of time an account pending deletion is kept around for, in seconds.
+/// Configured with the `ACCOUNT_DELETION_GRACE_PERIOD` environment variable.
+///
+/// Defaults to 30 days
+pub static GRACE_PERIOD: LazyLock<Duration> = LazyLock::new(|| {
+    std::env::var("ACCOUNT_DELETION_GRACE_PERIOD")
+        .map_or(Duration::from_secs(30 * 24 * 3600), |t| {
+            Duration::from_secs(t.parse().expect("Invalid ACCOUNT_DELETION_GRACE_PERIOD"))
+        })
+});
+
+/// The amount of time in-between checking for accounts to purge.
+///
+/// Defaults to 10 minutes
+const PURGE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Time at which an account whose deletion has been requested at `requested_at` gets purged
+pub fn purge_at(requested_at: u64) -> u64 {
+    requested_at + GRACE_PERIOD.as_secs()
+}
+
+/// Hard-deletes every account whose grace period is over
+async fn purge(state: &AppState) -> Result<(), libsql::Error> {
+    let conn = state.db.connect()?;
+    // Cascades are only enforced with foreign keys enabled on the connection
+    conn.execute("PRAGMA foreign_keys = ON", ()).await?;
+
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
+    let txn = conn.transaction().await?;
+
+    let mut rows = txn
+        .query(
+            "DELETE FROM \"users\" WHERE deletion_requested_at <= ? RETURNING id, username, email",
+            params![current_time.saturating_sub(GRACE_PERIOD.as_secs())],
+        )
+        .await?;
+
+    let mut events = Vec::new();
+    while let Some(row) = rows.next().await? {
+        events.push(Event::new(
+            "user.deleted",
+            json!({
+                "user_id": row.get::<u64>(0)?.to_string(),
+                "username": row.get::<String>(1)?,
+                "email": row.get::<Option<String>>(2)?,
+            }),
+        ));
+    }
+    drop(rows);
+
+    let purged = events.len();
+    for event in events {
+        outbox::enqueue_event(&txn, &state.webhooks, event).await?;
+    }
+
+    txn.commit().await?;
+
+    if purged > 0 {
+        info!("Purged {purged} account(s) pending deletion");
+    }
+    Ok(())
+}
+
+/// Runs the purge worker until the cancellation token is cancelled
+pub async fn run(state: AppState, ct: CancellationToken) {
+    info!("Account deletion worker started");
+
+    loop {
+        if let Err(e) = purge(&state).await {
+            warn!("Unable to purge accounts pending deletion, {e}");
+        }
+
+        select! {
+            () = ct.cancelled() => {
+                info!("Caught exit signal - Shutting down account deletion worker");
+                return;
+            }
+            () = tokio::time::sleep(PURGE_INTERVAL) => {}
+        }
+    }
+}
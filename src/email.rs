@@ -0,0 +1,104 @@
+// Email addresses
+// * Emails are stored as entered (`email`) & in their normalized form (`email_normalized`)
+//   * Only checked for a single `@` with something on both sides - whether the address exists is what verification
+//     is for. Usernames never contain an `@`, so that an email can never be mistaken for a username
+//   * Normalization trims & lowercases the whole address - provider-specific rules (dots or `+tags` of Gmail) are not
+//     applied, as they do not hold for every domain
+//   * The normalized form is what is looked up - it is unique across users once verified
+//     * Unverified emails may be shared, so that claiming someone else's email does not lock its owner out
+//     * Whoever verifies it first keeps it, other users then get a 409 when verifying it
+// * Login & forgot password accept an email in place of the username
+//   * Only verified emails are accepted, unless `LOGIN_UNVERIFIED_EMAILS` is set - anyone can claim an unverified
+//     email, it does not prove who the user is
+
+use std::sync::LazyLock;
+
+use libsql::{Connection, params};
+
+/// Whether unverified emails can be used in place of the username.
+/// Opt-in by setting the `LOGIN_UNVERIFIED_EMAILS` environment variable.
+pub static LOGIN_UNVERIFIED_EMAILS: LazyLock<bool> =
+    LazyLock::new(|| std::env::var("LOGIN_UNVERIFIED_EMAILS").is_ok());
+
+/// Maximum length of an email, as per RFC 5321
+const MAX_LENGTH: usize = 254;
+
+/// Whether the email looks like an address, see the top of this file
+pub fn is_valid(email: &str) -> bool {
+    let email = email.trim();
+    let Some((local_part, domain)) = email.split_once('@') else {
+        return false;
+    };
+
+    !local_part.is_empty()
+        && !domain.is_empty()
+        && !domain.contains('@')
+        && !email.chars().any(char::is_whitespace)
+        && email.len() <= MAX_LENGTH
+}
+
+/// Normalized form of an email, to be compared with `email_normalized`
+pub fn normalize(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Whether the email has already been verified by another user than `user_id`
+pub async fn is_taken(
+    conn: &Connection,
+    email: &str,
+    user_id: Option<u64>,
+) -> Result<bool, libsql::Error> {
+    let mut rows = conn
+        .query(
+            "SELECT COUNT(*) FROM \"users\" WHERE email_normalized = ? AND email_verified_at IS NOT NULL AND (? OR id != ?)",
+            params![
+                normalize(email),
+                user_id.is_none(),
+                user_id.unwrap_or_default()
+            ],
+        )
+        .await?;
+
+    let count = match rows.next().await? {
+        Some(row) => row.get::<u64>(0)?,
+        None => 0,
+    };
+    Ok(count > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_case_and_whitespace() {
+        assert_eq!(normalize(" Alice@Example.COM\n"), "alice@example.com");
+    }
+
+    #[test]
+    fn accepts_addresses() {
+        assert!(is_valid("alice@example.com"));
+        assert!(is_valid(" a.lice+tag@example.co.uk "));
+        assert!(is_valid("alice@localhost"));
+    }
+
+    #[test]
+    fn rejects_anything_else() {
+        for email in [
+            "alice",
+            "@example.com",
+            "alice@",
+            "alice@@example.com",
+            "a@b@c",
+            "al ice@example.com",
+        ] {
+            assert!(!is_valid(email), "{email} is valid");
+        }
+        assert!(!is_valid(&format!("{}@example.com", "a".repeat(250))));
+    }
+
+    #[test]
+    fn keeps_provider_specific_characters() {
+        assert_eq!(normalize("a.lice+tag@gmail.com"), "a.lice+tag@gmail.com");
+    }
+}
@@ -0,0 +1,38 @@
+use std::{sync::LazyLock, time::Duration};
+
+use rand::{Rng, SeedableRng};
+
+use crate::common::sha256_hex;
+
+/// Whether email one-time codes may be used as a second factor.
+/// Opt-in by setting the `EMAIL_OTP` environment variable.
+pub static ENABLED: LazyLock<bool> = LazyLock::new(|| std::env::var("EMAIL_OTP").is_ok());
+
+/// The amount of time that an email code is redeemable.
+///
+/// Defaults to 10 minutes
+pub const EMAIL_OTP_DURATION: Duration = Duration::from_secs(10 * 60);
+/// The maximum amount of attempts to redeem an email code before it is discarded.
+///
+/// Defaults to 5
+pub const EMAIL_OTP_MAX_ATTEMPTS: u64 = 5;
+/// The minimum amount of time in-between sending email codes to the same user.
+/// Used to prevent spam on the same user
+///
+/// Defaults to 1 minute
+pub const EMAIL_OTP_TIME_BETWEEN: Duration = Duration::from_secs(60);
+
+pub fn generate_code() -> String {
+    // Initialize new RNG every time function gets called
+    // This hopefully ensures that forward secrecy is maintained
+    let mut rng = rand::rngs::StdRng::from_os_rng();
+    format!("{:06}", rng.random_range(0..1_000_000))
+}
+
+/// Hashes a code for storage
+///
+/// The code space is tiny, so this only prevents codes from being read off the database at a glance.
+/// Short expiry & the attempt counter are what keep the codes from being guessed.
+pub fn hash_code(user_id: u64, code: &str) -> String {
+    sha256_hex(format!("{user_id}:{code}"))
+}
@@ -0,0 +1,119 @@
+// Bounded pool for password hashing
+// * Argon2 is CPU & memory heavy by design (19 MiB per hash by default) - an unbounded burst of logins would
+//   exhaust both, so every hash & verification goes through this pool
+// * At most `HASHING_CONCURRENCY` hashes run at once, up to `HASHING_QUEUE_SIZE` more wait for their turn
+//   * Past that, requests are turned away right away with a 503 & `Retry-After`, rather than piling up
+// * Queue depth, in-progress & rejected counts are exposed at `/admin/metrics`
+
+use std::sync::{
+    LazyLock,
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use axum::{
+    Json,
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
+
+use crate::common::env_or;
+
+/// Maximum amount of hashes computed at once.
+///
+/// Defaults to the amount of available CPUs
+pub static CONCURRENCY: LazyLock<usize> = LazyLock::new(|| {
+    let cpus = std::thread::available_parallelism().map_or(1, usize::from);
+    env_or("HASHING_CONCURRENCY", cpus).max(1)
+});
+
+/// Maximum amount of hashes waiting for a slot.
+///
+/// Defaults to 32
+pub static QUEUE_SIZE: LazyLock<usize> = LazyLock::new(|| env_or("HASHING_QUEUE_SIZE", 32));
+
+/// `Retry-After` sent when the queue is full, in seconds.
+///
+/// Defaults to 1 second
+const RETRY_AFTER: u64 = 1;
+
+static SLOTS: LazyLock<Semaphore> = LazyLock::new(|| {
+    info!(
+        "Hashing up to {} password(s) at once, with up to {} queued",
+        *CONCURRENCY, *QUEUE_SIZE
+    );
+    Semaphore::new(*CONCURRENCY)
+});
+
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+
+/// Leaves the queue once dropped, even if the caller gave up while waiting
+struct QueueSpot;
+
+impl Drop for QueueSpot {
+    fn drop(&mut self) {
+        QUEUED.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The hashing queue is full
+#[derive(Debug, Clone, Copy)]
+pub struct Busy;
+
+impl IntoResponse for Busy {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, RETRY_AFTER.to_string())],
+            Json(json!({ "error": "Server is busy - Please try again in a couple of seconds" })),
+        )
+            .into_response()
+    }
+}
+
+/// Runs a hashing job on the blocking pool once a slot is free
+///
+/// Fails right away if the queue is full
+pub async fn run<T, F>(job: F) -> Result<T, Busy>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    if QUEUED.fetch_add(1, Ordering::Relaxed) >= *QUEUE_SIZE {
+        QUEUED.fetch_sub(1, Ordering::Relaxed);
+        REJECTED.fetch_add(1, Ordering::Relaxed);
+        warn!("Hashing queue is full, turning request away");
+        return Err(Busy);
+    }
+    let queued = QueueSpot;
+    let slot = SLOTS.acquire().await.expect("Hashing pool closed");
+    drop(queued);
+
+    // The slot moves along with the job, so it is held until the hash is done even if the caller is gone
+    let result = tokio::task::spawn_blocking(move || {
+        let _slot = slot;
+        job()
+    })
+    .await
+    .unwrap();
+
+    Ok(result)
+}
+
+/// Amount of hashes waiting for a slot
+pub fn queue_depth() -> usize {
+    QUEUED.load(Ordering::Relaxed)
+}
+
+/// Amount of hashes being computed
+pub fn in_progress() -> usize {
+    *CONCURRENCY - SLOTS.available_permits()
+}
+
+/// Amount of hashes turned away since startup
+pub fn rejected() -> u64 {
+    REJECTED.load(Ordering::Relaxed)
+}
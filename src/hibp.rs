@@ -0,0 +1,242 @@
+// Breached password checking against Have I Been Pwned's Pwned Passwords
+// * Offline-first - `HIBP_DATASET` points at a local copy of the SHA-1 dataset, either
+//   * a text file of `SHA1:COUNT` lines sorted by hash (as produced by the PwnedPasswordsDownloader), searched in place
+//   * a SQLite database (`.sqlite` / `.db`) with a `"hibp" ("hash" text PRIMARY KEY, "count" integer)` table
+// * Online lookups through the k-anonymity range API are opt-in with `HIBP_ONLINE`
+//   * Only the first 5 hex characters of the hash ever leave the server
+// * Checking fails open - an unreadable dataset / unreachable API is logged but does not prevent setting a password
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::LazyLock,
+    time::Duration,
+};
+
+use libsql::{Builder, Database, params};
+use sha1::{Digest, Sha1};
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
+
+use crate::common::to_hex;
+
+/// Local copy of the Pwned Passwords SHA-1 dataset
+static DATASET: LazyLock<Option<PathBuf>> = LazyLock::new(|| {
+    let path = std::env::var("HIBP_DATASET").ok().map(PathBuf::from)?;
+    info!(
+        "Checking passwords against the HIBP dataset at {}",
+        path.display()
+    );
+    Some(path)
+});
+/// Whether passwords not found in the local dataset are looked up through the HIBP API.
+/// Opt-in by setting the `HIBP_ONLINE` environment variable.
+static ONLINE: LazyLock<bool> = LazyLock::new(|| std::env::var("HIBP_ONLINE").is_ok());
+/// The amount of breaches a password needs to have appeared in to be rejected.
+/// Configured with the `HIBP_MIN_COUNT` environment variable.
+///
+/// Defaults to 1
+static MIN_COUNT: LazyLock<u64> = LazyLock::new(|| {
+    std::env::var("HIBP_MIN_COUNT")
+        .map_or(1, |count| count.parse().expect("Invalid HIBP_MIN_COUNT"))
+});
+
+static SQLITE_DATASET: OnceCell<Database> = OnceCell::const_new();
+static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .timeout(HIBP_TIMEOUT)
+        .user_agent("picoauth")
+        .build()
+        .expect("Unable to create HIBP HTTP client")
+});
+
+const HIBP_RANGE_API: &str = "https://api.pwnedpasswords.com/range";
+/// The maximum amount of time to wait for the HIBP API.
+///
+/// Defaults to 5 seconds
+const HIBP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reads the first complete line starting at or after `offset`
+fn line_at(file: &mut File, offset: u64) -> io::Result<Option<String>> {
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+
+    // Unless at the very start, skip the rest of the line `offset` lands in
+    reader.seek(SeekFrom::Start(offset.saturating_sub(1)))?;
+    if offset > 0 {
+        reader.read_line(&mut line)?;
+        line.clear();
+    }
+
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(line.trim_end().to_string()))
+}
+
+/// Binary searches the sorted dataset file for the given uppercase hash
+fn file_count(path: &Path, hash: &str) -> io::Result<Option<u64>> {
+    let mut file = File::open(path)?;
+    let line_hash = |line: &str| line.split(':').next().unwrap_or_default().to_uppercase();
+
+    // Find the smallest offset whose next line is not lower than the hash
+    let (mut low, mut high) = (0, file.metadata()?.len());
+    while low < high {
+        let mid = low + (high - low) / 2;
+        match line_at(&mut file, mid)? {
+            Some(line) if line_hash(&line).as_str() < hash => low = mid + 1,
+            _ => high = mid,
+        }
+    }
+
+    Ok(line_at(&mut file, low)?
+        .filter(|line| line_hash(line) == hash)
+        .and_then(|line| line.split_once(':')?.1.trim().parse().ok()))
+}
+
+async fn sqlite_count(path: &Path, hash: &str) -> Result<Option<u64>, libsql::Error> {
+    let database = SQLITE_DATASET
+        .get_or_try_init(|| Builder::new_local(path).build())
+        .await?;
+    let conn = database.connect()?;
+
+    let mut rows = conn
+        .query("SELECT count FROM \"hibp\" WHERE hash = ?", params![hash])
+        .await?;
+    match rows.next().await? {
+        Some(row) => Ok(Some(row.get::<u64>(0)?)),
+        None => Ok(None),
+    }
+}
+
+async fn online_count(hash: &str) -> Result<Option<u64>, reqwest::Error> {
+    let (prefix, suffix) = hash.split_at(5);
+
+    // Padding hides the amount of suffixes sharing the prefix, padded entries have a count of 0
+    let body = CLIENT
+        .get(format!("{HIBP_RANGE_API}/{prefix}"))
+        .header("Add-Padding", "true")
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+
+    Ok(body.lines().find_map(|line| {
+        let (line_suffix, count) = line.trim().split_once(':')?;
+        if line_suffix.eq_ignore_ascii_case(suffix) {
+            count.parse().ok()
+        } else {
+            None
+        }
+    }))
+}
+
+/// The amount of breaches the password has appeared in, as far as we know
+pub async fn breach_count(password: &str) -> Option<u64> {
+    let hash = to_hex(&Sha1::digest(password)).to_uppercase();
+
+    if let Some(path) = DATASET.as_ref() {
+        let is_sqlite = matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("sqlite" | "db")
+        );
+        let count = if is_sqlite {
+            sqlite_count(path, &hash).await.map_err(|e| e.to_string())
+        } else {
+            let (path, hash) = (path.clone(), hash.clone());
+            tokio::task::spawn_blocking(move || file_count(&path, &hash))
+                .await
+                .unwrap()
+                .map_err(|e| e.to_string())
+        };
+
+        match count {
+            Ok(Some(count)) => return Some(count),
+            Ok(None) => {}
+            Err(e) => warn!("Unable to search the HIBP dataset, {e}"),
+        }
+    }
+
+    if *ONLINE {
+        match online_count(&hash).await {
+            Ok(count) => return count,
+            Err(e) => warn!("Unable to query the HIBP API, {e}"),
+        }
+    }
+
+    None
+}
+
+/// Whether the password has appeared in enough breaches to be rejected
+pub async fn is_breached(password: &str) -> bool {
+    breach_count(password)
+        .await
+        .is_some_and(|count| count >= *MIN_COUNT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// Writes a dataset file with the given lines, in the temporary directory
+    fn dataset(name: &str, lines: &[&str]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("picoauth-hibp-{}-{name}", std::process::id()));
+        std::fs::write(&path, lines.join("\r\n")).unwrap();
+        path
+    }
+
+    #[test]
+    fn finds_every_line() {
+        let lines: Vec<String> = (0..200_u32)
+            .map(|i| format!("{:040X}:{}", u64::from(i) * 7919, i + 1))
+            .collect();
+        let path = dataset(
+            "every",
+            &lines.iter().map(String::as_str).collect::<Vec<_>>(),
+        );
+
+        for (i, line) in lines.iter().enumerate() {
+            let hash = line.split(':').next().unwrap();
+            assert_eq!(file_count(&path, hash).unwrap(), Some(i as u64 + 1));
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn misses_absent_hashes() {
+        let path = dataset(
+            "absent",
+            &[
+                "0000000000000000000000000000000000000010:3",
+                "00000000000000000000000000000000000000A0:5",
+            ],
+        );
+
+        for hash in [
+            "0000000000000000000000000000000000000000",
+            "0000000000000000000000000000000000000011",
+            "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF",
+        ] {
+            assert_eq!(file_count(&path, hash).unwrap(), None);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn matches_lowercase_dataset() {
+        let path = dataset(
+            "lowercase",
+            &["00000000000000000000000000000000000000ab:12\n"],
+        );
+        assert_eq!(
+            file_count(&path, "00000000000000000000000000000000000000AB").unwrap(),
+            Some(12)
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
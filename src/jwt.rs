@@ -16,6 +16,8 @@ pub static DECODING_KEY: LazyLock<DecodingKey> = LazyLock::new(|| {
 
 const REFRESH_TOKEN_EXPIRATION: usize = 604_800; // 1 Week
 const ACCESS_TOKEN_EXPIRATION: usize = 3_600; // 1 Hour
+const CEREMONY_TOKEN_EXPIRATION: usize = 300; // 5 Minutes
+const MFA_TOKEN_EXPIRATION: usize = 300; // 5 Minutes
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RefreshClaims {
@@ -45,8 +47,33 @@ pub struct Claims {
     pub email_verified: Option<bool>,
 }
 
-pub fn issue_refresh_token(user_id: u64, auth_time: Option<usize>) -> Box<str> {
-    let utc = UNIX_EPOCH.elapsed().unwrap().as_secs() as usize;
+/// Claims for short-lived tokens carrying the state of a multi-step ceremony (e.g. WebAuthn)
+/// back to the client, so that the server does not need to store it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CeremonyClaims {
+    pub typ: String,         // Ceremony type, e.g. `WebauthnRegistration`
+    pub iat: usize,          // Issued at (as UTC timestamp seconds)
+    pub exp: usize,          // Expiration time (as UTC timestamp seconds)
+    pub iss: String,         // Issuer
+    pub sub: Option<String>, // Subject - User ID, if the user is already known
+    pub challenge: String,   // Challenge given to the client (base64url)
+}
+
+/// Claims for the token handed out after the first factor succeeded, to be exchanged
+/// (along with a second factor) for the final tokens
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MfaClaims {
+    pub typ: String,      // Must be `Mfa`
+    pub auth_time: usize, // Time when the first factor authentication occurred
+    pub iat: usize,       // Issued at (as UTC timestamp seconds)
+    pub exp: usize,       // Expiration time (as UTC timestamp seconds)
+    pub iss: String,      // Issuer
+    pub sub: String,      // Subject - User ID
+}
+
+/// `issued_at` is the issue time of the token (as UTC timestamp seconds), see `session::issued_at`
+pub fn issue_refresh_token(user_id: u64, auth_time: Option<usize>, issued_at: usize) -> Box<str> {
+    let utc = issued_at;
 
     let claims = RefreshClaims {
         // 1 Week
@@ -70,8 +97,9 @@ pub fn issue_access_token(
     email: Option<&str>,
     email_verified: Option<bool>,
     auth_time: usize,
+    issued_at: usize,
 ) -> Box<str> {
-    let utc = UNIX_EPOCH.elapsed().unwrap().as_secs() as usize;
+    let utc = issued_at;
 
     let claims = Claims {
         typ: "Access".to_string(),
@@ -92,10 +120,86 @@ pub fn issue_access_token(
     jwtstring.into_boxed_str()
 }
 
+/// Verifies an access token, rejecting tokens of any other type (refresh, MFA, ...)
 pub fn verify_access_token(token: &str) -> jsonwebtoken::errors::Result<TokenData<Claims>> {
-    jsonwebtoken::decode::<Claims>(token, &DECODING_KEY, &Validation::default())
+    let token_data = jsonwebtoken::decode::<Claims>(token, &DECODING_KEY, &Validation::default())?;
+
+    if token_data.claims.typ != "Access" {
+        return Err(jsonwebtoken::errors::ErrorKind::InvalidToken.into());
+    }
+
+    Ok(token_data)
 }
 
+/// Verifies a refresh token, rejecting tokens of any other type - an `mfa_token` shares the same
+/// claims & would otherwise skip the second factor
 pub fn verify_refresh_token(token: &str) -> jsonwebtoken::errors::Result<TokenData<RefreshClaims>> {
-    jsonwebtoken::decode::<RefreshClaims>(token, &DECODING_KEY, &Validation::default())
+    let token_data =
+        jsonwebtoken::decode::<RefreshClaims>(token, &DECODING_KEY, &Validation::default())?;
+
+    if token_data.claims.typ != "Refresh" {
+        return Err(jsonwebtoken::errors::ErrorKind::InvalidToken.into());
+    }
+
+    Ok(token_data)
+}
+
+pub fn issue_ceremony_token(typ: &str, user_id: Option<u64>, challenge: &str) -> Box<str> {
+    let utc = UNIX_EPOCH.elapsed().unwrap().as_secs() as usize;
+
+    let claims = CeremonyClaims {
+        typ: typ.to_string(),
+        exp: utc + CEREMONY_TOKEN_EXPIRATION,
+        iat: utc,
+        iss: "picoauth".to_string(),
+        sub: user_id.map(|id| id.to_string()),
+        challenge: challenge.to_string(),
+    };
+
+    let jwtstring =
+        jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &SECRET_KEY).unwrap();
+    jwtstring.into_boxed_str()
+}
+
+/// Verifies a ceremony token, additionally ensuring that it is of the expected type
+pub fn verify_ceremony_token(
+    token: &str,
+    typ: &str,
+) -> jsonwebtoken::errors::Result<TokenData<CeremonyClaims>> {
+    let token_data =
+        jsonwebtoken::decode::<CeremonyClaims>(token, &DECODING_KEY, &Validation::default())?;
+
+    if token_data.claims.typ != typ {
+        return Err(jsonwebtoken::errors::ErrorKind::InvalidToken.into());
+    }
+
+    Ok(token_data)
+}
+
+pub fn issue_mfa_token(user_id: u64, auth_time: usize) -> Box<str> {
+    let utc = UNIX_EPOCH.elapsed().unwrap().as_secs() as usize;
+
+    let claims = MfaClaims {
+        typ: "Mfa".to_string(),
+        exp: utc + MFA_TOKEN_EXPIRATION,
+        iat: utc,
+        iss: "picoauth".to_string(),
+        sub: user_id.to_string(),
+        auth_time,
+    };
+
+    let jwtstring =
+        jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &SECRET_KEY).unwrap();
+    jwtstring.into_boxed_str()
+}
+
+pub fn verify_mfa_token(token: &str) -> jsonwebtoken::errors::Result<TokenData<MfaClaims>> {
+    let token_data =
+        jsonwebtoken::decode::<MfaClaims>(token, &DECODING_KEY, &Validation::default())?;
+
+    if token_data.claims.typ != "Mfa" {
+        return Err(jsonwebtoken::errors::ErrorKind::InvalidToken.into());
+    }
+
+    Ok(token_data)
 }
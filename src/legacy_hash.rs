@@ -0,0 +1,186 @@
+// Verification of password hashes imported from other systems
+// * Formats are identified by their PHC / modular-crypt prefix:
+//   * bcrypt - `$2a$`, `$2b$`, `$2x$` & `$2y$`
+//   * scrypt - `$scrypt$` (PHC)
+//   * PBKDF2 - `$pbkdf2-sha256$`, `$pbkdf2-sha512$` & `$pbkdf2$` (PHC)
+//   * Django - `pbkdf2_sha256$`, `pbkdf2_sha1$`, `bcrypt_sha256$`, `bcrypt$`, `scrypt$` & `argon2$`
+// * Legacy hashes are verified against the password as-is - no normalization, no pepper
+// * Any legacy hash is upgraded to Argon2id on the next successful login, see `password::check`
+
+use argon2::password_hash::{PasswordHash, PasswordVerifier};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use pbkdf2::{Pbkdf2, pbkdf2_hmac};
+use scrypt::Scrypt;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::common::to_hex;
+
+/// Compares two byte strings in constant time (with regards to their content)
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn verify_phc(password: &str, hash: &str, verifier: &dyn PasswordVerifier) -> Option<bool> {
+    let hash = PasswordHash::new(hash).ok()?;
+    Some(verifier.verify_password(password.as_bytes(), &hash).is_ok())
+}
+
+/// `pbkdf2_<digest>$<iterations>$<salt>$<base64 key>`
+fn verify_django_pbkdf2(password: &str, fields: &str, sha1: bool) -> Option<bool> {
+    let mut fields = fields.splitn(3, '$');
+    let iterations = fields.next()?.parse().ok()?;
+    let salt = fields.next()?;
+    let expected = BASE64_STANDARD.decode(fields.next()?).ok()?;
+
+    let mut key = vec![0; expected.len()];
+    if sha1 {
+        pbkdf2_hmac::<Sha1>(password.as_bytes(), salt.as_bytes(), iterations, &mut key);
+    } else {
+        pbkdf2_hmac::<Sha256>(password.as_bytes(), salt.as_bytes(), iterations, &mut key);
+    }
+
+    Some(constant_time_eq(&key, &expected))
+}
+
+/// `scrypt$<salt>$<n>$<r>$<p>$<base64 key>`
+fn verify_django_scrypt(password: &str, fields: &str) -> Option<bool> {
+    let mut fields = fields.splitn(5, '$');
+    let salt = fields.next()?;
+    let n = fields.next()?.parse::<u64>().ok()?;
+    let r = fields.next()?.parse().ok()?;
+    let p = fields.next()?.parse().ok()?;
+    let expected = BASE64_STANDARD.decode(fields.next()?).ok()?;
+
+    if !n.is_power_of_two() {
+        return None;
+    }
+    let log_n = u8::try_from(n.trailing_zeros()).ok()?;
+    let params = scrypt::Params::new(log_n, r, p, expected.len()).ok()?;
+
+    let mut key = vec![0; expected.len()];
+    scrypt::scrypt(password.as_bytes(), salt.as_bytes(), &params, &mut key).ok()?;
+    Some(constant_time_eq(&key, &expected))
+}
+
+/// Prefixes of the supported legacy formats
+const PREFIXES: &[&str] = &[
+    "$2a$",
+    "$2b$",
+    "$2x$",
+    "$2y$",
+    "$scrypt$",
+    "$pbkdf2",
+    "pbkdf2_sha256$",
+    "pbkdf2_sha1$",
+    "bcrypt_sha256$",
+    "bcrypt$",
+    "scrypt$",
+    "argon2$",
+];
+
+/// Whether the hash looks like one of the supported legacy formats
+pub fn is_supported(hash: &str) -> bool {
+    PREFIXES.iter().any(|prefix| hash.starts_with(prefix))
+}
+
+/// Verifies a password against a legacy hash
+/// Returns `None` if the hash is malformed or not in a supported format
+pub fn verify(password: &str, hash: &str) -> Option<bool> {
+    if ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+    {
+        return bcrypt::verify(password, hash).ok();
+    }
+    if hash.starts_with("$scrypt$") {
+        return verify_phc(password, hash, &Scrypt);
+    }
+    if hash.starts_with("$pbkdf2") {
+        return verify_phc(password, hash, &Pbkdf2);
+    }
+
+    // Django - `<algorithm>$<fields>`
+    let (algorithm, fields) = hash.split_once('$')?;
+    match algorithm {
+        "pbkdf2_sha256" => verify_django_pbkdf2(password, fields, false),
+        "pbkdf2_sha1" => verify_django_pbkdf2(password, fields, true),
+        // Django pre-hashes the password, bypassing the 72 bytes limit of bcrypt
+        "bcrypt_sha256" => bcrypt::verify(to_hex(&Sha256::digest(password)), fields).ok(),
+        "bcrypt" => bcrypt::verify(password, fields).ok(),
+        "scrypt" => verify_django_scrypt(password, fields),
+        // Django stores the PHC string without its leading `$`
+        "argon2" => verify_phc(password, &format!("${fields}"), &argon2::Argon2::default()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &str = "correct horse";
+
+    fn assert_verifies(hash: &str, password: &str) {
+        assert!(is_supported(hash), "{hash} is not supported");
+        assert_eq!(verify(password, hash), Some(true), "{hash} did not verify");
+        assert_eq!(verify("wrong password", hash), Some(false));
+    }
+
+    #[test]
+    fn verifies_bcrypt() {
+        // Test vector of Openwall's crypt_blowfish
+        assert_verifies(
+            "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW",
+            "U*U",
+        );
+        assert_verifies(&bcrypt::hash(PASSWORD, 4).unwrap(), PASSWORD);
+    }
+
+    #[test]
+    fn verifies_phc() {
+        assert_verifies(
+            "$scrypt$ln=10,r=8,p=1$c2FsdHNhbHRzYWx0$JLoeRlAiVzz/fmBbrb3a7QkuecDu7wE6R0zHHE5DC7g",
+            PASSWORD,
+        );
+        assert_verifies(
+            "$pbkdf2-sha256$i=1000,l=32$c2FsdHNhbHRzYWx0$F7o7+5VTVzFAO998X6s3AHrDsJVdMiIlgndIMe19NvY",
+            PASSWORD,
+        );
+    }
+
+    #[test]
+    fn verifies_django() {
+        assert_verifies(
+            "pbkdf2_sha256$1000$Yd1r3PbHg2sG$LG313HUk8beQCEXKtopjacTVOKWDEgmDkcQXCwJmTS8=",
+            PASSWORD,
+        );
+        assert_verifies(
+            "pbkdf2_sha1$1000$Yd1r3PbHg2sG$VQ+OBDVBzf7pHd54vVxS5LNum0w=",
+            PASSWORD,
+        );
+        assert_verifies(
+            "scrypt$Yd1r3PbHg2sG$1024$8$1$jPYhfFaYWpeLa0wMfM1HoFbJAjw6m74k4uYhBKTNsBlIPHorGv97vVzsFqWmju3bbAJnR12EoRHawRJlXS+o5g==",
+            PASSWORD,
+        );
+
+        let bcrypt_sha256 = bcrypt::hash(to_hex(&Sha256::digest(PASSWORD)), 4).unwrap();
+        assert_verifies(&format!("bcrypt_sha256${bcrypt_sha256}"), PASSWORD);
+        assert_verifies(
+            &format!("bcrypt${}", bcrypt::hash(PASSWORD, 4).unwrap()),
+            PASSWORD,
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_and_malformed_hashes() {
+        assert!(!is_supported("md5$salt$hash"));
+        assert_eq!(verify(PASSWORD, "md5$salt$hash"), None);
+        assert_eq!(verify(PASSWORD, "pbkdf2_sha256$many$salt$hash"), None);
+        // Django requires a power of two for N
+        assert_eq!(
+            verify(PASSWORD, "scrypt$Yd1r3PbHg2sG$1000$8$1$jPYhfFaYWpeL"),
+            None
+        );
+    }
+}
@@ -0,0 +1,113 @@
+// Per-account lockout
+// * Failed password logins & failed second factors are counted per user, whichever IP they come from
+//   * So are wrong passwords & factors confirming sensitive actions (password change, email change, deletion)
+// * Once `LOGIN_LOCKOUT_THRESHOLD` failures in a row are reached, the account is locked for `LOGIN_LOCKOUT_BASE`,
+//   doubling on every further failure up to `LOGIN_LOCKOUT_MAX`
+//   * Attempts while the account is locked are not counted, so the lock does not keep growing while it is held
+// * A locked account answers exactly like a wrong password, even if the right one is given - the lock is not leaked
+//   * Users can still get in through a password reset, which clears the lock
+// * The lock is also cleared by a successful login (second factor included), or by an admin through
+//   `/admin/user/{id}/unlock`
+
+use std::{sync::LazyLock, time::Duration};
+
+use libsql::{Connection, params};
+
+use crate::common::env_or;
+
+/// Amount of failed logins in a row after which the account gets locked.
+///
+/// Defaults to 5
+pub static THRESHOLD: LazyLock<u32> = LazyLock::new(|| env_or("LOGIN_LOCKOUT_THRESHOLD", 5));
+
+/// Duration of the first lock, in seconds.
+///
+/// Defaults to 30 seconds
+pub static BASE: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_secs(env_or("LOGIN_LOCKOUT_BASE", 30)));
+
+/// Upper bound of the lock duration, in seconds.
+///
+/// Defaults to 1 hour
+pub static MAX: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_secs(env_or("LOGIN_LOCKOUT_MAX", 3600)));
+
+/// Duration of the lock after the given amount of failed logins in a row, if any
+pub fn lock_duration(failures: u32) -> Option<Duration> {
+    let over = failures.checked_sub(*THRESHOLD)?;
+    let factor = 2u32.checked_pow(over).unwrap_or(u32::MAX);
+
+    Some(BASE.saturating_mul(factor).min(*MAX))
+}
+
+/// Whether the account is locked at `current_time`
+pub fn is_locked(locked_until: Option<u64>, current_time: u64) -> bool {
+    locked_until.is_some_and(|locked_until| locked_until > current_time)
+}
+
+/// Counts a failed login, locking the account if the threshold has been reached
+///
+/// The count is incremented by the database, so that concurrent failures are all counted
+pub async fn record_failure(
+    conn: &Connection,
+    user_id: u64,
+    current_time: u64,
+) -> Result<(), libsql::Error> {
+    let mut rows = conn
+        .query(
+            "UPDATE \"users\" SET failed_login_attempts = failed_login_attempts + 1 WHERE id = ? RETURNING failed_login_attempts",
+            params![user_id],
+        )
+        .await?;
+    let Some(row) = rows.next().await? else {
+        return Ok(());
+    };
+    let failures = row.get::<u32>(0)?;
+
+    let Some(duration) = lock_duration(failures) else {
+        return Ok(());
+    };
+    // Concurrent failures may finish in any order - keep the longest lock
+    conn.execute(
+        "UPDATE \"users\" SET locked_until = MAX(COALESCE(locked_until, 0), ?) WHERE id = ?",
+        params![current_time + duration.as_secs(), user_id],
+    )
+    .await?;
+    Ok(())
+}
+
+/// Clears the failure count & lock of the account
+pub async fn reset(conn: &Connection, user_id: u64) -> Result<u64, libsql::Error> {
+    conn.execute(
+        "UPDATE \"users\" SET failed_login_attempts = 0, locked_until = NULL WHERE id = ?",
+        params![user_id],
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locks_once_threshold_is_reached() {
+        assert_eq!(lock_duration(0), None);
+        assert_eq!(lock_duration(4), None);
+        assert_eq!(lock_duration(5), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn doubles_lock_up_to_max() {
+        assert_eq!(lock_duration(6), Some(Duration::from_mins(1)));
+        assert_eq!(lock_duration(7), Some(Duration::from_mins(2)));
+        assert_eq!(lock_duration(12), Some(Duration::from_hours(1)));
+        assert_eq!(lock_duration(u32::MAX), Some(Duration::from_hours(1)));
+    }
+
+    #[test]
+    fn lock_expires() {
+        assert!(!is_locked(None, 100));
+        assert!(is_locked(Some(101), 100));
+        assert!(!is_locked(Some(100), 100));
+    }
+}
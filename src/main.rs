@@ -6,17 +6,38 @@
 #![warn(clippy::complexity)]
 #![warn(clippy::style)]
 
+mod audit;
+mod clients;
 mod common;
 mod db;
+mod deletion;
+mod email;
+mod email_otp;
+mod hashing;
+mod hibp;
 mod jwt;
+mod legacy_hash;
+mod lockout;
+mod notification;
+mod outbox;
 mod password;
+mod password_policy;
+mod rate_limit;
+mod recovery_codes;
 mod routes;
+mod session;
+mod templates;
+mod timing;
 mod totp;
+mod username;
+mod webauthn;
+mod webhook;
 
-use std::{sync::Arc, time::Duration};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use axum::{Router, routing::get};
 use common::RequestIdCounter;
+use notification::Notifier;
 use routes::health_check::health_check;
 use tokio::{select, task::JoinSet};
 use tokio_util::sync::CancellationToken;
@@ -28,10 +49,13 @@ use tower_http::{
     timeout::{Timeout, TimeoutLayer},
 };
 use tracing::info;
+use webhook::Webhooks;
 
 #[derive(Clone)]
 pub struct AppState {
     db: Arc<libsql::Database>,
+    notifier: Arc<dyn Notifier>,
+    webhooks: Arc<Webhooks>,
 }
 
 #[tokio::main]
@@ -44,35 +68,38 @@ async fn main() {
 
     // Verify proper envvar
     std::env::var("JWT_SECRET").expect("No JWT_SECRET provided!");
+    // Calibrate Argon2 before accepting requests, rather than on the first one
+    password::init();
 
     let ct = CancellationToken::new();
     let database = db::prepare().await;
+    username::backfill(&database).await;
+    password::backfill_peppers(&database).await;
     let mut http_servers: JoinSet<()> = JoinSet::new();
 
     let app_state = AppState {
         db: Arc::new(database),
+        notifier: notification::from_env(),
+        webhooks: Arc::new(Webhooks::from_env()),
     };
 
-    let mut app = Router::new()
+    let public_routes = Router::new()
         .nest("/auth", routes::auth::router())
         .nest("/jwt", routes::jwt::router())
-        .route("/health-check", get(health_check))
-        .layer(NormalizePathLayer::trim_trailing_slash())
-        .layer(CatchPanicLayer::new())
-        .layer(SetRequestIdLayer::x_request_id(RequestIdCounter::default()))
-        .layer(PropagateRequestIdLayer::x_request_id())
-        .layer(TimeoutLayer::new(Duration::from_secs(10)))
-        .with_state(app_state);
-
-    if std::env::var("HTTP_COMPRESS").is_ok() {
+        .route("/health-check", get(health_check));
+    // Admin API has no authentication of its own - only expose it through the Unix socket
+    let socket_routes = public_routes
+        .clone()
+        .nest("/admin", routes::admin::router());
+
+    let compress = std::env::var("HTTP_COMPRESS").is_ok();
+    if compress {
         info!("Env var `HTTP_COMPRESS` is set. Compressing responses...");
-        app = app.layer(
-            CompressionLayer::new()
-                .zstd(true)
-                .quality(tower_http::CompressionLevel::Precise(19)),
-        );
     }
 
+    let app = with_layers(public_routes, app_state.clone(), compress);
+    let socket_app = with_layers(socket_routes, app_state.clone(), compress);
+
     #[cfg(not(debug_assertions))]
     let socket = tokio::net::UnixListener::bind("/var/run/picoauth.sock")
         .expect("Unable to bind to /var/run/picoauth.sock.");
@@ -87,7 +114,7 @@ async fn main() {
     // Spawn Unix socket server
     {
         let ct = ct.clone();
-        let app = app.clone();
+        let app = socket_app;
 
         info!("Listening on ./picoauth.sock");
         http_servers.spawn(async move {
@@ -111,11 +138,19 @@ async fn main() {
                 _ = ct.cancelled() => {
                     info!("Caught exit signal - Shutting down TCP server");
                 }
-                _ = axum::serve(listener, app) => {}
+                // Peer address is needed for rate limiting
+                _ = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()) => {}
             }
         });
     }
 
+    // Spawn outbox worker
+    let outbox_worker = tokio::spawn(outbox::run(app_state.clone(), ct.clone()));
+    // Spawn account deletion worker
+    let deletion_worker = tokio::spawn(deletion::run(app_state.clone(), ct.clone()));
+    // Spawn auth event retention worker
+    let audit_worker = tokio::spawn(audit::run(app_state, ct.clone()));
+
     tokio::signal::ctrl_c()
         .await
         .expect("Unable to listen for SIGINT");
@@ -125,6 +160,9 @@ async fn main() {
     info!("Quit signal captured. Shutting down gracefully...");
     ct.cancel();
     http_servers.join_all().await;
+    outbox_worker.await.ok();
+    deletion_worker.await.ok();
+    audit_worker.await.ok();
 
     #[cfg(not(debug_assertions))]
     std::fs::remove_file("/var/run/picoauth.sock").ok();
@@ -133,3 +171,24 @@ async fn main() {
 
     info!("All HTTP servers shut down. Goodbye 👋");
 }
+
+/// Applies the common middlewares to a router
+fn with_layers(router: Router<AppState>, state: AppState, compress: bool) -> Router {
+    let app = router
+        .layer(NormalizePathLayer::trim_trailing_slash())
+        .layer(CatchPanicLayer::new())
+        .layer(SetRequestIdLayer::x_request_id(RequestIdCounter::default()))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(TimeoutLayer::new(Duration::from_secs(10)))
+        .with_state(state);
+
+    if compress {
+        return app.layer(
+            CompressionLayer::new()
+                .zstd(true)
+                .quality(tower_http::CompressionLevel::Precise(19)),
+        );
+    }
+
+    app
+}
@@ -0,0 +1,52 @@
+use std::{
+    path::PathBuf,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    time::UNIX_EPOCH,
+};
+
+use super::{Error, Message, Notifier};
+
+/// Writes every message as a file into a directory. Meant for development & testing.
+/// HTML bodies are written next to the message, with the same name & an `.html` extension.
+pub struct DirectoryNotifier {
+    directory: PathBuf,
+    counter: AtomicU64,
+}
+
+impl DirectoryNotifier {
+    pub const fn new(directory: PathBuf) -> Self {
+        Self {
+            directory,
+            counter: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Notifier for DirectoryNotifier {
+    fn send<'a>(
+        &'a self,
+        message: &'a Message,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>> {
+        Box::pin(async move {
+            tokio::fs::create_dir_all(&self.directory).await?;
+
+            // Timestamp first so that messages are sorted chronologically
+            let file_name = format!(
+                "{}-{}",
+                UNIX_EPOCH.elapsed().unwrap().as_millis(),
+                self.counter.fetch_add(1, Ordering::SeqCst)
+            );
+            let content = format!(
+                "To: {}\nSubject: {}\n\n{}\n",
+                message.to, message.subject, message.body
+            );
+
+            tokio::fs::write(self.directory.join(format!("{file_name}.txt")), content).await?;
+            if let Some(html) = &message.html {
+                tokio::fs::write(self.directory.join(format!("{file_name}.html")), html).await?;
+            }
+            Ok(())
+        })
+    }
+}
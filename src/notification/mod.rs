@@ -0,0 +1,88 @@
+// Notification providers
+// * Used to deliver side-channel messages (password reset, email verification, magic links, etc.)
+// * Selected with the `NOTIFIER` environment variable - `smtp`, `directory`, `stdout` or `none`
+//   * Defaults to `stdout` on debug builds, and to dropping messages on release builds
+
+use std::{pin::Pin, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+pub mod directory;
+pub mod smtp;
+pub mod stdout;
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// A message to be delivered to a user through a side-channel (e.g. email)
+#[derive(Serialize, Deserialize)]
+pub struct Message {
+    pub to: String,
+    pub subject: String,
+    /// Plain text body
+    pub body: String,
+    /// HTML alternative of the body, for sinks that support it
+    #[serde(default)]
+    pub html: Option<String>,
+}
+
+pub trait Notifier: Send + Sync {
+    /// Delivers a message to its recipient
+    fn send<'a>(
+        &'a self,
+        message: &'a Message,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>>;
+}
+
+/// Drops every message. Used when no notifier has been configured.
+struct Disabled;
+
+impl Notifier for Disabled {
+    fn send<'a>(
+        &'a self,
+        message: &'a Message,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>> {
+        Box::pin(async move {
+            warn!(
+                to = message.to,
+                subject = message.subject,
+                "No notifier configured - Message is dropped"
+            );
+            Ok(())
+        })
+    }
+}
+
+/// Creates the notifier configured through environment variables
+pub fn from_env() -> Arc<dyn Notifier> {
+    let notifier = std::env::var("NOTIFIER").ok();
+
+    match notifier.as_deref() {
+        Some("smtp") => {
+            info!("Delivering notifications through SMTP");
+            Arc::new(smtp::SmtpNotifier::from_env())
+        }
+        Some("directory") => {
+            let directory =
+                std::env::var("NOTIFIER_DIRECTORY").unwrap_or_else(|_| "./mail".to_string());
+            info!("Writing notifications to {directory}");
+            Arc::new(directory::DirectoryNotifier::new(directory.into()))
+        }
+        Some("stdout") => Arc::new(stdout::StdoutNotifier),
+        Some("none") => Arc::new(Disabled),
+        Some(other) => {
+            panic!("Unknown notifier `{other}`! Expected `smtp`, `directory`, `stdout` or `none`")
+        }
+
+        #[cfg(debug_assertions)]
+        None => Arc::new(stdout::StdoutNotifier),
+        #[cfg(not(debug_assertions))]
+        None => {
+            warn!(
+                "No notifier configured - Messages (password reset, verification, etc.) will NOT be delivered!"
+            );
+            warn!("Please check whether if you have provided a `NOTIFIER` environment variable.");
+            Arc::new(Disabled)
+        }
+    }
+}
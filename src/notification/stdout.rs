@@ -0,0 +1,21 @@
+use std::pin::Pin;
+
+use super::{Error, Message, Notifier};
+
+/// Prints messages to stdout. Meant for development only - messages usually contain secrets!
+pub struct StdoutNotifier;
+
+impl Notifier for StdoutNotifier {
+    fn send<'a>(
+        &'a self,
+        message: &'a Message,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>> {
+        Box::pin(async move {
+            println!(
+                "To: {}\nSubject: {}\n\n{}\n",
+                message.to, message.subject, message.body
+            );
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,202 @@
+// Durable outbox for side-effects (notifications & webhooks)
+// * Jobs are written in the same transaction as the change triggering them
+//   * A rolled back change never sends anything, a committed change is never lost
+//   * Requests don't block on slow / unavailable SMTP servers & webhook receivers
+// * A background worker delivers pending jobs, retrying failures with exponential backoff
+//   * Jobs failing `OUTBOX_MAX_ATTEMPTS` times are moved to the `dead` state, to be inspected / retried through the admin API
+// * Delivery is at-least-once - a job interrupted by a shutdown may be sent again
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use libsql::{Connection, params};
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{
+    AppState,
+    notification::{self, Message},
+    webhook::{Delivery, Event, Webhooks},
+};
+
+/// Job kind of a `notification::Message`
+pub const KIND_NOTIFICATION: &str = "notification";
+/// Job kind of a `webhook::Delivery`
+pub const KIND_WEBHOOK: &str = "webhook";
+
+pub const STATUS_PENDING: &str = "pending";
+pub const STATUS_DELIVERED: &str = "delivered";
+pub const STATUS_DEAD: &str = "dead";
+
+/// The amount of time in-between checking for due jobs.
+///
+/// Defaults to 1 second
+const OUTBOX_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// The maximum amount of jobs delivered in one go
+///
+/// Defaults to 32
+const OUTBOX_BATCH_SIZE: u64 = 32;
+/// The amount of failed attempts after which a job is given up on
+///
+/// Defaults to 10
+const OUTBOX_MAX_ATTEMPTS: u64 = 10;
+/// Delay before retrying a job for the first time, doubled on every subsequent failure
+///
+/// Defaults to 10 seconds
+const OUTBOX_BASE_BACKOFF: Duration = Duration::from_secs(10);
+/// Upper bound of the delay in-between retries
+///
+/// Defaults to 1 hour
+const OUTBOX_MAX_BACKOFF: Duration = Duration::from_secs(3600);
+/// The amount of time delivered jobs are kept around for
+///
+/// Defaults to 7 days
+const OUTBOX_RETENTION: Duration = Duration::from_secs(7 * 24 * 3600);
+
+async fn enqueue(conn: &Connection, kind: &str, payload: String) -> Result<(), libsql::Error> {
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
+
+    conn.execute(
+        "INSERT INTO \"outbox\" (kind, payload, next_attempt_at, created_at) VALUES (?, ?, ?, ?)",
+        params![kind, payload, current_time, current_time],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Queues a message to be sent by the notifier
+pub async fn enqueue_message(conn: &Connection, message: &Message) -> Result<(), libsql::Error> {
+    let payload = serde_json::to_string(message).expect("Message is serializable");
+    enqueue(conn, KIND_NOTIFICATION, payload).await
+}
+
+/// Queues an event to be delivered to every webhook endpoint
+pub async fn enqueue_event(
+    conn: &Connection,
+    webhooks: &Webhooks,
+    event: Event,
+) -> Result<(), libsql::Error> {
+    for delivery in webhooks.deliveries(&event) {
+        let payload = serde_json::to_string(&delivery).expect("Delivery is serializable");
+        enqueue(conn, KIND_WEBHOOK, payload).await?;
+    }
+
+    Ok(())
+}
+
+/// Delay before the next attempt of a job that failed `attempts` times
+fn backoff(attempts: u64) -> Duration {
+    let exponent = u32::try_from(attempts.saturating_sub(1)).unwrap_or(u32::MAX);
+
+    OUTBOX_BASE_BACKOFF
+        .saturating_mul(2_u32.saturating_pow(exponent))
+        .min(OUTBOX_MAX_BACKOFF)
+}
+
+async fn deliver(state: &AppState, kind: &str, payload: &str) -> Result<(), notification::Error> {
+    match kind {
+        KIND_NOTIFICATION => {
+            let message: Message = serde_json::from_str(payload)?;
+            state.notifier.send(&message).await
+        }
+        KIND_WEBHOOK => {
+            let delivery: Delivery = serde_json::from_str(payload)?;
+            Ok(state.webhooks.deliver(&delivery).await?)
+        }
+        other => Err(format!("Unknown job kind `{other}`").into()),
+    }
+}
+
+/// Delivers a batch of due jobs & records their outcome
+async fn process(state: &AppState, ct: &CancellationToken) -> Result<(), libsql::Error> {
+    let conn = state.db.connect()?;
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
+
+    let mut rows = conn
+        .query(
+            "SELECT id, kind, payload, attempts FROM \"outbox\" WHERE status = ? AND next_attempt_at <= ? ORDER BY next_attempt_at LIMIT ?",
+            params![STATUS_PENDING, current_time, OUTBOX_BATCH_SIZE],
+        )
+        .await?;
+
+    let mut jobs = Vec::new();
+    while let Some(row) = rows.next().await? {
+        jobs.push((
+            row.get::<u64>(0)?,
+            row.get::<String>(1)?,
+            row.get::<String>(2)?,
+            row.get::<u64>(3)?,
+        ));
+    }
+
+    for (id, kind, payload, attempts) in jobs {
+        if ct.is_cancelled() {
+            break;
+        }
+
+        let attempts = attempts + 1;
+        let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
+
+        match deliver(state, &kind, &payload).await {
+            Ok(()) => {
+                conn.execute(
+                    "UPDATE \"outbox\" SET status = ?, attempts = ?, delivered_at = ?, last_error = NULL WHERE id = ?",
+                    params![STATUS_DELIVERED, attempts, current_time, id],
+                )
+                .await?;
+            }
+            Err(e) if attempts >= OUTBOX_MAX_ATTEMPTS => {
+                warn!(id, kind, attempts, "Giving up on outbox job, {e}");
+                conn.execute(
+                    "UPDATE \"outbox\" SET status = ?, attempts = ?, last_error = ? WHERE id = ?",
+                    params![STATUS_DEAD, attempts, e.to_string(), id],
+                )
+                .await?;
+            }
+            Err(e) => {
+                warn!(id, kind, attempts, "Unable to deliver outbox job, {e}");
+                conn.execute(
+                    "UPDATE \"outbox\" SET attempts = ?, last_error = ?, next_attempt_at = ? WHERE id = ?",
+                    params![
+                        attempts,
+                        e.to_string(),
+                        current_time + backoff(attempts).as_secs(),
+                        id
+                    ],
+                )
+                .await?;
+            }
+        }
+    }
+
+    conn.execute(
+        "DELETE FROM \"outbox\" WHERE status = ? AND delivered_at < ?",
+        params![
+            STATUS_DELIVERED,
+            current_time.saturating_sub(OUTBOX_RETENTION.as_secs())
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Runs the outbox worker until the cancellation token is cancelled
+pub async fn run(state: AppState, ct: CancellationToken) {
+    info!("Outbox worker started");
+
+    loop {
+        if let Err(e) = process(&state, &ct).await {
+            warn!("Unable to process outbox, {e}");
+        }
+
+        select! {
+            () = ct.cancelled() => {
+                info!("Caught exit signal - Shutting down outbox worker");
+                return;
+            }
+            () = tokio::time::sleep(OUTBOX_POLL_INTERVAL) => {}
+        }
+    }
+}
@@ -1,46 +1,319 @@
-use std::{str::FromStr, sync::LazyLock};
+// Password hashing
+// * Argon2id, with the cost configured through `ARGON2_MEMORY_COST` (KiB), `ARGON2_TIME_COST` & `ARGON2_PARALLELISM`
+//   * Setting `ARGON2_CALIBRATE` to a target duration (ms) picks the time cost hitting it on this machine at startup
+//     * The picked parameters are logged & should be pinned, as every restart may pick different ones
+// * Hashes made with other parameters keep verifying - they are transparently re-hashed on the next login
+//   * So do hashes imported from other systems, see `legacy_hash.rs`
+// * Hashes may be peppered with a secret, whose id is stored alongside the hash (`users.password_pepper`)
+//   * `ARGON_SECRET` is the pepper with the `default` id
+//   * Rotated peppers are listed in `ARGON_SECRETS` (comma-separated ids, oldest first), e.g. `ARGON_SECRETS=2025`
+//     is configured with `ARGON_SECRET_2025`
+//   * New hashes use the newest pepper, hashes using an older one are re-hashed on the next login
+//   * Hashes made without any pepper (e.g. imported Argon2 hashes) have the `none` id
+//   * Hashes predating pepper ids are given one on startup, see `backfill_peppers`
+//   * Unsetting a pepper still in use fails the logins of its users, see `/admin/peppers` before retiring one
+
+use std::{
+    sync::{Arc, LazyLock},
+    time::{Duration, Instant},
+};
 
 use argon2::{
-    Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier,
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version,
     password_hash::{SaltString, rand_core::OsRng},
 };
+use libsql::{Database, params};
+use tracing::{error, info, warn};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{common::env_or, hashing, legacy_hash};
+
+/// Argon2 parameters of new hashes
+static PARAMS: LazyLock<Params> = LazyLock::new(|| {
+    let memory_cost = env_or("ARGON2_MEMORY_COST", Params::DEFAULT_M_COST);
+    let parallelism = env_or("ARGON2_PARALLELISM", Params::DEFAULT_P_COST);
+
+    if let Ok(target) = std::env::var("ARGON2_CALIBRATE") {
+        let target = Duration::from_millis(target.parse().expect("Invalid ARGON2_CALIBRATE"));
+        return calibrate(memory_cost, parallelism, target);
+    }
+
+    let time_cost = env_or("ARGON2_TIME_COST", Params::DEFAULT_T_COST);
+    Params::new(memory_cost, time_cost, parallelism, None).expect("Invalid Argon2 parameters!")
+});
+
+/// A pepper & the hasher using it
+pub struct Pepper {
+    pub id: &'static str,
+    hasher: Argon2<'static>,
+}
+
+impl Pepper {
+    fn new(id: &'static str, secret: Option<String>) -> Self {
+        let hasher = secret.map_or_else(
+            || Argon2::new(Algorithm::Argon2id, Version::V0x13, PARAMS.clone()),
+            |secret| {
+                Argon2::new_with_secret(
+                    secret.leak().as_bytes(), // oop
+                    Algorithm::Argon2id,
+                    Version::V0x13,
+                    PARAMS.clone(),
+                )
+                .expect("Unable to create Argon2 hasher!")
+            },
+        );
+
+        Self { id, hasher }
+    }
+}
+
+/// Pepper id of hashes made without any pepper, e.g. imported from other systems
+pub const UNPEPPERED: &str = "none";
 
-static HASHER: LazyLock<Argon2> = std::sync::LazyLock::new(|| {
-    let secret = std::env::var("ARGON_SECRET").ok();
-
-    secret.map_or_else(
-        || {
-            Argon2::new(
-                argon2::Algorithm::Argon2id,
-                argon2::Version::V0x13,
-                Params::DEFAULT,
-            )
-        },
-        |secret| {
-            Argon2::new_with_secret(
-                secret.leak().as_bytes(), // oop
-                argon2::Algorithm::Argon2id,
-                argon2::Version::V0x13,
-                Params::DEFAULT,
-            )
-            .expect("Unable to create Argon2 hasher!")
-        },
-    )
+/// Pepper id of `ARGON_SECRET`
+pub const DEFAULT_PEPPER: &str = "default";
+
+/// Hasher of the hashes made without any pepper, whether one is configured or not
+static UNPEPPERED_PEPPER: LazyLock<Pepper> = LazyLock::new(|| Pepper::new(UNPEPPERED, None));
+
+/// Configured peppers, oldest first - the last one is used for new hashes
+pub static PEPPERS: LazyLock<Vec<Pepper>> = LazyLock::new(|| {
+    let mut peppers: Vec<_> = std::env::var("ARGON_SECRET")
+        .ok()
+        .map(|secret| Pepper::new(DEFAULT_PEPPER, Some(secret)))
+        .into_iter()
+        .collect();
+
+    let mut rotated = 0;
+    for id in std::env::var("ARGON_SECRETS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+    {
+        assert!(
+            id != UNPEPPERED && id != DEFAULT_PEPPER,
+            "`{UNPEPPERED}` & `{DEFAULT_PEPPER}` are reserved, use another pepper id!"
+        );
+        let env_name = format!("ARGON_SECRET_{}", id.to_uppercase().replace('-', "_"));
+        let secret = std::env::var(&env_name).unwrap_or_else(|_| panic!("No {env_name} provided!"));
+        peppers.push(Pepper::new(id.to_string().leak(), Some(secret)));
+        rotated += 1;
+    }
+
+    if rotated > 0 {
+        info!("Configured {rotated} rotated pepper(s)");
+    }
+    peppers
 });
 
-pub fn hash(password: &str) -> Box<str> {
+/// Pepper used for new hashes
+pub fn current_pepper() -> &'static Pepper {
+    PEPPERS.last().unwrap_or(&UNPEPPERED_PEPPER)
+}
+
+fn find_pepper(id: Option<&str>) -> Option<&'static Pepper> {
+    let id = id?;
+    if id == UNPEPPERED {
+        return Some(&UNPEPPERED_PEPPER);
+    }
+    PEPPERS.iter().find(|pepper| pepper.id == id)
+}
+
+/// Gives Argon2 hashes predating pepper ids the id of the pepper they were made with
+///
+/// Those were made with `ARGON_SECRET` if it is set, without any pepper otherwise
+pub async fn backfill_peppers(db: &Database) {
+    let pepper = if std::env::var("ARGON_SECRET").is_ok() {
+        DEFAULT_PEPPER
+    } else {
+        UNPEPPERED
+    };
+
+    let conn = db.connect().expect("Unable to connect to database");
+    match conn
+        .execute(
+            "UPDATE \"users\" SET password_pepper = ? WHERE password_pepper IS NULL AND password LIKE '$argon2%'",
+            params![pepper],
+        )
+        .await
+    {
+        Ok(0) => {}
+        Ok(updated) => info!("Marked {updated} password hash(es) as using the `{pepper}` pepper"),
+        Err(e) => warn!("Unable to backfill password peppers, {e}"),
+    }
+}
+
+/// Upper bound of the time cost picked by the calibration
+const MAX_CALIBRATED_TIME_COST: u32 = 64;
+
+/// Picks the time cost for which hashing takes about `target` on this machine
+fn calibrate(memory_cost: u32, parallelism: u32, target: Duration) -> Params {
+    let measure = |time_cost: u32| {
+        let params = Params::new(memory_cost, time_cost, parallelism, None)
+            .expect("Invalid Argon2 parameters!");
+        let hasher = Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone());
+        let salt = SaltString::generate(&mut OsRng);
+
+        let start = Instant::now();
+        hasher
+            .hash_password(b"calibration", salt.as_salt())
+            .expect("Unable to hash password!");
+        (params, start.elapsed())
+    };
+
+    // Hashing time grows linearly with the time cost
+    let (_, single_pass) = measure(1);
+    let time_cost = (target.as_secs_f64() / single_pass.as_secs_f64().max(f64::EPSILON)).round();
+    let time_cost = (time_cost as u32).clamp(1, MAX_CALIBRATED_TIME_COST);
+
+    let (params, elapsed) = measure(time_cost);
+    info!(
+        "Calibrated Argon2 parameters to m={memory_cost}, t={time_cost}, p={parallelism} ({}ms) - Set ARGON2_MEMORY_COST, ARGON2_TIME_COST & ARGON2_PARALLELISM to pin them",
+        elapsed.as_millis()
+    );
+    params
+}
+
+/// Initializes the hashers, running the calibration if enabled
+pub fn init() {
+    LazyLock::force(&PEPPERS);
+    LazyLock::force(&DUMMY_HASH);
+}
+
+/// NFKC-normalizes a password, so that it can be typed the same way across keyboards & platforms
+pub fn normalize(password: &str) -> String {
+    password.nfkc().collect()
+}
+
+/// A new password hash & the id of the pepper it was made with
+pub struct Hashed {
+    pub hash: Box<str>,
+    pub pepper: &'static str,
+}
+
+pub fn hash(password: &str) -> Hashed {
+    let pepper = current_pepper();
     let salt = SaltString::generate(&mut OsRng);
-    let hash = HASHER
-        .hash_password(password.as_bytes(), salt.as_salt())
+    let hash = pepper
+        .hasher
+        .hash_password(normalize(password).as_bytes(), salt.as_salt())
         .expect("Unable to hash password!");
 
-    hash.to_string().into_boxed_str()
+    Hashed {
+        hash: hash.to_string().into_boxed_str(),
+        pepper: pepper.id,
+    }
 }
 
-pub fn verify(password: &str, hash: &str) -> bool {
-    let pw_hash = PasswordHash::new(hash).expect("Malformed password hash");
+/// Outcome of checking a password against a stored hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Invalid,
+    /// Password matches, `rehash` tells whether the stored hash is outdated & should be replaced
+    Valid {
+        rehash: bool,
+    },
+}
+
+/// Checks a password against a stored hash & the id of its pepper, telling whether the hash should be upgraded
+pub fn check(password: &str, hash: &str, pepper_id: Option<&str>) -> Verification {
+    // Hashes imported from other systems are always upgraded
+    if legacy_hash::is_supported(hash) {
+        return match legacy_hash::verify(password, hash) {
+            Some(true) => Verification::Valid { rehash: true },
+            Some(false) => Verification::Invalid,
+            None => {
+                error!("Malformed legacy password hash");
+                Verification::Invalid
+            }
+        };
+    }
+
+    let Ok(pw_hash) = PasswordHash::new(hash) else {
+        error!("Malformed password hash");
+        return Verification::Invalid;
+    };
+    let Some(pepper) = find_pepper(pepper_id) else {
+        error!(
+            pepper_id,
+            "Password hash uses an unknown pepper - Is it missing from ARGON_SECRET or ARGON_SECRETS?"
+        );
+        return Verification::Invalid;
+    };
+    let normalized = normalize(password);
 
-    HASHER
-        .verify_password(password.as_bytes(), &pw_hash)
+    if pepper
+        .hasher
+        .verify_password(normalized.as_bytes(), &pw_hash)
         .is_ok()
+    {
+        let is_current = pepper.id == current_pepper().id
+            && pw_hash.algorithm == Algorithm::Argon2id.ident()
+            && pw_hash.version == Some(Version::V0x13.into())
+            && Params::try_from(&pw_hash).is_ok_and(|params| {
+                params.m_cost() == PARAMS.m_cost()
+                    && params.t_cost() == PARAMS.t_cost()
+                    && params.p_cost() == PARAMS.p_cost()
+            });
+
+        return Verification::Valid {
+            rehash: !is_current,
+        };
+    }
+
+    // Hashes set before passwords were normalized were made from the password as-is
+    if normalized != password
+        && pepper
+            .hasher
+            .verify_password(password.as_bytes(), &pw_hash)
+            .is_ok()
+    {
+        return Verification::Valid { rehash: true };
+    }
+
+    Verification::Invalid
+}
+
+pub fn verify(password: &str, hash: &str, pepper_id: Option<&str>) -> bool {
+    matches!(check(password, hash, pepper_id), Verification::Valid { .. })
+}
+
+/// Hash made with the current parameters & pepper, of a password nobody knows
+static DUMMY_HASH: LazyLock<Hashed> = LazyLock::new(|| hash(&crate::common::generate_url_token()));
+
+/// Verifies the password against a dummy hash, taking as long as verifying it against the hash of an actual user
+///
+/// Used where no user has been found, so that response times do not tell which users exist
+pub fn verify_dummy(password: &str) {
+    check(password, &DUMMY_HASH.hash, Some(DUMMY_HASH.pepper));
+}
+
+/// Replaces an outdated hash of the user with one using the current parameters, without blocking the caller
+///
+/// The hash is only replaced if it has not been changed in the meantime
+pub fn rehash_in_background(db: Arc<Database>, user_id: u64, password: String, outdated: String) {
+    tokio::spawn(async move {
+        // Hashing pool is full - the hash is upgraded on a later login instead
+        let Ok(hashed) = hashing::run(move || hash(&password)).await else {
+            return;
+        };
+
+        let updated = match db.connect() {
+            Ok(conn) => {
+                conn.execute(
+                    "UPDATE \"users\" SET password = ?, password_pepper = ? WHERE id = ? AND password = ?",
+                    params![hashed.hash, hashed.pepper, user_id, outdated],
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+
+        match updated {
+            Ok(1) => info!(user_id, "Upgraded outdated password hash"),
+            Ok(_) => {}
+            Err(e) => warn!(user_id, "Unable to upgrade outdated password hash, {e}"),
+        }
+    });
 }
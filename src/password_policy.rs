@@ -0,0 +1,407 @@
+// Password policy shared by every path setting a password (registration, change, reset, ...)
+// * Passwords are NFKC-normalized before being checked & hashed, lengths are counted in characters
+// * Rules, configured through environment variables:
+//   * `PASSWORD_MIN_LENGTH` / `PASSWORD_MAX_LENGTH` - the maximum bounds the hashing & scoring work of a single
+//     request, longer passwords are turned away before any other rule & before being hashed on login & confirmations
+//   * `PASSWORD_MIN_SCORE` - minimum strength score (0-4, zxcvbn-style), `0` disables the check
+//   * `PASSWORD_BANNED_WORDS` (comma-separated) & `PASSWORD_BANNED_WORDS_FILE` (one per line) - case-insensitive
+//   * Passwords may not contain the username or the email (or its local part)
+// * Every violated rule is reported, so that the frontend can show them all at once - over-length passwords excepted
+
+use std::sync::LazyLock;
+
+use axum::{
+    Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde_json::{Value, json};
+use tracing::info;
+
+use crate::{common::env_or, password::normalize};
+
+/// Minimum amount of characters of a password.
+///
+/// Defaults to 8
+static MIN_LENGTH: LazyLock<usize> = LazyLock::new(|| env_or("PASSWORD_MIN_LENGTH", 8));
+/// Maximum amount of characters of a password.
+///
+/// Defaults to 128
+static MAX_LENGTH: LazyLock<usize> = LazyLock::new(|| env_or("PASSWORD_MAX_LENGTH", 128));
+/// Minimum strength score of a password, from 0 (too guessable) to 4 (very unguessable).
+///
+/// Defaults to 2
+static MIN_SCORE: LazyLock<u8> = LazyLock::new(|| env_or("PASSWORD_MIN_SCORE", 2));
+/// Lowercase words passwords may not contain
+static BANNED_WORDS: LazyLock<Vec<String>> = LazyLock::new(|| {
+    let mut words: Vec<String> = std::env::var("PASSWORD_BANNED_WORDS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::to_string)
+        .collect();
+
+    if let Ok(path) = std::env::var("PASSWORD_BANNED_WORDS_FILE") {
+        let file =
+            std::fs::read_to_string(&path).expect("Unable to read PASSWORD_BANNED_WORDS_FILE");
+        words.extend(file.lines().map(str::to_string));
+    }
+
+    let words: Vec<String> = words
+        .iter()
+        .map(|word| normalize(word.trim()).to_lowercase())
+        .filter(|word| !word.is_empty())
+        .collect();
+    if !words.is_empty() {
+        info!("Loaded {} banned password word(s)", words.len());
+    }
+    words
+});
+
+/// Very common password fragments, each counted as a single guess of a small dictionary
+const COMMON_WORDS: &[&str] = &[
+    "password",
+    "passw0rd",
+    "qwerty",
+    "azerty",
+    "letmein",
+    "welcome",
+    "admin",
+    "login",
+    "iloveyou",
+    "monkey",
+    "dragon",
+    "master",
+    "shadow",
+    "sunshine",
+    "princess",
+    "football",
+    "baseball",
+    "superman",
+    "batman",
+    "trustno1",
+    "hello",
+    "secret",
+    "abc123",
+    "qwertyuiop",
+    "asdf",
+    "zxcv",
+];
+
+/// A violated rule of the password policy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    MinLength { min: usize },
+    MaxLength { max: usize },
+    TooWeak { score: u8, min_score: u8 },
+    BannedWord,
+    ContainsUsername,
+    ContainsEmail,
+}
+
+impl Violation {
+    fn to_json(&self) -> Value {
+        match self {
+            Self::MinLength { min } => json!({
+                "rule": "min_length",
+                "min": min,
+                "message": format!("Password must be {min} characters or longer"),
+            }),
+            Self::MaxLength { max } => json!({
+                "rule": "max_length",
+                "max": max,
+                "message": format!("Password must be {max} characters or shorter"),
+            }),
+            Self::TooWeak { score, min_score } => json!({
+                "rule": "strength",
+                "score": score,
+                "min_score": min_score,
+                "message": "Password is too easy to guess",
+            }),
+            Self::BannedWord => json!({
+                "rule": "banned_word",
+                "message": "Password contains a banned word",
+            }),
+            Self::ContainsUsername => json!({
+                "rule": "contains_username",
+                "message": "Password may not contain the username",
+            }),
+            Self::ContainsEmail => json!({
+                "rule": "contains_email",
+                "message": "Password may not contain the email",
+            }),
+        }
+    }
+}
+
+/// Length of the longest run starting at `chars[0]` where every character follows the previous one
+/// by the same step of -1, 0 or 1 (e.g. `aaaa`, `abcd`, `4321`)
+fn run_length(chars: &[char]) -> usize {
+    let Some(step) = chars
+        .get(1)
+        .map(|next| i64::from(u32::from(*next)) - i64::from(u32::from(chars[0])))
+        .filter(|step| step.abs() <= 1)
+    else {
+        return 1;
+    };
+
+    1 + chars
+        .windows(2)
+        .take_while(|pair| i64::from(u32::from(pair[1])) - i64::from(u32::from(pair[0])) == step)
+        .count()
+}
+
+/// Whether `chars` starts with `word`, without collecting the rest of the password
+fn starts_with(chars: &[char], word: &str) -> bool {
+    let mut chars = chars.iter();
+    word.chars().all(|c| chars.next() == Some(&c))
+}
+
+/// Estimates how guessable a password is, as a score from 0 to 4
+///
+/// Loosely modelled after zxcvbn - the password is split into common words, repeated / sequential runs &
+/// brute-forced characters, whose guesses are multiplied together. The thresholds match the ones of zxcvbn.
+pub fn score(password: &str) -> u8 {
+    let lowercase = password.to_lowercase();
+    let chars: Vec<char> = lowercase.chars().collect();
+    // Common words are also matched with l33t substitutions undone, e.g. `p@ssw0rd`
+    let unleet: Vec<char> = chars
+        .iter()
+        .map(|c| match c {
+            '@' | '4' => 'a',
+            '3' => 'e',
+            '1' | '!' => 'i',
+            '0' => 'o',
+            '$' | '5' => 's',
+            '7' => 't',
+            c => *c,
+        })
+        .collect();
+
+    let mut pool = 0_u32;
+    if password.chars().any(|c| c.is_ascii_lowercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_uppercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_digit()) {
+        pool += 10;
+    }
+    if password
+        .chars()
+        .any(|c| c.is_ascii_punctuation() || c == ' ')
+    {
+        pool += 33;
+    }
+    if !password.is_ascii() {
+        pool += 100;
+    }
+    let char_guesses = f64::from(pool.max(10)).log10();
+
+    let mut guesses = 0.0;
+    let mut i = 0;
+    while i < chars.len() {
+        if let Some(word) = COMMON_WORDS
+            .iter()
+            .filter(|w| starts_with(&chars[i..], w) || starts_with(&unleet[i..], w))
+            .max_by_key(|w| w.len())
+        {
+            guesses += f64::from(u32::try_from(COMMON_WORDS.len()).unwrap_or(u32::MAX)).log10();
+            i += word.chars().count();
+            continue;
+        }
+
+        let run = run_length(&chars[i..]);
+        if run >= 3 {
+            // Guessing the start of the run & its length
+            guesses += char_guesses + f64::from(u32::try_from(run).unwrap_or(u32::MAX)).log10();
+            i += run;
+        } else {
+            guesses += char_guesses;
+            i += 1;
+        }
+    }
+
+    match guesses {
+        g if g < 3.0 => 0,
+        g if g < 6.0 => 1,
+        g if g < 8.0 => 2,
+        g if g < 10.0 => 3,
+        _ => 4,
+    }
+}
+
+/// Whether the password is longer than `PASSWORD_MAX_LENGTH`
+///
+/// Such a password cannot have been set, paths verifying a password check this before hashing it
+pub fn exceeds_max_length(password: &str) -> bool {
+    normalize(password).chars().count() > *MAX_LENGTH
+}
+
+/// Checks a new password against the password policy, returning every violated rule
+pub fn check(password: &str, username: &str, email: Option<&str>) -> Result<(), Vec<Violation>> {
+    let password = normalize(password);
+    let length = password.chars().count();
+
+    // Scoring & matching words take longer with the length - turn away over-length passwords first
+    if length > *MAX_LENGTH {
+        return Err(vec![Violation::MaxLength { max: *MAX_LENGTH }]);
+    }
+
+    let lowercase = password.to_lowercase();
+    let mut violations = Vec::new();
+    if length < *MIN_LENGTH {
+        violations.push(Violation::MinLength { min: *MIN_LENGTH });
+    }
+
+    if *MIN_SCORE > 0 {
+        let score = score(&password);
+        if score < *MIN_SCORE {
+            violations.push(Violation::TooWeak {
+                score,
+                min_score: *MIN_SCORE,
+            });
+        }
+    }
+
+    if BANNED_WORDS
+        .iter()
+        .any(|word| lowercase.contains(word.as_str()))
+    {
+        violations.push(Violation::BannedWord);
+    }
+
+    if lowercase.contains(&normalize(username).to_lowercase()) {
+        violations.push(Violation::ContainsUsername);
+    }
+
+    if let Some(email) = email {
+        let email = normalize(email).to_lowercase();
+        let local_part = email.split('@').next().unwrap_or_default();
+        if lowercase.contains(&email)
+            || (local_part.chars().count() >= 3 && lowercase.contains(local_part))
+        {
+            violations.push(Violation::ContainsEmail);
+        }
+    }
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+/// Responds with every violated rule of the password policy
+pub fn rejection(violations: &[Violation]) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({
+            "error": "Insecure password - Password does not satisfy the password policy",
+            "code": "password_policy",
+            "violations": violations.iter().map(Violation::to_json).collect::<Vec<_>>(),
+        })),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores_guessable_passwords_low() {
+        for password in [
+            "password",
+            "p@ssw0rd",
+            "aaaaaaaa",
+            "abcdefgh",
+            "12345678",
+            "qwerty123",
+        ] {
+            assert!(score(password) < 2, "{password} scored {}", score(password));
+        }
+    }
+
+    #[test]
+    fn scores_random_passwords_high() {
+        for password in [
+            "correct horse battery staple",
+            "Tr0ub4dor&3xq!",
+            "x9#Kv2$mQ7pL",
+        ] {
+            assert!(
+                score(password) >= 3,
+                "{password} scored {}",
+                score(password)
+            );
+        }
+    }
+
+    #[test]
+    fn counts_runs_of_same_step() {
+        assert_eq!(run_length(&['a', 'a', 'a', 'b']), 3);
+        assert_eq!(run_length(&['4', '3', '2', '1']), 4);
+        assert_eq!(run_length(&['a', 'c', 'e']), 1);
+        assert_eq!(run_length(&['a']), 1);
+    }
+
+    #[test]
+    fn accepts_strong_password() {
+        assert_eq!(
+            check("x9#Kv2$mQ7pL", "alice", Some("alice@example.com")),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn reports_every_violation() {
+        let violations = check("aaaaa", "aaa", Some("aaa@example.com")).unwrap_err();
+        assert!(violations.contains(&Violation::MinLength { min: 8 }));
+        assert!(violations.contains(&Violation::ContainsUsername));
+        assert!(violations.contains(&Violation::ContainsEmail));
+        assert!(
+            violations
+                .iter()
+                .any(|violation| matches!(violation, Violation::TooWeak { .. }))
+        );
+    }
+
+    #[test]
+    fn rejects_username_and_email_in_any_case() {
+        assert_eq!(
+            check("x9#Kv2$mQ7pL-ALICE", "alice", None),
+            Err(vec![Violation::ContainsUsername])
+        );
+        assert_eq!(
+            check("x9#Kv2$mQ7pL-Bobby", "alice", Some("bobby@example.com")),
+            Err(vec![Violation::ContainsEmail])
+        );
+        // Local parts this short would match too many passwords
+        assert_eq!(
+            check("x9#Kv2$mQ7pL-bo", "alice", Some("bo@example.com")),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_over_length_password() {
+        let password = "x9#Kv2$mQ7pL".repeat(11);
+        assert!(exceeds_max_length(&password));
+        assert!(!exceeds_max_length(&password[..128]));
+        assert_eq!(
+            check(&password, "alice", None),
+            Err(vec![Violation::MaxLength { max: 128 }])
+        );
+    }
+
+    #[test]
+    fn rejects_request_sized_password_without_scoring() {
+        // Only reported as too long, even though it is too weak & contains the username
+        let password = "alice".repeat(400_000);
+        assert_eq!(
+            check(&password, "alice", None),
+            Err(vec![Violation::MaxLength { max: 128 }])
+        );
+    }
+}
@@ -0,0 +1,230 @@
+// Per-IP rate limiting
+// * Opt-in, for deployments without a reverse proxy in front - each limited route is configured with
+//   `<requests>/<seconds>`, e.g. `RATE_LIMIT_LOGIN=10/60`:
+//   * `RATE_LIMIT_LOGIN` - `/auth/login`
+//   * `RATE_LIMIT_REGISTER` - `/auth/register`
+//   * `RATE_LIMIT_FORGOT_PASSWORD` - `/auth/forgot_password`
+//   * `RATE_LIMIT_VERIFY_EMAIL` - `/auth/verify_email`
+//   * `RATE_LIMIT_JWT_REFRESH` - `/jwt/refresh`
+// * Token bucket per client IP - up to `<requests>` at once, refilling over `<seconds>`
+//   * IPv6 clients are keyed by their /64, as a single host usually holds the whole prefix
+//   * The peer address is used as-is - `X-Forwarded-For` is not trusted, behind a proxy every client shares its IP
+// * Limited requests get a 429 with `Retry-After`
+// * Buckets are kept in memory, they are reset on restart & not shared across instances
+// * The Unix socket has no peer IP & is never limited
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use tracing::{info, warn};
+
+pub static LOGIN: LazyLock<Option<Limiter>> = LazyLock::new(|| Limiter::from_env("LOGIN"));
+pub static REGISTER: LazyLock<Option<Limiter>> = LazyLock::new(|| Limiter::from_env("REGISTER"));
+pub static FORGOT_PASSWORD: LazyLock<Option<Limiter>> =
+    LazyLock::new(|| Limiter::from_env("FORGOT_PASSWORD"));
+pub static VERIFY_EMAIL: LazyLock<Option<Limiter>> =
+    LazyLock::new(|| Limiter::from_env("VERIFY_EMAIL"));
+pub static JWT_REFRESH: LazyLock<Option<Limiter>> =
+    LazyLock::new(|| Limiter::from_env("JWT_REFRESH"));
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+struct Buckets {
+    buckets: HashMap<IpAddr, Bucket>,
+    pruned_at: Instant,
+}
+
+pub struct Limiter {
+    /// Maximum amount of tokens, i.e. requests at once
+    capacity: f64,
+    /// Time for an empty bucket to be full again
+    period: Duration,
+    state: Mutex<Buckets>,
+}
+
+impl Limiter {
+    fn from_env(route: &str) -> Option<Self> {
+        let env_name = format!("RATE_LIMIT_{route}");
+        let value = std::env::var(&env_name).ok()?;
+
+        let (requests, seconds) = value
+            .split_once('/')
+            .and_then(|(requests, seconds)| {
+                Some((
+                    requests.trim().parse::<u32>().ok()?,
+                    seconds.trim().parse::<u64>().ok()?,
+                ))
+            })
+            .filter(|(requests, seconds)| *requests > 0 && *seconds > 0)
+            .unwrap_or_else(|| panic!("Invalid {env_name} - Expected `<requests>/<seconds>`"));
+
+        info!("Rate limiting {route} to {requests} request(s) per {seconds}s per IP");
+        Some(Self {
+            capacity: f64::from(requests),
+            period: Duration::from_secs(seconds),
+            state: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                pruned_at: Instant::now(),
+            }),
+        })
+    }
+
+    /// Takes a token from the bucket of the IP, returning how long to wait for one if it is empty
+    fn acquire(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let refill_rate = self.capacity / self.period.as_secs_f64();
+        let mut state = self.state.lock().unwrap();
+
+        // Buckets untouched for a whole period are full again - same as not having one
+        if now.duration_since(state.pruned_at) > self.period {
+            let period = self.period;
+            state
+                .buckets
+                .retain(|_, bucket| now.duration_since(bucket.updated_at) < period);
+            state.pruned_at = now;
+        }
+
+        let bucket = state.buckets.entry(ip).or_insert(Bucket {
+            tokens: self.capacity,
+            updated_at: now,
+        });
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = elapsed
+            .mul_add(refill_rate, bucket.tokens)
+            .min(self.capacity);
+        bucket.updated_at = now;
+
+        let acquired = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / refill_rate))
+        };
+        drop(state);
+
+        acquired
+    }
+}
+
+/// Key of the bucket of an IP
+fn bucket_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or_else(
+            || IpAddr::V6((ip.to_bits() & !u128::from(u64::MAX)).into()),
+            IpAddr::V4,
+        ),
+    }
+}
+
+/// Middleware limiting the requests of every client IP
+///
+/// Used with `axum::middleware::from_fn_with_state(&*rate_limit::LOGIN, rate_limit::limit)`
+pub async fn limit(
+    State(limiter): State<&'static Option<Limiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (Some(limiter), Some(ConnectInfo(addr))) = (
+        limiter,
+        request.extensions().get::<ConnectInfo<SocketAddr>>(),
+    ) else {
+        return next.run(request).await;
+    };
+
+    if let Err(retry_after) = limiter.acquire(bucket_key(addr.ip())) {
+        warn!(ip = %addr.ip(), "Rate limited {}", request.uri().path());
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(
+                header::RETRY_AFTER,
+                retry_after.as_secs_f64().ceil().to_string(),
+            )],
+            Json(json!({ "error": "Too many requests - Please try again later" })),
+        )
+            .into_response();
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    fn limiter(requests: u32, seconds: u64) -> Limiter {
+        Limiter {
+            capacity: f64::from(requests),
+            period: Duration::from_secs(seconds),
+            state: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                pruned_at: Instant::now(),
+            }),
+        }
+    }
+
+    #[test]
+    fn limits_once_bucket_is_empty() {
+        let limiter = limiter(2, 60);
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+        assert_eq!(limiter.acquire(ip), Ok(()));
+        assert_eq!(limiter.acquire(ip), Ok(()));
+        let retry_after = limiter.acquire(ip).unwrap_err();
+        // One token refills every 30 seconds
+        assert!(retry_after <= Duration::from_secs(30));
+        assert!(retry_after > Duration::from_secs(29));
+    }
+
+    #[test]
+    fn limits_each_ip_separately() {
+        let limiter = limiter(1, 60);
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let other_ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+        assert_eq!(limiter.acquire(ip), Ok(()));
+        assert!(limiter.acquire(ip).is_err());
+        assert_eq!(limiter.acquire(other_ip), Ok(()));
+    }
+
+    #[test]
+    fn keys_ipv4_by_address() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        assert_eq!(bucket_key(ip), ip);
+    }
+
+    #[test]
+    fn keys_ipv6_by_prefix() {
+        let ip: IpAddr = "2001:db8:1:2:3:4:5:6".parse().unwrap();
+        let same_prefix: IpAddr = "2001:db8:1:2:ffff::1".parse().unwrap();
+        let other_prefix: IpAddr = "2001:db8:1:3:3:4:5:6".parse().unwrap();
+
+        assert_eq!(bucket_key(ip), "2001:db8:1:2::".parse::<IpAddr>().unwrap());
+        assert_eq!(bucket_key(ip), bucket_key(same_prefix));
+        assert_ne!(bucket_key(ip), bucket_key(other_prefix));
+    }
+
+    #[test]
+    fn keys_ipv4_mapped_ipv6_as_ipv4() {
+        let ip = IpAddr::V6(Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped());
+        assert_eq!(bucket_key(ip), IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_ne!(bucket_key(IpAddr::V6(Ipv6Addr::LOCALHOST)), bucket_key(ip));
+    }
+}
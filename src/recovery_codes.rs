@@ -0,0 +1,80 @@
+// Recovery codes
+// * Single-use codes that stand in for a second factor, for when the user lost access to theirs
+// * Generated as a batch - generating a new batch discards the previous one, used or not
+// * Only their hashes are stored, codes are shown once to the user when generated
+//   * Codes are 50 bits of entropy, so a plain hash is enough to keep them from being read off the database
+// * Dashes, spaces & case are ignored when redeeming, so that codes can be typed as they are printed
+
+use rand::{Rng, SeedableRng};
+
+use crate::common::sha256_hex;
+
+/// The amount of codes generated in a batch.
+///
+/// Defaults to 10
+pub const RECOVERY_CODES_COUNT: usize = 10;
+
+/// Alphabet of the codes, without the characters that are easily mistaken for one another (`0/o`, `1/i/l`)
+const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+/// The amount of characters on each side of the dash
+const GROUP_LENGTH: usize = 5;
+
+pub fn generate_codes() -> Vec<String> {
+    // Initialize new RNG every time function gets called
+    // This hopefully ensures that forward secrecy is maintained
+    let mut rng = rand::rngs::StdRng::from_os_rng();
+    let mut group = || {
+        (0..GROUP_LENGTH)
+            .map(|_| ALPHABET[rng.random_range(0..ALPHABET.len())] as char)
+            .collect::<String>()
+    };
+
+    (0..RECOVERY_CODES_COUNT)
+        .map(|_| format!("{}-{}", group(), group()))
+        .collect()
+}
+
+/// Hashes a code for storage, ignoring its formatting
+pub fn hash_code(user_id: u64, code: &str) -> String {
+    let code = code
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect::<String>()
+        .to_lowercase();
+    sha256_hex(format!("{user_id}:{code}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_distinct_codes_from_the_alphabet() {
+        let codes = generate_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES_COUNT);
+        for code in &codes {
+            let (left, right) = code.split_once('-').unwrap();
+            assert_eq!(left.len(), GROUP_LENGTH);
+            assert_eq!(right.len(), GROUP_LENGTH);
+            assert!(
+                left.bytes()
+                    .chain(right.bytes())
+                    .all(|c| ALPHABET.contains(&c))
+            );
+        }
+
+        let mut unique = codes.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), codes.len());
+    }
+
+    #[test]
+    fn hash_ignores_formatting() {
+        let hash = hash_code(1, "abcde-fghjk");
+        assert_eq!(hash_code(1, "ABCDE FGHJK"), hash);
+        assert_eq!(hash_code(1, " abcdefghjk "), hash);
+        assert_ne!(hash_code(2, "abcde-fghjk"), hash);
+        assert_ne!(hash_code(1, "abcde-fghjm"), hash);
+    }
+}
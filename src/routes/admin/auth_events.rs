@@ -0,0 +1,143 @@
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use libsql::Value;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{error, instrument, warn};
+
+use crate::{AppState, common::DATABASE_BUSY_RESPONSE};
+
+/// Maximum amount of events listed at once
+const AUTH_EVENTS_LIST_LIMIT: u64 = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct AuthEventsQueryDto {
+    user_id: Option<u64>,
+    /// e.g. `login` or `password.reset`
+    event: Option<String>,
+    /// `success` or `failure`
+    outcome: Option<String>,
+    ip: Option<String>,
+    /// Only events at or after this time (UNIX seconds)
+    since: Option<u64>,
+    /// Only events before this time (UNIX seconds)
+    until: Option<u64>,
+    /// Only events older than this event ID - pass `next_before` of the previous page
+    before: Option<u64>,
+    limit: Option<u64>,
+}
+
+/// Lists auth events matching the given filters, most recent first
+#[instrument(skip(state))]
+pub async fn get(
+    State(state): State<AppState>,
+    Query(query): Query<AuthEventsQueryDto>,
+) -> impl IntoResponse {
+    if query
+        .outcome
+        .as_deref()
+        .is_some_and(|outcome| !["success", "failure"].contains(&outcome))
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Invalid outcome - Expected `success` or `failure`" })),
+        )
+            .into_response();
+    }
+    let limit = query
+        .limit
+        .unwrap_or(AUTH_EVENTS_LIST_LIMIT)
+        .clamp(1, AUTH_EVENTS_LIST_LIMIT);
+
+    let mut conditions = Vec::new();
+    let mut values: Vec<Value> = Vec::new();
+    let filters = [
+        (
+            "user_id = ?",
+            query.user_id.map(|id| Value::Integer(id as i64)),
+        ),
+        ("event = ?", query.event.map(Value::Text)),
+        ("outcome = ?", query.outcome.map(Value::Text)),
+        ("ip = ?", query.ip.map(Value::Text)),
+        (
+            "created_at >= ?",
+            query.since.map(|t| Value::Integer(t as i64)),
+        ),
+        (
+            "created_at < ?",
+            query.until.map(|t| Value::Integer(t as i64)),
+        ),
+        ("id < ?", query.before.map(|id| Value::Integer(id as i64))),
+    ];
+    for (condition, value) in filters {
+        if let Some(value) = value {
+            conditions.push(condition);
+            values.push(value);
+        }
+    }
+    values.push(Value::Integer(limit as i64));
+
+    let where_clause = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+
+    let Ok(conn) = state.db.connect() else {
+        error!("Unable to connect to database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(mut rows) = conn
+        .query(
+            &format!(
+                "SELECT id, user_id, event, outcome, reason, ip, user_agent, request_id, created_at FROM \"auth_events\" {where_clause} ORDER BY id DESC LIMIT ?"
+            ),
+            values,
+        )
+        .await
+    else {
+        warn!("Unable to query auth events");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let mut events = Vec::new();
+    let mut last_id = None;
+    loop {
+        match rows.next().await {
+            Ok(Some(row)) => {
+                let id = row.get::<u64>(0).unwrap();
+                last_id = Some(id);
+                events.push(json!({
+                    "id": id,
+                    "user_id": row.get::<Option<u64>>(1).unwrap().map(|id| id.to_string()),
+                    "event": row.get::<String>(2).unwrap(),
+                    "outcome": row.get::<String>(3).unwrap(),
+                    "reason": row.get::<Option<String>>(4).unwrap(),
+                    "ip": row.get::<Option<String>>(5).unwrap(),
+                    "user_agent": row.get::<Option<String>>(6).unwrap(),
+                    "request_id": row.get::<Option<String>>(7).unwrap(),
+                    "created_at": row.get::<u64>(8).unwrap(),
+                }));
+            }
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Unable to query auth events, {e}");
+                return DATABASE_BUSY_RESPONSE.clone().into_response();
+            }
+        }
+    }
+
+    // A full page may be followed by more events
+    let next_before = last_id.filter(|_| events.len() as u64 == limit);
+
+    (
+        StatusCode::OK,
+        Json(json!({ "events": events, "next_before": next_before })),
+    )
+        .into_response()
+}
@@ -0,0 +1,36 @@
+use std::fmt::Write;
+
+use axum::{http::header, response::IntoResponse};
+
+use crate::hashing;
+
+/// Exposes runtime metrics in the Prometheus text format
+pub async fn get() -> impl IntoResponse {
+    let mut body = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        writeln!(body, "# HELP {name} {help}").unwrap();
+        writeln!(body, "# TYPE {name} {kind}").unwrap();
+        writeln!(body, "{name} {value}").unwrap();
+    };
+
+    metric(
+        "picoauth_hashing_queue_depth",
+        "gauge",
+        "Password hashes waiting for a slot",
+        hashing::queue_depth() as u64,
+    );
+    metric(
+        "picoauth_hashing_in_progress",
+        "gauge",
+        "Password hashes being computed",
+        hashing::in_progress() as u64,
+    );
+    metric(
+        "picoauth_hashing_rejected_total",
+        "counter",
+        "Requests turned away because the hashing queue was full",
+        hashing::rejected(),
+    );
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
@@ -1,7 +1,14 @@
-use axum::{Router, routing::get};
+use axum::{
+    Router,
+    routing::{get, post},
+};
 
 use crate::AppState;
 
+pub mod auth_events;
+pub mod metrics;
+pub mod outbox;
+pub mod peppers;
 pub mod user;
 pub mod users;
 
@@ -12,4 +19,10 @@ pub fn router() -> Router<AppState> {
             "/user/{user_id}",
             get(user::get).put(user::put).delete(user::delete),
         )
+        .route("/user/{user_id}/unlock", post(user::unlock))
+        .route("/outbox", get(outbox::get))
+        .route("/outbox/{job_id}/retry", post(outbox::retry))
+        .route("/peppers", get(peppers::get))
+        .route("/metrics", get(metrics::get))
+        .route("/auth_events", get(auth_events::get))
 }
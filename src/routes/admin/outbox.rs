@@ -0,0 +1,117 @@
+use std::time::UNIX_EPOCH;
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use libsql::params;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{error, instrument, warn};
+
+use crate::{
+    AppState,
+    common::DATABASE_BUSY_RESPONSE,
+    outbox::{STATUS_DEAD, STATUS_DELIVERED, STATUS_PENDING},
+};
+
+/// Maximum amount of jobs listed at once
+const OUTBOX_LIST_LIMIT: u64 = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct OutboxQueryDto {
+    /// Defaults to `dead`
+    status: Option<String>,
+    limit: Option<u64>,
+}
+
+/// Lists outbox jobs of the given status, most recent first.
+/// Payloads are left out as they may contain secrets (e.g. reset links).
+#[instrument(skip(state))]
+pub async fn get(
+    State(state): State<AppState>,
+    Query(query): Query<OutboxQueryDto>,
+) -> impl IntoResponse {
+    let status = query.status.unwrap_or_else(|| STATUS_DEAD.to_string());
+    if ![STATUS_PENDING, STATUS_DELIVERED, STATUS_DEAD].contains(&status.as_str()) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Invalid status - Expected `pending`, `delivered` or `dead`" })),
+        )
+            .into_response();
+    }
+    let limit = query
+        .limit
+        .unwrap_or(OUTBOX_LIST_LIMIT)
+        .min(OUTBOX_LIST_LIMIT);
+
+    let Ok(conn) = state.db.connect() else {
+        error!("Unable to connect to database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(mut rows) = conn
+        .query(
+            "SELECT id, kind, status, attempts, next_attempt_at, last_error, created_at, delivered_at FROM \"outbox\" WHERE status = ? ORDER BY id DESC LIMIT ?",
+            params![status, limit],
+        )
+        .await
+    else {
+        warn!("Unable to query outbox");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let mut jobs = Vec::new();
+    loop {
+        match rows.next().await {
+            Ok(Some(row)) => jobs.push(json!({
+                "id": row.get::<u64>(0).unwrap(),
+                "kind": row.get::<String>(1).unwrap(),
+                "status": row.get::<String>(2).unwrap(),
+                "attempts": row.get::<u64>(3).unwrap(),
+                "next_attempt_at": row.get::<u64>(4).unwrap(),
+                "last_error": row.get::<Option<String>>(5).unwrap(),
+                "created_at": row.get::<u64>(6).unwrap(),
+                "delivered_at": row.get::<Option<u64>>(7).unwrap(),
+            })),
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Unable to query outbox, {e}");
+                return DATABASE_BUSY_RESPONSE.clone().into_response();
+            }
+        }
+    }
+
+    (StatusCode::OK, Json(json!({ "jobs": jobs }))).into_response()
+}
+
+/// Schedules an undelivered job to be retried right away, resetting its attempts
+#[instrument(skip(state))]
+pub async fn retry(State(state): State<AppState>, Path(job_id): Path<u64>) -> impl IntoResponse {
+    let Ok(conn) = state.db.connect() else {
+        error!("Unable to connect to database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    match conn
+        .execute(
+            "UPDATE \"outbox\" SET status = ?, attempts = 0, next_attempt_at = ? WHERE id = ? AND status != ?",
+            params![
+                STATUS_PENDING,
+                UNIX_EPOCH.elapsed().unwrap().as_secs(),
+                job_id,
+                STATUS_DELIVERED
+            ],
+        )
+        .await
+    {
+        Ok(0) => StatusCode::NOT_FOUND.into_response(),
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            warn!("Unable to reschedule outbox job, {e}");
+            DATABASE_BUSY_RESPONSE.clone().into_response()
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use axum::{Json, extract::State, response::IntoResponse};
+use serde_json::json;
+use tracing::{error, instrument, warn};
+
+use crate::{
+    AppState,
+    common::DATABASE_BUSY_RESPONSE,
+    password::{PEPPERS, UNPEPPERED, current_pepper},
+};
+
+/// Counts the password hashes using each pepper, telling whether an old pepper can be retired.
+/// Peppers still in use but missing from the configuration are listed as well - their users cannot log in.
+#[instrument(skip(state))]
+pub async fn get(State(state): State<AppState>) -> impl IntoResponse {
+    let Ok(conn) = state.db.connect() else {
+        error!("Unable to connect to database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(mut rows) = conn
+        .query(
+            "SELECT password_pepper, COUNT(*) FROM \"users\" GROUP BY password_pepper",
+            (),
+        )
+        .await
+    else {
+        warn!("Unable to query password peppers");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let mut counts: Vec<(Option<String>, u64)> = Vec::new();
+    loop {
+        match rows.next().await {
+            Ok(Some(row)) => counts.push((
+                row.get::<Option<String>>(0).unwrap(),
+                row.get::<u64>(1).unwrap(),
+            )),
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Unable to read password peppers, {e}");
+                return DATABASE_BUSY_RESPONSE.clone().into_response();
+            }
+        }
+    }
+
+    let count_of = |id: Option<&str>| {
+        counts
+            .iter()
+            .find(|(pepper, _)| pepper.as_deref() == id)
+            .map_or(0, |(_, count)| *count)
+    };
+
+    let mut peppers: Vec<_> = PEPPERS
+        .iter()
+        .map(|pepper| {
+            json!({
+                "id": pepper.id,
+                "configured": true,
+                "hashes": count_of(Some(pepper.id)),
+            })
+        })
+        .collect();
+    // Hashes without pepper always verify, but are re-hashed with the current one if there is one
+    peppers.push(json!({
+        "id": UNPEPPERED,
+        "configured": true,
+        "hashes": count_of(Some(UNPEPPERED)),
+    }));
+    peppers.extend(
+        counts
+            .iter()
+            .filter_map(|(id, count)| Some((id.as_deref()?, count)))
+            .filter(|(id, _)| *id != UNPEPPERED && !PEPPERS.iter().any(|pepper| pepper.id == *id))
+            .map(|(id, count)| {
+                json!({
+                    "id": id,
+                    "configured": false,
+                    "hashes": count,
+                })
+            }),
+    );
+
+    Json(json!({
+        "current": current_pepper().id,
+        "peppers": peppers,
+        // Hashes imported in other formats never use a pepper, see `legacy_hash.rs`
+        "legacy_hashes": count_of(None),
+    }))
+    .into_response()
+}
@@ -5,10 +5,10 @@ use axum::{
     response::IntoResponse,
 };
 use libsql::params;
-use serde_json::{Map, Value};
+use serde_json::{Map, Value, json};
 use tracing::{error, instrument, warn};
 
-use crate::{AppState, common::DATABASE_BUSY_RESPONSE};
+use crate::{AppState, common::DATABASE_BUSY_RESPONSE, lockout, outbox, webhook::Event};
 
 #[instrument(skip(state))]
 pub async fn get(State(state): State<AppState>, Path(user_id): Path<u64>) -> impl IntoResponse {
@@ -19,7 +19,7 @@ pub async fn get(State(state): State<AppState>, Path(user_id): Path<u64>) -> imp
 
     let Ok(mut q) = conn
         .query(
-            "SELECT id, username, display_name, email FROM \"users\" WHERE id = ?",
+            "SELECT id, username, display_name, email, failed_login_attempts, locked_until FROM \"users\" WHERE id = ?",
             params![user_id],
         )
         .await
@@ -56,6 +56,15 @@ pub async fn get(State(state): State<AppState>, Path(user_id): Path<u64>) -> imp
         email.map_or(Value::Null, Value::String),
     );
 
+    data.insert(
+        "failed_login_attempts".to_string(),
+        Value::from(user.get::<u32>(4).unwrap()),
+    );
+    data.insert(
+        "locked_until".to_string(),
+        Value::from(user.get::<Option<u64>>(5).unwrap()),
+    );
+
     (StatusCode::OK, Json(data)).into_response()
 }
 
@@ -71,19 +80,25 @@ pub async fn delete(State(state): State<AppState>, Path(user_id): Path<u64>) ->
         return DATABASE_BUSY_RESPONSE.clone().into_response();
     };
 
-    let Ok(mut q) = conn
+    let Ok(txn) = conn.transaction().await else {
+        warn!("Unable to initialize a transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(mut q) = txn
         .query(
             "DELETE FROM \"users\" WHERE id = ? RETURNING id, username, display_name, email",
             params![user_id],
         )
         .await
     else {
+        txn.rollback().await.ok();
         return DATABASE_BUSY_RESPONSE.clone().into_response();
     };
 
     let Ok(Some(user)) = q.next().await else {
-        warn!("User deleted but unable to fetch returned user data");
-        return (StatusCode::NO_CONTENT).into_response();
+        txn.rollback().await.ok();
+        return (StatusCode::NOT_FOUND).into_response();
     };
 
     let mut data = Map::new();
@@ -108,5 +123,42 @@ pub async fn delete(State(state): State<AppState>, Path(user_id): Path<u64>) ->
         email.map_or(Value::Null, Value::String),
     );
 
+    let event = Event::new(
+        "user.deleted",
+        json!({
+            "user_id": user_id.to_string(),
+            "username": data["username"],
+            "email": data["email"],
+        }),
+    );
+    if let Err(e) = outbox::enqueue_event(&txn, &state.webhooks, event).await {
+        txn.rollback().await.ok();
+        warn!("Unable to queue webhook event, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    if txn.commit().await.is_err() {
+        warn!("Unable to commit transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
     (StatusCode::NO_CONTENT, Json(data)).into_response()
 }
+
+/// Clears the failed logins & lock of a user
+#[instrument(skip(state))]
+pub async fn unlock(State(state): State<AppState>, Path(user_id): Path<u64>) -> impl IntoResponse {
+    let Ok(conn) = state.db.connect() else {
+        error!("Unable to connect to database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    match lockout::reset(&conn, user_id).await {
+        Ok(0) => StatusCode::NOT_FOUND.into_response(),
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            warn!("Unable to unlock user, {e}");
+            DATABASE_BUSY_RESPONSE.clone().into_response()
+        }
+    }
+}
@@ -1,5 +1,208 @@
+use std::time::UNIX_EPOCH;
+
+use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use libsql::params;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{error, instrument, warn};
+
+use crate::{
+    AppState,
+    common::{DATABASE_BUSY_RESPONSE, is_unique_violation},
+    email, hashing, legacy_hash, outbox, password,
+    templates::LOCALE_REGEX,
+    username,
+    webhook::Event,
+};
+
 pub async fn get() {}
 
-pub async fn post() {}
+#[derive(Deserialize)]
+pub struct CreateUserDto {
+    username: String,
+    /// Plaintext password, hashed with the current parameters
+    password: Option<String>,
+    /// Existing hash, e.g. imported from another system - see `legacy_hash.rs` for the supported formats
+    password_hash: Option<String>,
+
+    email: Option<String>,
+    /// Whether the email has already been verified by the other system
+    #[serde(default)]
+    email_verified: bool,
+    display_name: Option<String>,
+    locale: Option<String>,
+}
+
+/// Creates a user, either with a password or with an imported password hash
+/// Imported hashes are upgraded to Argon2id on the first successful login of the user
+#[instrument(skip(state, dto), fields(username = %dto.username))]
+pub async fn post(
+    State(state): State<AppState>,
+    Json(dto): Json<CreateUserDto>,
+) -> impl IntoResponse {
+    let username = match username::parse(&dto.username) {
+        Ok(username) => username,
+        Err(error) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response();
+        }
+    };
+    if dto
+        .email
+        .as_deref()
+        .is_some_and(|email| !email::is_valid(email))
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Invalid email" })),
+        )
+            .into_response();
+    }
+    if dto
+        .locale
+        .as_ref()
+        .is_some_and(|locale| !LOCALE_REGEX.is_match(locale))
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Invalid locale" })),
+        )
+            .into_response();
+    }
+
+    // Imported hashes are not peppered - Argon2 ones are marked as such, so that they are verified without the
+    // configured pepper & re-hashed with it on the next login. Other formats never use a pepper, see `legacy_hash.rs`
+    let (password_hash, pepper) = match (dto.password, dto.password_hash) {
+        (Some(password), None) => {
+            let hashed = match hashing::run(move || password::hash(&password)).await {
+                Ok(hashed) => hashed,
+                Err(busy) => return busy.into_response(),
+            };
+            (hashed.hash.into_string(), Some(hashed.pepper))
+        }
+        (None, Some(hash)) if hash.starts_with("$argon2") => (hash, Some(password::UNPEPPERED)),
+        (None, Some(hash)) if legacy_hash::is_supported(&hash) => (hash, None),
+        (None, Some(_)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "Unsupported password hash format" })),
+            )
+                .into_response();
+        }
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "Exactly one of `password` & `password_hash` is required" })),
+            )
+                .into_response();
+        }
+    };
+
+    let Ok(conn) = state.db.connect() else {
+        error!("Unable to connect to database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(is_taken) = username::is_taken(&conn, &username).await else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+    if is_taken {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "Username already taken" })),
+        )
+            .into_response();
+    }
+    if let Some(email) = &dto.email {
+        let Ok(is_taken) = email::is_taken(&conn, email, None).await else {
+            warn!("Database query failed!");
+            return DATABASE_BUSY_RESPONSE.clone().into_response();
+        };
+        if is_taken {
+            return (
+                StatusCode::CONFLICT,
+                Json(json!({ "error": "Email already in use" })),
+            )
+                .into_response();
+        }
+    }
+
+    let email_verified = dto.email_verified && dto.email.is_some();
+
+    let Ok(txn) = conn.transaction().await else {
+        warn!("Unable to initialize a transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let inserted = match txn
+        .query(
+            "INSERT INTO \"users\" (username, username_canonical, username_skeleton, password, password_pepper, email, email_normalized, email_verified_at, display_name, locale) VALUES (?, ?, ?, ?, ?, ?, ?, CASE WHEN ? THEN ? END, ?, ?) RETURNING id",
+            params![
+                username.display.clone(),
+                username.canonical,
+                username.skeleton,
+                password_hash,
+                pepper,
+                dto.email.clone(),
+                dto.email.as_deref().map(email::normalize),
+                email_verified,
+                UNIX_EPOCH.elapsed().unwrap().as_secs(),
+                dto.display_name.clone(),
+                dto.locale
+            ],
+        )
+        .await
+    {
+        Ok(mut rows) => rows.next().await,
+        Err(e) => Err(e),
+    };
+    let inserted = match inserted {
+        Ok(row) => row,
+        // Taken by a concurrent request since it was checked
+        Err(e) if is_unique_violation(&e) => {
+            txn.rollback().await.ok();
+            return (
+                StatusCode::CONFLICT,
+                Json(json!({ "error": "Username or email already taken" })),
+            )
+                .into_response();
+        }
+        Err(e) => {
+            warn!("Unable to insert user into database: {e}");
+            None
+        }
+    };
+
+    let Some(user_id) = inserted.map(|row| row.get::<u64>(0).unwrap()) else {
+        txn.rollback().await.ok();
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let event = Event::new(
+        "user.registered",
+        json!({
+            "user_id": user_id.to_string(),
+            "username": username.display,
+            "email": dto.email,
+            "display_name": dto.display_name,
+        }),
+    );
+    if let Err(e) = outbox::enqueue_event(&txn, &state.webhooks, event).await {
+        txn.rollback().await.ok();
+        warn!("Unable to queue webhook event, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    if txn.commit().await.is_err() {
+        warn!("Unable to commit transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    (
+        StatusCode::CREATED,
+        Json(json!({ "id": user_id.to_string() })),
+    )
+        .into_response()
+}
 
 pub async fn delete() {}
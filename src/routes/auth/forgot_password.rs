@@ -5,12 +5,19 @@
 // * This is user-facing endpoint; Resetting password will require a side-channel (email, SMS, etc.)
 // * Going to store URL tokens with expiry in the database
 //   * Tokens MUST be long & crypto safe
+// * Accounts are looked up by username or email, see `email.rs`
+// * Reset links are only mailed to verified emails - an unverified one may belong to someone else
+//   * Other services still get the `password.reset_requested` event, e.g. to reach the user another way
+// * Resetting the password revokes every session of the user, like changing it does (see `password.rs`)
 // TODO: MFA Should be enforced - think of how to handle this
 
 // Frontend notes
 // * Frontend SHOULD handle rate-limiting mechanism
 
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{
+    sync::LazyLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use axum::{
     Json,
@@ -19,21 +26,31 @@ use axum::{
     response::IntoResponse,
 };
 use libsql::{TransactionBehavior, params};
-use rand::{distr, prelude::*};
+use minijinja::context;
 use serde::Deserialize;
-use tracing::{instrument, warn};
-
-use crate::{AppState, common::DATABASE_BUSY_RESPONSE, password};
+use serde_json::json;
+use tracing::{error, instrument, warn};
+
+use crate::{
+    AppState,
+    audit::{self, Outcome, RequestMeta},
+    clients::{self, Action, LinkOptionsDto},
+    common::{BREACHED_PASSWORD_RESPONSE, DATABASE_BUSY_RESPONSE, generate_url_token},
+    email, hashing, hibp, outbox, password, password_policy, templates, timing, username,
+    webhook::Event,
+};
 
 /// The amount of time that a forgot password token is deemed "Active" / redeemable.
 ///
 /// Defaults to 24 Hours
 const FORGOT_PASSWORD_TOKEN_DURATION: Duration = Duration::from_secs(24 * 3600);
-/// The minimum amount of time for a forgot password submission to respond to.
+/// The minimum amount of time for a forgot password submission to respond to, in milliseconds.
+/// Configured with the `FORGOT_PASSWORD_MINIMUM_TIME` environment variable.
 /// Used to prevent probing / timing attack.
 ///
 /// Defaults to 200ms
-const FORGOT_PASSWORD_MINIMUM_TIME: Duration = Duration::from_millis(200);
+pub static FORGOT_PASSWORD_MINIMUM_TIME: LazyLock<Duration> =
+    LazyLock::new(|| timing::from_env("FORGOT_PASSWORD_MINIMUM_TIME", Duration::from_millis(200)));
 /// The minimum amount of time in-between forgot password tokens generation.
 /// Used to prevent spam on the same user
 ///
@@ -47,78 +64,148 @@ const FORGOT_PASSWORD_MAX_ACTIVE_TOKEN: u64 = 3;
 
 #[derive(Deserialize)]
 pub struct ForgotPasswordSubmitDto {
+    /// Username, or email - see `email.rs`
     username: String,
+    #[serde(flatten)]
+    link: LinkOptionsDto,
 }
 /// Submit a new forgot password request
-#[instrument(skip(state, req))]
+/// Responses are padded to `FORGOT_PASSWORD_MINIMUM_TIME`, see `routes/auth/mod.rs`
+#[instrument(skip(state, meta, req))]
 pub async fn post(
     State(state): State<AppState>,
+    meta: RequestMeta,
     Json(req): Json<ForgotPasswordSubmitDto>,
 ) -> impl IntoResponse {
-    let minimum_time = std::env::var("FORGOT_PASSWORD_MINIMUM_TIME")
-        .map_or(FORGOT_PASSWORD_MINIMUM_TIME, |t| {
-            Duration::from_millis(t.parse().unwrap())
-        });
-    let deadline = Instant::now() + minimum_time;
+    let (client, redirect_to) = match req.link.resolve() {
+        Ok(resolved) => resolved,
+        Err(response) => return response,
+    };
 
     // Process
     let Ok(conn) = state.db.connect() else {
         warn!("Unable to connect to the database");
-        tokio::time::sleep_until(deadline.into()).await;
         return DATABASE_BUSY_RESPONSE.clone().into_response();
     };
 
+    // The username wins over an email equal to it, see `login.rs`
+    let canonical = username::canonicalize(&req.username);
     let Ok(mut rows) = conn
         .query(
-            "SELECT id FROM \"users\" WHERE username = ?",
-            params![req.username.clone()],
+            "SELECT id, username, display_name, email, locale, email_verified_at FROM \"users\" WHERE username_canonical = ? OR (email_normalized = ? AND (? OR email_verified_at IS NOT NULL)) ORDER BY username_canonical = ? DESC LIMIT 1",
+            params![
+                canonical.clone(),
+                email::normalize(&req.username),
+                *email::LOGIN_UNVERIFIED_EMAILS,
+                canonical
+            ],
         )
         .await
     else {
         warn!("Unable to query for user existence");
-        tokio::time::sleep_until(deadline.into()).await;
         return DATABASE_BUSY_RESPONSE.clone().into_response();
     };
 
     let Ok(row) = rows.next().await else {
         warn!("Unable to query for user existence");
-        tokio::time::sleep_until(deadline.into()).await;
         return DATABASE_BUSY_RESPONSE.clone().into_response();
     };
 
     // If user exists, do whole processing, otherwise skip this whole block
     if let Some(row) = row {
         let user_id = row.get::<u64>(0).unwrap();
+        let username = row.get::<String>(1).unwrap();
+        let display_name = row.get::<Option<String>>(2).unwrap();
+        let locale = row.get::<Option<String>>(4).unwrap();
+        // Unverified emails are left out, as if the user had none
+        let email = row
+            .get::<Option<String>>(3)
+            .unwrap()
+            .filter(|_| row.get::<Option<u64>>(5).unwrap().is_some());
 
-        // Generate token
-        // Initialize new RNG every time function gets called
-        // This hopefully ensures that forward secrecy is maintained
-        let rng = rand::rngs::StdRng::from_os_rng();
-        let token: String = rng
-            .sample_iter(distr::Alphanumeric)
-            .take(64)
-            .map(char::from)
-            .collect();
+        let token = generate_url_token();
 
         let expire_time =
             UNIX_EPOCH.elapsed().unwrap().as_secs() + FORGOT_PASSWORD_TOKEN_DURATION.as_secs();
 
+        let message = match templates::render(
+            templates::PASSWORD_RESET,
+            locale.as_deref(),
+            email.clone().unwrap_or_default(),
+            &context! {
+                username,
+                display_name,
+                action_url => client.url(Action::ForgotPassword, &token, redirect_to.as_deref()),
+                expires_in_minutes => FORGOT_PASSWORD_TOKEN_DURATION.as_secs() / 60,
+            },
+        ) {
+            Ok(message) => message,
+            Err(e) => {
+                error!("Unable to render password reset message, {e}");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+
+        let Ok(txn) = conn.transaction().await else {
+            warn!("Unable to initialize a transaction");
+            return DATABASE_BUSY_RESPONSE.clone().into_response();
+        };
+
         // Store token in database
-        if let Err(e) = conn.execute(
-            "INSERT INTO \"forgot_password_token\" (token, user_id, expires_at) VALUES (?, ?, ?)",
-            params![token, user_id, expire_time],
+        if let Err(e) = txn.execute(
+            "INSERT INTO \"forgot_password_token\" (token, user_id, expires_at, redirect_to) VALUES (?, ?, ?, ?)",
+            params![token.clone(), user_id, expire_time, redirect_to.clone()],
         )
         .await {
+            txn.rollback().await.ok();
             warn!("Unable to store password token in database, {e}");
-            tokio::time::sleep_until(deadline.into()).await;
             return DATABASE_BUSY_RESPONSE.clone().into_response();
         }
 
-        // TODO: Enqueue side-channel message
-    }
+        // Let other services handle the message too, e.g. for users without an email
+        let event = Event::new(
+            "password.reset_requested",
+            json!({
+                "user_id": user_id.to_string(),
+                "username": username,
+                "email": email,
+                "token": token,
+                "expires_at": expire_time,
+                "redirect_to": redirect_to,
+                "locale": locale,
+                "message": {
+                    "subject": message.subject,
+                    "body": message.body,
+                    "html": message.html,
+                },
+            }),
+        );
+        let mut queued = outbox::enqueue_event(&txn, &state.webhooks, event).await;
+        if queued.is_ok() && email.is_some() {
+            queued = outbox::enqueue_message(&txn, &message).await;
+        }
+
+        if let Err(e) = queued {
+            txn.rollback().await.ok();
+            warn!("Unable to queue password reset message, {e}");
+            return DATABASE_BUSY_RESPONSE.clone().into_response();
+        }
 
-    // Wait until minimum time has passed
-    tokio::time::sleep_until(deadline.into()).await;
+        if txn.commit().await.is_err() {
+            warn!("Unable to commit transaction");
+            return DATABASE_BUSY_RESPONSE.clone().into_response();
+        }
+
+        audit::record(
+            &conn,
+            &meta,
+            Some(user_id),
+            "password.reset_requested",
+            Outcome::Success,
+            None,
+        )
+        .await;
+    }
 
     // Send response
     (StatusCode::NO_CONTENT).into_response()
@@ -135,7 +222,7 @@ pub async fn get(State(state): State<AppState>, Path(token): Path<String>) -> im
 
     let Ok(mut rows) = conn
         .query(
-            "SELECT expires_at, used_at FROM \"forgot_password_token\" WHERE token = ?",
+            "SELECT expires_at, used_at, redirect_to FROM \"forgot_password_token\" WHERE token = ?",
             params![token],
         )
         .await
@@ -165,7 +252,12 @@ pub async fn get(State(state): State<AppState>, Path(token): Path<String>) -> im
         return (StatusCode::GONE).into_response();
     }
 
-    StatusCode::NO_CONTENT.into_response()
+    let redirect_to = row.get::<Option<String>>(2).unwrap();
+    (
+        StatusCode::NO_CONTENT,
+        clients::redirect_header(redirect_to),
+    )
+        .into_response()
 }
 
 #[derive(Deserialize)]
@@ -176,6 +268,7 @@ pub struct ForgotPasswordExecuteDto {
 /// Does not need to contain deadline as user will be probing for a CSPRNG generated token
 pub async fn put(
     State(state): State<AppState>,
+    meta: RequestMeta,
     Path(token): Path<String>,
     Json(req): Json<ForgotPasswordExecuteDto>,
 ) -> impl IntoResponse {
@@ -184,88 +277,206 @@ pub async fn put(
         return DATABASE_BUSY_RESPONSE.clone().into_response();
     };
 
-    let Ok(txn) = conn
-        .transaction_with_behavior(TransactionBehavior::Deferred)
-        .await
-    else {
-        warn!("Unable to initialize a transaction");
-        return DATABASE_BUSY_RESPONSE.clone().into_response();
-    };
-
-    let Ok(mut rows) = txn
+    // Checked without a transaction first, the breach lookup & hashing should not hold the database lock
+    let Ok(mut rows) = conn
         .query(
-            "SELECT user_id, expires_at, used_at FROM \"forgot_password_token\" WHERE token = ?",
+            "SELECT t.user_id, t.expires_at, t.used_at, t.redirect_to, u.username, u.email FROM \"forgot_password_token\" t JOIN \"users\" u ON u.id = t.user_id WHERE t.token = ?",
             params![token.clone()],
         )
         .await
     else {
-        txn.rollback().await.ok();
         warn!("Unable to query for token existence");
         return DATABASE_BUSY_RESPONSE.clone().into_response();
     };
 
     let Ok(row) = rows.next().await else {
-        txn.rollback().await.ok();
         return DATABASE_BUSY_RESPONSE.clone().into_response();
     };
 
     let Some(row) = row else {
-        txn.rollback().await.ok();
         return (StatusCode::NOT_FOUND).into_response();
     };
 
     let user_id = row.get::<u64>(0).unwrap();
+    let redirect_to = row.get::<Option<String>>(3).unwrap();
 
     // Check if token has been used
-    if !row.get_value(1).unwrap().is_null() {
-        txn.rollback().await.ok();
+    if !row.get_value(2).unwrap().is_null() {
         return (StatusCode::GONE).into_response();
     }
 
     // Check if token is expired
-    let expirity_time = UNIX_EPOCH + Duration::from_secs(row.get::<u64>(0).unwrap());
+    let expirity_time = UNIX_EPOCH + Duration::from_secs(row.get::<u64>(1).unwrap());
     if SystemTime::now() > expirity_time {
-        txn.rollback().await.ok();
         return (StatusCode::GONE).into_response();
     }
 
+    let db_username = row.get::<String>(4).unwrap();
+    let db_email = row.get::<Option<String>>(5).unwrap();
+    drop(rows);
+
+    if let Err(violations) =
+        password_policy::check(&req.password, &db_username, db_email.as_deref())
+    {
+        return password_policy::rejection(&violations);
+    }
+    if hibp::is_breached(&req.password).await {
+        return BREACHED_PASSWORD_RESPONSE.clone().into_response();
+    }
+
     // --------------
     // Update password
     // Hash password
-    let password_hash = tokio::task::spawn_blocking(move || password::hash(&req.password))
+    let hashed = match hashing::run(move || password::hash(&req.password)).await {
+        Ok(hashed) => hashed,
+        Err(busy) => return busy.into_response(),
+    };
+
+    let Ok(txn) = conn
+        .transaction_with_behavior(TransactionBehavior::Immediate)
         .await
-        .unwrap();
+    else {
+        warn!("Unable to initialize a transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
 
-    // Update user password
-    if let Err(e) = txn
+    // Mark token as used, unless it has been used or has expired in the meantime
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
+    match txn
         .execute(
-            "UPDATE \"users\" SET password = ? WHERE id = ?",
-            params![password_hash, user_id],
+            "UPDATE \"forgot_password_token\" SET used_at = ? WHERE token = ? AND used_at IS NULL AND expires_at >= ?",
+            params![current_time, token, current_time],
         )
         .await
     {
-        txn.rollback().await.ok();
-        warn!("Unable to update user password, {e}");
-        return DATABASE_BUSY_RESPONSE.clone().into_response();
+        Ok(1) => {}
+        Ok(_) => {
+            txn.rollback().await.ok();
+            return (StatusCode::GONE).into_response();
+        }
+        Err(e) => {
+            txn.rollback().await.ok();
+            warn!("Unable to mark token as used, {e}");
+            return DATABASE_BUSY_RESPONSE.clone().into_response();
+        }
     }
 
-    // Mark token as used
+    // Update user password, revoking every session - some may be the ones of whoever knew the old password
     if let Err(e) = txn
         .execute(
-            "UPDATE \"forgot_password_token\" SET used_at = ? WHERE token = ?",
-            params![UNIX_EPOCH.elapsed().unwrap().as_secs(), token],
+            "UPDATE \"users\" SET password = ?, password_pepper = ?, failed_login_attempts = 0, locked_until = NULL, sessions_revoked_at = ? WHERE id = ?",
+            params![hashed.hash, hashed.pepper, current_time, user_id],
         )
         .await
     {
         txn.rollback().await.ok();
-        warn!("Unable to mark token as used, {e}");
+        warn!("Unable to update user password, {e}");
         return DATABASE_BUSY_RESPONSE.clone().into_response();
     }
 
-    if let Ok(_) = txn.commit().await {
-        StatusCode::OK.into_response()
-    } else {
+    if let Err(e) = outbox::enqueue_event(
+        &txn,
+        &state.webhooks,
+        Event::new("password.reset", json!({ "user_id": user_id.to_string() })),
+    )
+    .await
+    {
+        txn.rollback().await.ok();
+        warn!("Unable to queue webhook event, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    if txn.commit().await.is_err() {
         warn!("Unable to commit transaction");
-        DATABASE_BUSY_RESPONSE.clone().into_response()
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    audit::record(
+        &conn,
+        &meta,
+        Some(user_id),
+        "password.reset",
+        Outcome::Success,
+        None,
+    )
+    .await;
+    (StatusCode::OK, clients::redirect_header(redirect_to)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::test_state;
+
+    #[tokio::test]
+    async fn mails_verified_emails_only() {
+        let state = test_state().await;
+        let conn = state.db.connect().unwrap();
+        conn.execute(
+            "INSERT INTO \"users\" (id, username, username_canonical, password, email, email_normalized, email_verified_at) VALUES (1, 'alice', 'alice', '', 'alice@example.com', 'alice@example.com', NULL), (2, 'bob', 'bob', '', 'bob@example.com', 'bob@example.com', 1)",
+            (),
+        )
+        .await
+        .unwrap();
+
+        for username in ["alice", "bob"] {
+            let req = serde_json::from_value(json!({ "username": username })).unwrap();
+            let response = post(State(state.clone()), RequestMeta::default(), Json(req))
+                .await
+                .into_response();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        }
+
+        let mut rows = conn
+            .query(
+                "SELECT payload FROM \"outbox\" WHERE kind = ?",
+                params![outbox::KIND_NOTIFICATION],
+            )
+            .await
+            .unwrap();
+        let message = rows
+            .next()
+            .await
+            .unwrap()
+            .unwrap()
+            .get::<String>(0)
+            .unwrap();
+        assert!(message.contains("bob@example.com"));
+        assert!(rows.next().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn redeems_token_once() {
+        let state = test_state().await;
+        let conn = state.db.connect().unwrap();
+        conn.execute(
+            "INSERT INTO \"users\" (id, username, username_canonical, password) VALUES (1, 'alice', 'alice', '')",
+            (),
+        )
+        .await
+        .unwrap();
+        conn.execute(
+            "INSERT INTO \"forgot_password_token\" (token, user_id, expires_at) VALUES ('token', 1, ?)",
+            params![UNIX_EPOCH.elapsed().unwrap().as_secs() + 60],
+        )
+        .await
+        .unwrap();
+
+        let reset = || async {
+            let req = ForgotPasswordExecuteDto {
+                password: "a rather long passphrase".to_string(),
+            };
+            put(
+                State(state.clone()),
+                RequestMeta::default(),
+                Path("token".to_string()),
+                Json(req),
+            )
+            .await
+            .into_response()
+            .status()
+        };
+        assert_eq!(reset().await, StatusCode::OK);
+        assert_eq!(reset().await, StatusCode::GONE);
     }
 }
@@ -1,172 +1,267 @@
-use std::time::UNIX_EPOCH;
+use std::{
+    sync::LazyLock,
+    time::{Duration, UNIX_EPOCH},
+};
 
-use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use axum::{Json, extract::State, response::IntoResponse};
 use libsql::params;
 use serde::Deserialize;
-use serde_json::json;
 use tracing::{instrument, warn};
 
 use crate::{
     AppState,
+    audit::{self, Outcome, RequestMeta},
     common::{DATABASE_BUSY_RESPONSE, INVALID_USERNAME_PASSWORD_RESPONSE},
-    jwt,
-    password::verify,
-    totp,
+    email, hashing, lockout,
+    password::{self, Verification},
+    password_policy,
+    routes::auth::login_mfa,
+    session, timing, username,
 };
 
+/// The minimum amount of time for a login attempt to respond to, in milliseconds.
+/// Configured with the `LOGIN_MINIMUM_TIME` environment variable.
+/// Should be above the time taken by a password verification, see `ARGON2_CALIBRATE`.
+///
+/// Defaults to 300ms
+pub static LOGIN_MINIMUM_TIME: LazyLock<Duration> =
+    LazyLock::new(|| timing::from_env("LOGIN_MINIMUM_TIME", Duration::from_millis(300)));
+
 #[derive(Deserialize)]
 pub struct UserLoginDto {
+    /// Username, or email - see `email.rs`
     username: String,
     password: String,
-    totp: Option<String>,
 }
 
-#[instrument(skip(state, dto), fields(username = %dto.username))]
+/// Responses are padded to `LOGIN_MINIMUM_TIME`, see `routes/auth/mod.rs`
+#[instrument(skip(state, meta, dto), fields(username = %dto.username))]
 pub async fn post(
     State(state): State<AppState>,
+    meta: RequestMeta,
     Json(dto): Json<UserLoginDto>,
 ) -> impl IntoResponse {
     let db = state.db.connect().unwrap();
 
     let username = dto.username;
     let password = dto.password;
-    let totp = dto.totp;
 
-    // Select username case-insensitively
+    // Over-length passwords cannot match - turn them away before spending any hashing work on them
+    if password_policy::exceeds_max_length(&password) {
+        audit::record(
+            &db,
+            &meta,
+            None,
+            "login",
+            Outcome::Failure,
+            Some("password_too_long"),
+        )
+        .await;
+        return INVALID_USERNAME_PASSWORD_RESPONSE.clone().into_response();
+    }
+
+    // Select user by the canonical, case-insensitive form of its username, or by its normalized email
+    // The username wins - an email equal to another user's username (stored before emails were validated) must not
+    // lock that user out
+    let canonical = username::canonicalize(&username);
     let Ok(mut query) = db
         .query(
-            "SELECT username, password, requires_second_factor FROM \"users\" WHERE username = ? COLLATE NOCASE",
-            params![username.clone()],
+            "SELECT id, username, password, requires_second_factor, password_pepper, failed_login_attempts, locked_until, username_canonical = ? FROM \"users\" WHERE username_canonical = ? OR (email_normalized = ? AND (? OR email_verified_at IS NOT NULL)) ORDER BY username_canonical = ? DESC",
+            params![
+                canonical.clone(),
+                canonical.clone(),
+                email::normalize(&username),
+                *email::LOGIN_UNVERIFIED_EMAILS,
+                canonical
+            ],
         )
         .await
     else {
         warn!("Database query failed!");
-        return DATABASE_BUSY_RESPONSE.clone();
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
     };
 
     // Get first user
     let Ok(user) = query.next().await else {
         warn!("Database query failed!");
-        return DATABASE_BUSY_RESPONSE.clone();
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
     };
 
     let Some(user) = user else {
-        return INVALID_USERNAME_PASSWORD_RESPONSE.clone();
+        // Take as long as for an existing user
+        if let Err(busy) = hashing::run(move || password::verify_dummy(&password)).await {
+            return busy.into_response();
+        }
+        audit::record(
+            &db,
+            &meta,
+            None,
+            "login",
+            Outcome::Failure,
+            Some("unknown_user"),
+        )
+        .await;
+        return INVALID_USERNAME_PASSWORD_RESPONSE.clone().into_response();
     };
 
     // Get user fields first
     // LibSQL bug on getting previous Row on an advanced Rows - https://github.com/tursodatabase/libsql/issues/1947
-    let db_username = user.get::<String>(0).unwrap();
-    let db_password = user.get::<String>(1).unwrap();
-    let db_requires_second_factor = user.get::<bool>(2).unwrap();
+    let db_userid = user.get::<u64>(0).unwrap();
+    let db_password = user.get::<String>(2).unwrap();
+    let db_requires_second_factor = user.get::<bool>(3).unwrap();
+    let db_pepper = user.get::<Option<String>>(4).unwrap();
+    let db_failed_login_attempts = user.get::<u32>(5).unwrap();
+    let db_locked_until = user.get::<Option<u64>>(6).unwrap();
+    let db_is_username_match = user.get::<bool>(7).unwrap();
 
-    // Sanity-check: Ensure that there is only one user with the given username
-    // Shouldn't happen, may be removed in the future
+    // Sanity-check: Ensure that there is only one user with the given email
+    // Unverified emails may be shared when `LOGIN_UNVERIFIED_EMAILS` is set
     if let Ok(next_user) = query.next().await {
-        if next_user.is_some() {
+        if next_user.is_some() && !db_is_username_match {
             warn!(
                 username,
-                "There seem to be multiple users with the same username. Treating as if user does not exist at all",
+                "There seem to be multiple users with the same email. Treating as if user does not exist at all",
             );
-            return INVALID_USERNAME_PASSWORD_RESPONSE.clone();
+            return INVALID_USERNAME_PASSWORD_RESPONSE.clone().into_response();
         }
     } else {
-        return DATABASE_BUSY_RESPONSE.clone();
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
     }
 
-    let is_password_match = tokio::task::spawn_blocking(move || verify(&password, &db_password))
-        .await
-        .unwrap();
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
 
-    if !is_password_match {
-        return INVALID_USERNAME_PASSWORD_RESPONSE.clone();
+    // Locked accounts answer like a wrong password, taking as long
+    if lockout::is_locked(db_locked_until, current_time) {
+        if let Err(busy) = hashing::run(move || password::verify_dummy(&password)).await {
+            return busy.into_response();
+        }
+        audit::record(
+            &db,
+            &meta,
+            Some(db_userid),
+            "login",
+            Outcome::Failure,
+            Some("locked"),
+        )
+        .await;
+        return INVALID_USERNAME_PASSWORD_RESPONSE.clone().into_response();
     }
 
-    // Error if TOTP is given but no second factor is required
-    // TODO: Is it logical to have TOTP without 2FA?
-    if !db_requires_second_factor && totp.is_some() {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({ "error": "TOTP is provided while 2FA is not required" })),
-        );
+    let checked = hashing::run(move || {
+        (
+            password::check(&password, &db_password, db_pepper.as_deref()),
+            password,
+            db_password,
+        )
+    })
+    .await;
+    let (verification, password, db_password) = match checked {
+        Ok(checked) => checked,
+        Err(busy) => return busy.into_response(),
+    };
+
+    match verification {
+        Verification::Invalid => {
+            if let Err(e) = lockout::record_failure(&db, db_userid, current_time).await {
+                warn!("Unable to record failed login, {e}");
+            }
+            audit::record(
+                &db,
+                &meta,
+                Some(db_userid),
+                "login",
+                Outcome::Failure,
+                Some("invalid_password"),
+            )
+            .await;
+            return INVALID_USERNAME_PASSWORD_RESPONSE.clone().into_response();
+        }
+        // Hash has been made with other parameters or pepper - upgrade it now that we know the password
+        Verification::Valid { rehash: true } => {
+            password::rehash_in_background(state.db.clone(), db_userid, password, db_password);
+        }
+        Verification::Valid { rehash: false } => {}
     }
 
-    if db_requires_second_factor {
-        let Some(totp) = totp else {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(json!({ "error": "2FA is required" })),
-            );
-        };
+    let current_time = current_time as usize;
 
-        let Ok(mut query) = db
-            .query(
-                "SELECT totp_secret FROM \"users\" WHERE username = ? COLLATE NOCASE",
-                params![username.clone()],
-            )
-            .await
-        else {
-            warn!("Database query failed!");
-            return DATABASE_BUSY_RESPONSE.clone();
-        };
+    // Password is correct but a second factor is required
+    // Hand out a short-lived token to be exchanged at `/auth/login/mfa`
+    // Failed logins are only cleared once the second factor is given, so that failed factors keep adding up
+    if db_requires_second_factor {
+        audit::record(
+            &db,
+            &meta,
+            Some(db_userid),
+            "login.password",
+            Outcome::Success,
+            None,
+        )
+        .await;
+        return login_mfa::challenge(&db, db_userid, current_time).await;
+    }
 
-        // REFACTOR: Handle edge cases & return DATABASE_BUSY_RESPONSE on error
-        let totp_secret = query
-            .next()
-            .await
-            .unwrap()
-            .unwrap()
-            .get::<String>(0)
-            .unwrap();
-
-        let is_totp_valid = totp::check_current(totp_secret.as_bytes(), &totp);
-        if !is_totp_valid {
-            return INVALID_USERNAME_PASSWORD_RESPONSE.clone();
-        }
+    if db_failed_login_attempts > 0
+        && let Err(e) = lockout::reset(&db, db_userid).await
+    {
+        warn!("Unable to reset failed logins, {e}");
     }
 
+    audit::record(&db, &meta, Some(db_userid), "login", Outcome::Success, None).await;
+
     // -------------------------
     // If everything is correct
     // Start generating JWT
-    let Ok(mut query) = db
-        .query(
-            "SELECT id, display_name, email, email_verified_at FROM \"users\" WHERE username = ? COLLATE NOCASE",
-            params![username.clone()],
+    session::issue_tokens(&db, db_userid, current_time).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use super::*;
+    use crate::common::test_state;
+
+    /// `correct horse`, as a Django hash - cheaper to verify than Argon2
+    const PASSWORD_HASH: &str =
+        "pbkdf2_sha256$1000$Yd1r3PbHg2sG$LG313HUk8beQCEXKtopjacTVOKWDEgmDkcQXCwJmTS8=";
+
+    async fn login(state: &AppState, username: &str, password: &str) -> StatusCode {
+        let dto = UserLoginDto {
+            username: username.to_string(),
+            password: password.to_string(),
+        };
+        post(State(state.clone()), RequestMeta::default(), Json(dto))
+            .await
+            .into_response()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn prefers_username_over_equal_email() {
+        let state = test_state().await;
+        let conn = state.db.connect().unwrap();
+        conn.execute(
+            "INSERT INTO \"users\" (username, username_canonical, password) VALUES ('alice', 'alice', ?)",
+            params![PASSWORD_HASH],
         )
         .await
-    else {
-        warn!("Database query failed!");
-        return DATABASE_BUSY_RESPONSE.clone();
-    };
-
-    let user = query.next().await.unwrap().unwrap();
-    let db_userid = user.get::<u64>(0).unwrap();
-    let db_display_name = user.get::<Option<String>>(1).unwrap();
-    let db_email = user.get::<Option<String>>(2).unwrap();
-    let db_email_verified = user.get::<Option<i64>>(3).unwrap();
-
-    let email_verified = match db_email_verified {
-        Some(_) => Some(true),
-        None if db_email.is_some() => Some(false),
-        None => None,
-    };
+        .unwrap();
+        // Stored before emails were validated
+        conn.execute(
+            "INSERT INTO \"users\" (username, username_canonical, password, email, email_normalized, email_verified_at) VALUES ('bob', 'bob', ?, 'alice', 'alice', 1)",
+            params![PASSWORD_HASH],
+        )
+        .await
+        .unwrap();
 
-    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs() as usize;
-    let refresh_token = jwt::issue_refresh_token(db_userid, Some(current_time));
-    let access_token = jwt::issue_access_token(
-        db_userid,
-        &db_username,
-        db_display_name.as_deref(),
-        db_email.as_deref(),
-        email_verified,
-        current_time,
-    );
-
-    return (
-        StatusCode::OK,
-        Json(json!({
-            "refresh_token": refresh_token,
-            "access_token": access_token,
-        })),
-    );
+        assert_eq!(
+            login(&state, "alice", "correct horse").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            login(&state, "alice", "wrong horse").await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
 }
@@ -0,0 +1,452 @@
+// Second step of the login flow
+// * `/auth/login` hands out a short-lived `mfa_token` once the password has been verified
+// * The `mfa_token` is single-use - any attempt (successful or not) consumes it, which means that
+//   guessing a factor requires going through the password check (& its hashing cost) again
+// * Recovery codes are consumed when redeemed, whether the rest of the login succeeds or not
+// * Failed factors count towards the lockout of the account, see `lockout.rs`
+//   * The count is only cleared once the second factor succeeds, a correct password alone does not clear it
+
+use std::time::UNIX_EPOCH;
+
+use axum::{
+    Json,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use libsql::{Connection, params};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{error, instrument, warn};
+
+use crate::{
+    AppState,
+    audit::{self, Outcome, RequestMeta},
+    common::{DATABASE_BUSY_RESPONSE, INVALID_USERNAME_PASSWORD_RESPONSE},
+    email_otp, jwt, lockout, recovery_codes,
+    routes::auth::webauthn::{self, AssertionDto},
+    session, totp,
+};
+
+/// Second factors that may be used to complete a login
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Factor {
+    Totp,
+    Recovery,
+    Webauthn,
+    Email,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "factor", rename_all = "lowercase")]
+pub enum FactorResponseDto {
+    Totp { code: String },
+    Recovery { code: String },
+    Webauthn(AssertionDto),
+    Email { code: String },
+}
+
+#[derive(Deserialize)]
+pub struct LoginMfaDto {
+    mfa_token: String,
+    #[serde(flatten)]
+    response: FactorResponseDto,
+}
+
+/// Lists the second factors the given user has set up
+pub async fn available_factors(conn: &Connection, user_id: u64) -> Result<Vec<Factor>, Response> {
+    let Ok(mut query) = conn
+        .query(
+            "SELECT totp_secret IS NOT NULL, (SELECT COUNT(*) FROM \"webauthn_credentials\" WHERE user_id = \"users\".id), email IS NOT NULL AND email_verified_at IS NOT NULL, (SELECT COUNT(*) FROM \"recovery_codes\" WHERE user_id = \"users\".id AND used_at IS NULL) FROM \"users\" WHERE id = ?",
+            params![user_id],
+        )
+        .await
+    else {
+        warn!("Unable to query for available second factors");
+        return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+    };
+
+    let Ok(Some(row)) = query.next().await else {
+        warn!("Unable to query for available second factors");
+        return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+    };
+
+    let mut factors = Vec::new();
+    if row.get::<bool>(0).unwrap() {
+        factors.push(Factor::Totp);
+    }
+    if row.get::<u64>(3).unwrap() > 0 {
+        factors.push(Factor::Recovery);
+    }
+    if row.get::<u64>(1).unwrap() > 0 {
+        factors.push(Factor::Webauthn);
+    }
+    if *email_otp::ENABLED && row.get::<bool>(2).unwrap() {
+        factors.push(Factor::Email);
+    }
+
+    Ok(factors)
+}
+
+/// Responds with an `mfa_token` & the available factors, to be used when the first factor
+/// succeeded for a user that requires a second factor
+pub async fn challenge(conn: &Connection, user_id: u64, auth_time: usize) -> Response {
+    let factors = match available_factors(conn, user_id).await {
+        Ok(factors) => factors,
+        Err(response) => return response,
+    };
+
+    if factors.is_empty() {
+        warn!(
+            user_id,
+            "User requires a second factor but has none set up. User is unable to log in",
+        );
+        return (
+            StatusCode::FORBIDDEN,
+            Json(
+                json!({ "error": "2FA is required but no second factor has been set up - Please contact an administrator" }),
+            ),
+        )
+            .into_response();
+    }
+
+    // Include a WebAuthn challenge right away to save a roundtrip
+    let webauthn_options = if factors.contains(&Factor::Webauthn) {
+        match webauthn::authentication_options(conn, Some(user_id)).await {
+            Ok(options) => options,
+            Err(response) => return response,
+        }
+    } else {
+        None
+    };
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "mfa_token": jwt::issue_mfa_token(user_id, auth_time),
+            "factors": factors,
+            "webauthn": webauthn_options,
+        })),
+    )
+        .into_response()
+}
+
+/// Verifies & consumes the email code sent to the user
+async fn verify_email_code(conn: &Connection, user_id: u64, code: &str) -> Result<(), Response> {
+    let Ok(mut query) = conn
+        .query(
+            "SELECT code_hash, attempts, expires_at FROM \"email_otp\" WHERE user_id = ?",
+            params![user_id],
+        )
+        .await
+    else {
+        warn!("Unable to query for email code");
+        return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+    };
+
+    let Ok(row) = query.next().await else {
+        warn!("Unable to query for email code");
+        return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+    };
+
+    let Some(row) = row else {
+        return Err(INVALID_USERNAME_PASSWORD_RESPONSE.clone().into_response());
+    };
+    let db_code_hash = row.get::<String>(0).unwrap();
+    let db_attempts = row.get::<u64>(1).unwrap();
+    let db_expires_at = row.get::<u64>(2).unwrap();
+
+    if db_attempts >= email_otp::EMAIL_OTP_MAX_ATTEMPTS
+        || UNIX_EPOCH.elapsed().unwrap().as_secs() > db_expires_at
+    {
+        return Err(INVALID_USERNAME_PASSWORD_RESPONSE.clone().into_response());
+    }
+
+    if email_otp::hash_code(user_id, code) != db_code_hash {
+        if let Err(e) = conn
+            .execute(
+                "UPDATE \"email_otp\" SET attempts = attempts + 1 WHERE user_id = ?",
+                params![user_id],
+            )
+            .await
+        {
+            warn!("Unable to increment email code attempts, {e}");
+            return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+        }
+
+        return Err(INVALID_USERNAME_PASSWORD_RESPONSE.clone().into_response());
+    }
+
+    // Code is single-use
+    if let Err(e) = conn
+        .execute(
+            "DELETE FROM \"email_otp\" WHERE user_id = ?",
+            params![user_id],
+        )
+        .await
+    {
+        warn!("Unable to consume email code, {e}");
+        return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+    }
+
+    Ok(())
+}
+
+/// Verifies a second factor response of the given user
+pub async fn verify_factor(
+    conn: &Connection,
+    user_id: u64,
+    response: FactorResponseDto,
+) -> Result<(), Response> {
+    match response {
+        FactorResponseDto::Totp { code } => {
+            let Ok(mut query) = conn
+                .query(
+                    "SELECT totp_secret FROM \"users\" WHERE id = ?",
+                    params![user_id],
+                )
+                .await
+            else {
+                warn!("Database query failed!");
+                return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+            };
+
+            let Ok(row) = query.next().await else {
+                warn!("Database query failed!");
+                return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+            };
+
+            // User may have been deleted in the meantime
+            let Some(Some(totp_secret)) = row.map(|row| row.get::<Option<String>>(0).unwrap())
+            else {
+                return Err(INVALID_USERNAME_PASSWORD_RESPONSE.clone().into_response());
+            };
+
+            if !totp::check_current(totp_secret.as_bytes(), &code) {
+                return Err(INVALID_USERNAME_PASSWORD_RESPONSE.clone().into_response());
+            }
+        }
+
+        FactorResponseDto::Recovery { code } => {
+            // Marking the code as used in the same statement keeps it from being redeemed twice
+            let used = match conn
+                .execute(
+                    "UPDATE \"recovery_codes\" SET used_at = ? WHERE user_id = ? AND code_hash = ? AND used_at IS NULL",
+                    params![
+                        UNIX_EPOCH.elapsed().unwrap().as_secs(),
+                        user_id,
+                        recovery_codes::hash_code(user_id, &code)
+                    ],
+                )
+                .await
+            {
+                Ok(used) => used,
+                Err(e) => {
+                    warn!("Unable to redeem recovery code, {e}");
+                    return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+                }
+            };
+
+            if used == 0 {
+                return Err(INVALID_USERNAME_PASSWORD_RESPONSE.clone().into_response());
+            }
+        }
+
+        FactorResponseDto::Webauthn(assertion) => {
+            webauthn::verify_authentication(conn, &assertion, Some(user_id)).await?;
+        }
+
+        FactorResponseDto::Email { code } => {
+            if !*email_otp::ENABLED {
+                return Err(StatusCode::BAD_REQUEST.into_response());
+            }
+
+            verify_email_code(conn, user_id, &code).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Exchanges an `mfa_token` & a second factor response for the final tokens
+#[instrument(skip(state, meta, dto))]
+pub async fn post(
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Json(dto): Json<LoginMfaDto>,
+) -> impl IntoResponse {
+    let Ok(token_data) = jwt::verify_mfa_token(&dto.mfa_token) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let claims = token_data.claims;
+    let Ok(user_id) = claims.sub.parse::<u64>() else {
+        error!(
+            "Signed MFA token contains an invalid user ID! Unless frontend is provided the same JWT key, JWT key has been compromised!"
+        );
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    let Ok(db) = state.db.connect() else {
+        warn!("Unable to connect to the database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    match session::redeem_token(&db, &dto.mfa_token).await {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::UNAUTHORIZED.into_response(),
+        Err(response) => return response,
+    }
+
+    let Ok(mut query) = db
+        .query(
+            "SELECT locked_until FROM \"users\" WHERE id = ?",
+            params![user_id],
+        )
+        .await
+    else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(row) = query.next().await else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Some(row) = row else {
+        // User may have been deleted in the meantime
+        return INVALID_USERNAME_PASSWORD_RESPONSE.clone().into_response();
+    };
+    let db_locked_until = row.get::<Option<u64>>(0).unwrap();
+
+    // Failed factors lock the account just like failed passwords, see `lockout.rs`
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
+    if lockout::is_locked(db_locked_until, current_time) {
+        audit::record(
+            &db,
+            &meta,
+            Some(user_id),
+            "login.mfa",
+            Outcome::Failure,
+            Some("locked"),
+        )
+        .await;
+        return INVALID_USERNAME_PASSWORD_RESPONSE.clone().into_response();
+    }
+
+    if let Err(response) = verify_factor(&db, user_id, dto.response).await {
+        if !response.status().is_server_error() {
+            if let Err(e) = lockout::record_failure(&db, user_id, current_time).await {
+                warn!("Unable to record failed second factor, {e}");
+            }
+            audit::record(
+                &db,
+                &meta,
+                Some(user_id),
+                "login.mfa",
+                Outcome::Failure,
+                Some("invalid_factor"),
+            )
+            .await;
+        }
+        return response;
+    }
+
+    if let Err(e) = lockout::reset(&db, user_id).await {
+        warn!("Unable to reset failed logins, {e}");
+    }
+
+    audit::record(
+        &db,
+        &meta,
+        Some(user_id),
+        "login.mfa",
+        Outcome::Success,
+        None,
+    )
+    .await;
+    audit::record(&db, &meta, Some(user_id), "login", Outcome::Success, None).await;
+    session::issue_tokens(&db, user_id, claims.auth_time).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::test_state;
+
+    async fn exchange(state: &AppState, code: &str) -> StatusCode {
+        // Tokens issued within the same second are the same, forget the ones already redeemed
+        state
+            .db
+            .connect()
+            .unwrap()
+            .execute("DELETE FROM \"revoked_jwt\"", ())
+            .await
+            .unwrap();
+        let now = UNIX_EPOCH.elapsed().unwrap().as_secs() as usize;
+        let dto = LoginMfaDto {
+            mfa_token: jwt::issue_mfa_token(1, now).to_string(),
+            response: FactorResponseDto::Recovery {
+                code: code.to_string(),
+            },
+        };
+        post(State(state.clone()), RequestMeta::default(), Json(dto))
+            .await
+            .into_response()
+            .status()
+    }
+
+    async fn count(conn: &Connection, sql: &str) -> u64 {
+        let mut rows = conn.query(sql, ()).await.unwrap();
+        rows.next().await.unwrap().unwrap().get::<u64>(0).unwrap()
+    }
+
+    #[tokio::test]
+    async fn locks_after_failed_factors() {
+        let state = test_state().await;
+        let conn = state.db.connect().unwrap();
+        conn.execute(
+            "INSERT INTO \"users\" (id, username, username_canonical, password, requires_second_factor) VALUES (1, 'alice', 'alice', '', 1)",
+            (),
+        )
+        .await
+        .unwrap();
+        conn.execute(
+            "INSERT INTO \"recovery_codes\" (user_id, code_hash, created_at) VALUES (1, ?, 0)",
+            params![recovery_codes::hash_code(1, "abcde-fghjk")],
+        )
+        .await
+        .unwrap();
+
+        for _ in 0..*lockout::THRESHOLD {
+            assert_eq!(
+                exchange(&state, "zzzzz-zzzzz").await,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+
+        // Even the right code is turned away while locked, & is not consumed
+        assert_eq!(
+            exchange(&state, "abcde-fghjk").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM \"recovery_codes\" WHERE used_at IS NULL"
+            )
+            .await,
+            1
+        );
+
+        conn.execute("UPDATE \"users\" SET locked_until = NULL", ())
+            .await
+            .unwrap();
+        assert_eq!(exchange(&state, "abcde-fghjk").await, StatusCode::OK);
+        assert_eq!(
+            count(&conn, "SELECT failed_login_attempts FROM \"users\"").await,
+            0
+        );
+    }
+}
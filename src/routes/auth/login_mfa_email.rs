@@ -0,0 +1,155 @@
+use std::time::UNIX_EPOCH;
+
+use axum::{
+    Json,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use libsql::{Connection, params};
+use minijinja::context;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{error, instrument, warn};
+
+use crate::{
+    AppState,
+    audit::{self, Outcome, RequestMeta},
+    common::DATABASE_BUSY_RESPONSE,
+    email_otp, jwt, outbox, templates,
+};
+
+#[derive(Deserialize)]
+pub struct SendEmailCodeDto {
+    mfa_token: String,
+}
+
+/// Sends a one-time login code to the verified email of the user
+/// The `mfa_token` is not consumed, it still needs to be exchanged at `/auth/login/mfa`
+#[instrument(skip(state, meta, dto))]
+pub async fn post(
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Json(dto): Json<SendEmailCodeDto>,
+) -> impl IntoResponse {
+    if !*email_otp::ENABLED {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let Ok(token_data) = jwt::verify_mfa_token(&dto.mfa_token) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let Ok(user_id) = token_data.claims.sub.parse::<u64>() else {
+        error!(
+            "Signed MFA token contains an invalid user ID! Unless frontend is provided the same JWT key, JWT key has been compromised!"
+        );
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    let Ok(db) = state.db.connect() else {
+        warn!("Unable to connect to the database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    send_code(&db, &meta, user_id, "login.mfa_email_sent").await
+}
+
+/// Mails a one-time code to the verified email of the user, replacing any previously sent code
+/// Also used to confirm sensitive actions of an authenticated user, see `/auth/me/step_up/email`
+pub async fn send_code(db: &Connection, meta: &RequestMeta, user_id: u64, event: &str) -> Response {
+    let Ok(mut query) = db
+        .query(
+            "SELECT email, (SELECT sent_at FROM \"email_otp\" WHERE user_id = \"users\".id), username, display_name, locale FROM \"users\" WHERE id = ? AND email IS NOT NULL AND email_verified_at IS NOT NULL",
+            params![user_id],
+        )
+        .await
+    else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(row) = query.next().await else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Some(row) = row else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "User does not have a verified email" })),
+        )
+            .into_response();
+    };
+    let email = row.get::<String>(0).unwrap();
+    let db_last_sent_at = row.get::<Option<u64>>(1).unwrap();
+    let username = row.get::<String>(2).unwrap();
+    let display_name = row.get::<Option<String>>(3).unwrap();
+    let locale = row.get::<Option<String>>(4).unwrap();
+
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
+    if db_last_sent_at
+        .is_some_and(|sent_at| current_time < sent_at + email_otp::EMAIL_OTP_TIME_BETWEEN.as_secs())
+    {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(json!({ "error": "A code has been sent recently - Please wait before requesting another one" })),
+        )
+            .into_response();
+    }
+
+    let code = email_otp::generate_code();
+    let message = match templates::render(
+        templates::LOGIN_CODE,
+        locale.as_deref(),
+        email,
+        &context! {
+            username,
+            display_name,
+            code,
+            expires_in_minutes => email_otp::EMAIL_OTP_DURATION.as_secs() / 60,
+        },
+    ) {
+        Ok(message) => message,
+        Err(e) => {
+            error!("Unable to render login code message, {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let Ok(txn) = db.transaction().await else {
+        warn!("Unable to initialize a transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    // Replaces any previously sent code, resetting the attempts
+    if let Err(e) = txn
+        .execute(
+            "INSERT OR REPLACE INTO \"email_otp\" (user_id, code_hash, attempts, expires_at, sent_at) VALUES (?, ?, 0, ?, ?)",
+            params![
+                user_id,
+                email_otp::hash_code(user_id, &code),
+                current_time + email_otp::EMAIL_OTP_DURATION.as_secs(),
+                current_time
+            ],
+        )
+        .await
+    {
+        txn.rollback().await.ok();
+        warn!("Unable to store email code, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    if let Err(e) = outbox::enqueue_message(&txn, &message).await {
+        txn.rollback().await.ok();
+        warn!("Unable to queue login code, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    if txn.commit().await.is_err() {
+        warn!("Unable to commit transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    audit::record(db, meta, Some(user_id), event, Outcome::Success, None).await;
+    StatusCode::NO_CONTENT.into_response()
+}
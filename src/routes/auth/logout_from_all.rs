@@ -1,6 +1,112 @@
-use axum::{http::StatusCode, response::IntoResponse};
+// Logout of every session of the authenticated user
+// * Every session is revoked by bumping `sessions_revoked_at`, like a password change does (see `password.rs`)
+//   * The session of the caller included - its tokens are rejected from then on
 
-pub async fn post() -> impl IntoResponse {
-    // TODO: Add to database & refactor jwt::validate
-    (StatusCode::NO_CONTENT).into_response()
+use std::time::UNIX_EPOCH;
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse};
+use libsql::{TransactionBehavior, params};
+use serde_json::json;
+use tracing::{instrument, warn};
+
+use crate::{
+    AppState,
+    audit::{self, Outcome, RequestMeta},
+    common::DATABASE_BUSY_RESPONSE,
+    outbox,
+    session::AuthUser,
+    webhook::Event,
+};
+
+/// Revokes every session of the authenticated user
+#[instrument(skip(state, meta))]
+pub async fn post(
+    State(state): State<AppState>,
+    AuthUser { id: user_id, .. }: AuthUser,
+    meta: RequestMeta,
+) -> impl IntoResponse {
+    let Ok(conn) = state.db.connect() else {
+        warn!("Unable to connect to the database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(txn) = conn
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .await
+    else {
+        warn!("Unable to initialize a transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
+    if let Err(e) = txn
+        .execute(
+            "UPDATE \"users\" SET sessions_revoked_at = ? WHERE id = ?",
+            params![current_time, user_id],
+        )
+        .await
+    {
+        txn.rollback().await.ok();
+        warn!("Unable to revoke sessions, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    if let Err(e) = outbox::enqueue_event(
+        &txn,
+        &state.webhooks,
+        Event::new(
+            "sessions.revoked",
+            json!({ "user_id": user_id.to_string() }),
+        ),
+    )
+    .await
+    {
+        txn.rollback().await.ok();
+        warn!("Unable to queue webhook event, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    if txn.commit().await.is_err() {
+        warn!("Unable to commit transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    audit::record(
+        &conn,
+        &meta,
+        Some(user_id),
+        "sessions.revoked",
+        Outcome::Success,
+        None,
+    )
+    .await;
+    StatusCode::NO_CONTENT.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{common::test_state, jwt, session};
+
+    #[tokio::test]
+    async fn revokes_every_session() {
+        let state = test_state().await;
+        let conn = state.db.connect().unwrap();
+        conn.execute(
+            "INSERT INTO \"users\" (id, username, username_canonical, password) VALUES (1, 'alice', 'alice', '')",
+            (),
+        )
+        .await
+        .unwrap();
+        let now = session::issued_at(None);
+        let access_token = jwt::issue_access_token(1, "alice", None, None, None, now, now);
+
+        let auth_user = session::authenticate(&conn, &access_token).await.unwrap();
+        let response = post(State(state.clone()), auth_user, RequestMeta::default())
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        assert!(session::authenticate(&conn, &access_token).await.is_err());
+    }
 }
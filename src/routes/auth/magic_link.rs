@@ -0,0 +1,357 @@
+// Considerations to be made:
+// * Same as forgot password - Message & response time should be consistent for existent / non-existent accounts
+// * Links are redeemed with a POST; mail scanners & link previewers happily GET every link they see
+// * Optionally, the link may be bound to the browser that requested it with a nonce cookie
+//   * The cookie is always set, even for non-existent accounts, to keep the response consistent
+// * Links are only mailed to verified emails
+//   * Anyone can claim an unverified email - the owner of the address would be signed into the account of whoever
+//     claimed it
+// * Users with 2FA enabled still need to go through `/auth/login/mfa`
+
+use std::{
+    sync::LazyLock,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use axum_extra::extract::{
+    CookieJar,
+    cookie::{Cookie, SameSite},
+};
+use libsql::{TransactionBehavior, params};
+use minijinja::context;
+use serde::Deserialize;
+use tracing::{error, instrument, warn};
+
+use crate::{
+    AppState,
+    audit::{self, Outcome, RequestMeta},
+    clients::{self, Action, LinkOptionsDto},
+    common::{DATABASE_BUSY_RESPONSE, generate_url_token, sha256_hex},
+    email, outbox,
+    routes::auth::login_mfa,
+    session, templates, timing,
+};
+
+/// Whether magic link login is available.
+/// Opt-in by setting the `MAGIC_LINK` environment variable.
+static ENABLED: LazyLock<bool> = LazyLock::new(|| std::env::var("MAGIC_LINK").is_ok());
+/// Whether magic links may only be redeemed by the browser that requested them.
+/// Opt-in by setting the `MAGIC_LINK_BIND_BROWSER` environment variable.
+static BIND_BROWSER: LazyLock<bool> =
+    LazyLock::new(|| std::env::var("MAGIC_LINK_BIND_BROWSER").is_ok());
+
+/// The amount of time that a magic link is redeemable.
+///
+/// Defaults to 15 minutes
+const MAGIC_LINK_TOKEN_DURATION: Duration = Duration::from_secs(15 * 60);
+/// The minimum amount of time for a magic link request to respond to, in milliseconds.
+/// Configured with the `MAGIC_LINK_MINIMUM_TIME` environment variable.
+/// Used to prevent probing / timing attack.
+///
+/// Defaults to 200ms
+pub static MAGIC_LINK_MINIMUM_TIME: LazyLock<Duration> =
+    LazyLock::new(|| timing::from_env("MAGIC_LINK_MINIMUM_TIME", Duration::from_millis(200)));
+/// Name of the cookie holding the browser nonce
+const MAGIC_LINK_NONCE_COOKIE: &str = "picoauth_magic_link";
+
+#[derive(Deserialize)]
+pub struct MagicLinkRequestDto {
+    email: String,
+    #[serde(flatten)]
+    link: LinkOptionsDto,
+}
+
+/// Mails a magic link to the user owning the given email
+/// Responses are padded to `MAGIC_LINK_MINIMUM_TIME`, see `routes/auth/mod.rs`
+#[instrument(skip(state, meta, jar, req))]
+pub async fn post(
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    jar: CookieJar,
+    Json(req): Json<MagicLinkRequestDto>,
+) -> impl IntoResponse {
+    if !*ENABLED {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let (client, redirect_to) = match req.link.resolve() {
+        Ok(resolved) => resolved,
+        Err(response) => return response,
+    };
+
+    // Nonce is generated regardless of the user existence
+    let (jar, nonce_hash) = if *BIND_BROWSER {
+        let nonce = generate_url_token();
+        let cookie = Cookie::build((MAGIC_LINK_NONCE_COOKIE, nonce.clone()))
+            .path("/auth/magic_link")
+            .http_only(true)
+            .secure(true)
+            .same_site(SameSite::Lax)
+            .max_age(
+                MAGIC_LINK_TOKEN_DURATION
+                    .try_into()
+                    .expect("Magic link duration is out of range"),
+            );
+
+        (jar.add(cookie), Some(sha256_hex(nonce)))
+    } else {
+        (jar, None)
+    };
+
+    // Process
+    let Ok(conn) = state.db.connect() else {
+        warn!("Unable to connect to the database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(mut rows) = conn
+        .query(
+            "SELECT id, username, display_name, email, locale FROM \"users\" WHERE email_normalized = ? AND email_verified_at IS NOT NULL",
+            params![email::normalize(&req.email)],
+        )
+        .await
+    else {
+        warn!("Unable to query for user existence");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(row) = rows.next().await else {
+        warn!("Unable to query for user existence");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    // If user exists, do whole processing, otherwise skip this whole block
+    if let Some(row) = row {
+        let user_id = row.get::<u64>(0).unwrap();
+        let username = row.get::<String>(1).unwrap();
+        let display_name = row.get::<Option<String>>(2).unwrap();
+        let email = row.get::<String>(3).unwrap();
+        let locale = row.get::<Option<String>>(4).unwrap();
+
+        let token = generate_url_token();
+        let expire_time =
+            UNIX_EPOCH.elapsed().unwrap().as_secs() + MAGIC_LINK_TOKEN_DURATION.as_secs();
+
+        let message = match templates::render(
+            templates::MAGIC_LINK,
+            locale.as_deref(),
+            email,
+            &context! {
+                username,
+                display_name,
+                action_url => client.url(Action::MagicLink, &token, redirect_to.as_deref()),
+                expires_in_minutes => MAGIC_LINK_TOKEN_DURATION.as_secs() / 60,
+            },
+        ) {
+            Ok(message) => message,
+            Err(e) => {
+                error!("Unable to render magic link message, {e}");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+
+        let Ok(txn) = conn.transaction().await else {
+            warn!("Unable to initialize a transaction");
+            return DATABASE_BUSY_RESPONSE.clone().into_response();
+        };
+
+        // Store token in database
+        if let Err(e) = txn
+            .execute(
+                "INSERT INTO \"magic_link_token\" (token, user_id, expires_at, nonce_hash, redirect_to) VALUES (?, ?, ?, ?, ?)",
+                params![token.clone(), user_id, expire_time, nonce_hash, redirect_to],
+            )
+            .await
+        {
+            txn.rollback().await.ok();
+            warn!("Unable to store magic link token in database, {e}");
+            return DATABASE_BUSY_RESPONSE.clone().into_response();
+        }
+
+        if let Err(e) = outbox::enqueue_message(&txn, &message).await {
+            txn.rollback().await.ok();
+            warn!("Unable to queue magic link, {e}");
+            return DATABASE_BUSY_RESPONSE.clone().into_response();
+        }
+
+        if txn.commit().await.is_err() {
+            warn!("Unable to commit transaction");
+            return DATABASE_BUSY_RESPONSE.clone().into_response();
+        }
+
+        audit::record(
+            &conn,
+            &meta,
+            Some(user_id),
+            "magic_link.requested",
+            Outcome::Success,
+            None,
+        )
+        .await;
+    }
+
+    // Send response
+    (StatusCode::NO_CONTENT, jar).into_response()
+}
+
+/// Redeems a magic link, logging the user in
+/// Does not need to contain deadline as user will be probing for a CSPRNG generated token
+#[instrument(skip(state, meta, jar, token))]
+pub async fn redeem(
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    jar: CookieJar,
+    Path(token): Path<String>,
+) -> impl IntoResponse {
+    if !*ENABLED {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let Ok(conn) = state.db.connect() else {
+        warn!("Unable to connect to the database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(txn) = conn
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .await
+    else {
+        warn!("Unable to initialize a transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(mut rows) = txn
+        .query(
+            "SELECT user_id, expires_at, used_at, nonce_hash, redirect_to FROM \"magic_link_token\" WHERE token = ?",
+            params![token.clone()],
+        )
+        .await
+    else {
+        txn.rollback().await.ok();
+        warn!("Unable to query for token existence");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(row) = rows.next().await else {
+        txn.rollback().await.ok();
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Some(row) = row else {
+        txn.rollback().await.ok();
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let user_id = row.get::<u64>(0).unwrap();
+    let expires_at = row.get::<u64>(1).unwrap();
+    let used_at = row.get::<Option<u64>>(2).unwrap();
+    let nonce_hash = row.get::<Option<String>>(3).unwrap();
+    let redirect_to = row.get::<Option<String>>(4).unwrap();
+
+    // Check if token has been used or is expired
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
+    if used_at.is_some() || current_time > expires_at {
+        txn.rollback().await.ok();
+        audit::record(
+            &conn,
+            &meta,
+            Some(user_id),
+            "login.magic_link",
+            Outcome::Failure,
+            Some("expired"),
+        )
+        .await;
+        return StatusCode::GONE.into_response();
+    }
+
+    // Check if link is redeemed from the same browser
+    if let Some(nonce_hash) = nonce_hash {
+        let nonce = jar.get(MAGIC_LINK_NONCE_COOKIE).map(Cookie::value);
+        if nonce.map(sha256_hex) != Some(nonce_hash) {
+            txn.rollback().await.ok();
+            audit::record(
+                &conn,
+                &meta,
+                Some(user_id),
+                "login.magic_link",
+                Outcome::Failure,
+                Some("browser_mismatch"),
+            )
+            .await;
+            return StatusCode::FORBIDDEN.into_response();
+        }
+    }
+
+    // Mark token as used
+    if let Err(e) = txn
+        .execute(
+            "UPDATE \"magic_link_token\" SET used_at = ? WHERE token = ?",
+            params![current_time, token],
+        )
+        .await
+    {
+        txn.rollback().await.ok();
+        warn!("Unable to mark token as used, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    let Ok(mut query) = txn
+        .query(
+            "SELECT requires_second_factor FROM \"users\" WHERE id = ?",
+            params![user_id],
+        )
+        .await
+    else {
+        txn.rollback().await.ok();
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(Some(user)) = query.next().await else {
+        txn.rollback().await.ok();
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+    let requires_second_factor = user.get::<bool>(0).unwrap();
+
+    if txn.commit().await.is_err() {
+        warn!("Unable to commit transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    audit::record(
+        &conn,
+        &meta,
+        Some(user_id),
+        "login.magic_link",
+        Outcome::Success,
+        None,
+    )
+    .await;
+
+    let jar = jar.remove(Cookie::build(MAGIC_LINK_NONCE_COOKIE).path("/auth/magic_link"));
+    let headers = clients::redirect_header(redirect_to);
+    let auth_time = current_time as usize;
+
+    if requires_second_factor {
+        return (
+            jar,
+            headers,
+            login_mfa::challenge(&conn, user_id, auth_time).await,
+        )
+            .into_response();
+    }
+
+    (
+        jar,
+        headers,
+        session::issue_tokens(&conn, user_id, auth_time).await,
+    )
+        .into_response()
+}
@@ -1,4 +1,790 @@
-use axum::response::IntoResponse;
+// Profile of the authenticated user
+// * `PUT` replaces the profile - omitted fields are cleared, `PATCH` only updates the fields given
+// * Changing the email un-verifies it & mails a verification link to the new address
+//   * Requires the current password - a stolen access token could otherwise change it & reset the password
+//   * Wrong passwords count towards the lockout of the account, see `lockout.rs`
+//   * Codes & links mailed to the previous address (one-time codes, password resets, magic links) are discarded
+//   * Emails are unique across users, see `email.rs`
+//   * Verification tokens sent to the previous address become unusable, as tokens are bound to their email
+//   * Shares the resend interval of `/auth/verify_email`, so that it cannot be used to spam arbitrary addresses
+// * Access tokens carry the profile as claims - they only reflect the changes once refreshed
+// * `DELETE` schedules the account for deletion, see `deletion.rs`
+//   * Requires the password (& a second factor if the user requires one) to be confirmed
+//   * Wrong passwords & factors count towards the lockout of the account, like on login
+//   * `/auth/me/step_up` hands out the WebAuthn challenge & `/auth/me/step_up/email` mails the code to confirm with
 
-//
-pub async fn put() -> impl IntoResponse {}
+use std::time::UNIX_EPOCH;
+
+use axum::{
+    Json,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use libsql::{Connection, TransactionBehavior, params};
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+use tracing::{instrument, warn};
+
+use crate::{
+    AppState,
+    audit::{self, Outcome, RequestMeta},
+    clients::LinkOptionsDto,
+    common::DATABASE_BUSY_RESPONSE,
+    deletion, email, email_otp, hashing, lockout, outbox, password, password_policy,
+    routes::auth::{
+        login_mfa::{self, Factor, FactorResponseDto},
+        login_mfa_email, verify_email, webauthn,
+    },
+    session::{self, AuthUser},
+    templates::LOCALE_REGEX,
+    webhook::Event,
+};
+
+#[derive(Deserialize)]
+pub struct UpdateMeDto {
+    #[serde(default, deserialize_with = "present")]
+    display_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    email: Option<Option<String>>,
+    /// Preferred language of the user, e.g. `en` or `pt-BR`
+    #[serde(default, deserialize_with = "present")]
+    locale: Option<Option<String>>,
+    /// Client & redirect of the verification link, if the email is changed
+    #[serde(flatten)]
+    link: LinkOptionsDto,
+    /// Required to change the email
+    current_password: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteMeDto {
+    password: String,
+    /// Second factor response, required if the user requires a second factor
+    #[serde(flatten)]
+    second_factor: Option<FactorResponseDto>,
+}
+
+/// Distinguishes a field explicitly set to `null` (`Some(None)`) from an omitted one (`None`)
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Responds with the profile of the given user
+async fn profile(conn: &Connection, user_id: u64) -> Response {
+    let Ok(mut query) = conn
+        .query(
+            "SELECT username, display_name, email, email_verified_at, locale, requires_second_factor, created_at, deletion_requested_at FROM \"users\" WHERE id = ?",
+            params![user_id],
+        )
+        .await
+    else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(user) = query.next().await else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Some(user) = user else {
+        // May be invalid if user has been deleted off of database
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let db_email = user.get::<Option<String>>(2).unwrap();
+    let db_email_verified_at = user.get::<Option<u64>>(3).unwrap();
+    let db_deletion_requested_at = user.get::<Option<u64>>(7).unwrap();
+
+    let factors = match login_mfa::available_factors(conn, user_id).await {
+        Ok(factors) => factors,
+        Err(response) => return response,
+    };
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "id": user_id.to_string(),
+            "username": user.get::<String>(0).unwrap(),
+            "display_name": user.get::<Option<String>>(1).unwrap(),
+            "email_verified": db_email.as_ref().map(|_| db_email_verified_at.is_some()),
+            "email": db_email,
+            "locale": user.get::<Option<String>>(4).unwrap(),
+            "requires_second_factor": user.get::<bool>(5).unwrap(),
+            "second_factors": factors,
+            "created_at": user.get::<String>(6).unwrap(),
+            "pending_deletion": db_deletion_requested_at.map(|requested_at| json!({
+                "requested_at": requested_at,
+                "purge_at": deletion::purge_at(requested_at),
+            })),
+        })),
+    )
+        .into_response()
+}
+
+/// Returns the profile of the authenticated user
+#[instrument(skip(state))]
+pub async fn get(
+    State(state): State<AppState>,
+    AuthUser { id: user_id, .. }: AuthUser,
+) -> impl IntoResponse {
+    let Ok(conn) = state.db.connect() else {
+        warn!("Unable to connect to the database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    profile(&conn, user_id).await
+}
+
+/// Replaces the profile of the authenticated user, clearing omitted fields
+#[instrument(skip(state, meta, dto))]
+pub async fn put(
+    State(state): State<AppState>,
+    AuthUser { id: user_id, .. }: AuthUser,
+    meta: RequestMeta,
+    Json(mut dto): Json<UpdateMeDto>,
+) -> impl IntoResponse {
+    for field in [&mut dto.display_name, &mut dto.email, &mut dto.locale] {
+        field.get_or_insert(None);
+    }
+
+    update(&state, &meta, user_id, dto).await
+}
+
+/// Updates the given fields of the authenticated user's profile
+#[instrument(skip(state, meta, dto))]
+pub async fn patch(
+    State(state): State<AppState>,
+    AuthUser { id: user_id, .. }: AuthUser,
+    meta: RequestMeta,
+    Json(dto): Json<UpdateMeDto>,
+) -> impl IntoResponse {
+    update(&state, &meta, user_id, dto).await
+}
+
+async fn update(state: &AppState, meta: &RequestMeta, user_id: u64, dto: UpdateMeDto) -> Response {
+    if let Some(Some(locale)) = &dto.locale
+        && !LOCALE_REGEX.is_match(locale)
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(
+                json!({ "error": "Invalid locale - Locale must be a language tag, e.g. `en` or `pt-BR`" }),
+            ),
+        )
+            .into_response();
+    }
+
+    if let Some(Some(email)) = &dto.email
+        && !email::is_valid(email)
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Invalid email - Email must be an address, e.g. `name@example.com`" })),
+        )
+            .into_response();
+    }
+
+    // Users without an email could never log in again
+    if *session::REQUIRE_VERIFIED_EMAIL && matches!(dto.email, Some(None)) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Email is required - It cannot be removed" })),
+        )
+            .into_response();
+    }
+
+    let (client, redirect_to) = match dto.link.resolve() {
+        Ok(resolved) => resolved,
+        Err(response) => return response,
+    };
+
+    let Ok(conn) = state.db.connect() else {
+        warn!("Unable to connect to the database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    // Confirmed before the transaction, hashing should not hold the database lock
+    if let Some(email) = &dto.email
+        && let Err(response) =
+            confirm_email_change(&conn, meta, user_id, email.as_deref(), dto.current_password).await
+    {
+        return response;
+    }
+
+    let Ok(txn) = conn
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .await
+    else {
+        warn!("Unable to initialize a transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(mut query) = txn
+        .query(
+            "SELECT username, display_name, email, locale, (SELECT MAX(created_at) FROM \"email_verification_token\" WHERE user_id = \"users\".id) FROM \"users\" WHERE id = ?",
+            params![user_id],
+        )
+        .await
+    else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(user) = query.next().await else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Some(user) = user else {
+        // May be invalid if user has been deleted off of database
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let db_username = user.get::<String>(0).unwrap();
+    let db_display_name = user.get::<Option<String>>(1).unwrap();
+    let db_email = user.get::<Option<String>>(2).unwrap();
+    let db_locale = user.get::<Option<String>>(3).unwrap();
+    let db_last_sent_at = user.get::<Option<u64>>(4).unwrap();
+
+    let display_name = dto.display_name.unwrap_or_else(|| db_display_name.clone());
+    let email = dto.email.unwrap_or_else(|| db_email.clone());
+    let locale = dto.locale.unwrap_or_else(|| db_locale.clone());
+
+    // A new casing of the same address is stored, but does not need to be verified again
+    let email_changed =
+        email.as_deref().map(email::normalize) != db_email.as_deref().map(email::normalize);
+    if email == db_email && display_name == db_display_name && locale == db_locale {
+        txn.rollback().await.ok();
+        return profile(&conn, user_id).await;
+    }
+
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
+    if email_changed
+        && email.is_some()
+        && db_last_sent_at.is_some_and(|sent_at| {
+            current_time < sent_at + verify_email::VERIFY_EMAIL_TIME_BETWEEN.as_secs()
+        })
+    {
+        txn.rollback().await.ok();
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(json!({ "error": "A verification email has been sent recently - Please wait before changing the email again" })),
+        )
+            .into_response();
+    }
+
+    if email_changed && let Some(email) = &email {
+        let Ok(is_taken) = email::is_taken(&txn, email, Some(user_id)).await else {
+            txn.rollback().await.ok();
+            warn!("Database query failed!");
+            return DATABASE_BUSY_RESPONSE.clone().into_response();
+        };
+        if is_taken {
+            txn.rollback().await.ok();
+            return (
+                StatusCode::CONFLICT,
+                Json(json!({ "error": "Email already in use" })),
+            )
+                .into_response();
+        }
+    }
+
+    if let Err(e) = txn
+        .execute(
+            "UPDATE \"users\" SET display_name = ?, email = ?, email_normalized = ?, locale = ?, email_verified_at = CASE WHEN ? THEN NULL ELSE email_verified_at END WHERE id = ?",
+            params![
+                display_name.clone(),
+                email.clone(),
+                email.as_deref().map(email::normalize),
+                locale.clone(),
+                email_changed,
+                user_id
+            ],
+        )
+        .await
+    {
+        txn.rollback().await.ok();
+        warn!("Unable to update user, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    if email_changed {
+        // Codes & links mailed to the previous address must not be usable anymore
+        for statement in [
+            "DELETE FROM \"email_otp\" WHERE user_id = ?",
+            "DELETE FROM \"forgot_password_token\" WHERE user_id = ?",
+            "DELETE FROM \"magic_link_token\" WHERE user_id = ?",
+        ] {
+            if let Err(e) = txn.execute(statement, params![user_id]).await {
+                txn.rollback().await.ok();
+                warn!("Unable to discard codes & links of the previous email, {e}");
+                return DATABASE_BUSY_RESPONSE.clone().into_response();
+            }
+        }
+
+        if let Some(email) = &email
+            && let Err(e) = verify_email::queue_verification(
+                &txn,
+                user_id,
+                email,
+                client,
+                redirect_to.as_deref(),
+            )
+            .await
+        {
+            txn.rollback().await.ok();
+            warn!("Unable to queue verification email, {e}");
+            return DATABASE_BUSY_RESPONSE.clone().into_response();
+        }
+    }
+
+    let event = Event::new(
+        "user.updated",
+        json!({
+            "user_id": user_id.to_string(),
+            "username": db_username,
+            "display_name": display_name,
+            "email": email,
+            "email_changed": email_changed,
+            "locale": locale,
+        }),
+    );
+    if let Err(e) = outbox::enqueue_event(&txn, &state.webhooks, event).await {
+        txn.rollback().await.ok();
+        warn!("Unable to queue webhook event, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    if txn.commit().await.is_err() {
+        warn!("Unable to commit transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    audit::record(
+        &conn,
+        meta,
+        Some(user_id),
+        "user.updated",
+        Outcome::Success,
+        None,
+    )
+    .await;
+    profile(&conn, user_id).await
+}
+
+/// Checks the current password of the user if the email is being changed to another address
+async fn confirm_email_change(
+    conn: &Connection,
+    meta: &RequestMeta,
+    user_id: u64,
+    email: Option<&str>,
+    current_password: Option<String>,
+) -> Result<(), Response> {
+    let Ok(mut query) = conn
+        .query(
+            "SELECT email, password, password_pepper, locked_until FROM \"users\" WHERE id = ?",
+            params![user_id],
+        )
+        .await
+    else {
+        warn!("Database query failed!");
+        return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+    };
+
+    let Ok(user) = query.next().await else {
+        warn!("Database query failed!");
+        return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+    };
+
+    let Some(user) = user else {
+        // May be invalid if user has been deleted off of database
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    };
+    let db_email = user.get::<Option<String>>(0).unwrap();
+    let db_password = user.get::<String>(1).unwrap();
+    let db_pepper = user.get::<Option<String>>(2).unwrap();
+    let db_locked_until = user.get::<Option<u64>>(3).unwrap();
+
+    if email.map(email::normalize) == db_email.as_deref().map(email::normalize) {
+        return Ok(());
+    }
+
+    let Some(current_password) = current_password else {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Password is required - Please confirm the email change with your current password" })),
+        )
+            .into_response());
+    };
+
+    // Locked accounts answer like a wrong password
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
+    let is_locked = lockout::is_locked(db_locked_until, current_time);
+    // Over-length passwords cannot match, no need to hash them
+    let is_password_match = if is_locked || password_policy::exceeds_max_length(&current_password) {
+        false
+    } else {
+        hashing::run(move || {
+            password::verify(&current_password, &db_password, db_pepper.as_deref())
+        })
+        .await
+        .map_err(IntoResponse::into_response)?
+    };
+
+    if !is_password_match {
+        if !is_locked && let Err(e) = lockout::record_failure(conn, user_id, current_time).await {
+            warn!("Unable to record failed password confirmation, {e}");
+        }
+        audit::record(
+            conn,
+            meta,
+            Some(user_id),
+            "user.updated",
+            Outcome::Failure,
+            Some(if is_locked {
+                "locked"
+            } else {
+                "invalid_password"
+            }),
+        )
+        .await;
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Invalid password - Current password is incorrect" })),
+        )
+            .into_response());
+    }
+
+    Ok(())
+}
+
+/// Schedules the account of the authenticated user for deletion & revokes all of its sessions
+#[instrument(skip(state, meta, dto))]
+pub async fn delete(
+    State(state): State<AppState>,
+    AuthUser { id: user_id, .. }: AuthUser,
+    meta: RequestMeta,
+    Json(dto): Json<DeleteMeDto>,
+) -> impl IntoResponse {
+    let Ok(conn) = state.db.connect() else {
+        warn!("Unable to connect to the database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(mut query) = conn
+        .query(
+            "SELECT password, requires_second_factor, deletion_requested_at, password_pepper, locked_until FROM \"users\" WHERE id = ?",
+            params![user_id],
+        )
+        .await
+    else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(user) = query.next().await else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Some(user) = user else {
+        // May be invalid if user has been deleted off of database
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let db_password = user.get::<String>(0).unwrap();
+    let db_requires_second_factor = user.get::<bool>(1).unwrap();
+    let db_deletion_requested_at = user.get::<Option<u64>>(2).unwrap();
+    let db_pepper = user.get::<Option<String>>(3).unwrap();
+    let db_locked_until = user.get::<Option<u64>>(4).unwrap();
+
+    if db_deletion_requested_at.is_some() {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "Account is already pending deletion" })),
+        )
+            .into_response();
+    }
+
+    // Locked accounts answer like a wrong password
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
+    let is_locked = lockout::is_locked(db_locked_until, current_time);
+    let confirm_password = dto.password;
+    // Over-length passwords cannot match, no need to hash them
+    let is_password_match = if is_locked || password_policy::exceeds_max_length(&confirm_password) {
+        false
+    } else {
+        match hashing::run(move || {
+            password::verify(&confirm_password, &db_password, db_pepper.as_deref())
+        })
+        .await
+        {
+            Ok(is_password_match) => is_password_match,
+            Err(busy) => return busy.into_response(),
+        }
+    };
+
+    if !is_password_match {
+        if !is_locked && let Err(e) = lockout::record_failure(&conn, user_id, current_time).await {
+            warn!("Unable to record failed password confirmation, {e}");
+        }
+        audit::record(
+            &conn,
+            &meta,
+            Some(user_id),
+            "user.deletion_scheduled",
+            Outcome::Failure,
+            Some(if is_locked {
+                "locked"
+            } else {
+                "invalid_password"
+            }),
+        )
+        .await;
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Invalid password - Password is incorrect" })),
+        )
+            .into_response();
+    }
+
+    if db_requires_second_factor {
+        let Some(second_factor) = dto.second_factor else {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(json!({ "error": "2FA is required - Please confirm with a second factor" })),
+            )
+                .into_response();
+        };
+
+        if let Err(response) = login_mfa::verify_factor(&conn, user_id, second_factor).await {
+            if !response.status().is_server_error() {
+                if let Err(e) = lockout::record_failure(&conn, user_id, current_time).await {
+                    warn!("Unable to record failed second factor, {e}");
+                }
+                audit::record(
+                    &conn,
+                    &meta,
+                    Some(user_id),
+                    "user.deletion_scheduled",
+                    Outcome::Failure,
+                    Some("invalid_factor"),
+                )
+                .await;
+            }
+            return response;
+        }
+    }
+
+    let Ok(txn) = conn
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .await
+    else {
+        warn!("Unable to initialize a transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    if let Err(e) = txn
+        .execute(
+            "UPDATE \"users\" SET deletion_requested_at = ?, sessions_revoked_at = ? WHERE id = ?",
+            params![current_time, current_time, user_id],
+        )
+        .await
+    {
+        txn.rollback().await.ok();
+        warn!("Unable to schedule user deletion, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    let purge_at = deletion::purge_at(current_time);
+    let event = Event::new(
+        "user.deletion_scheduled",
+        json!({ "user_id": user_id.to_string(), "purge_at": purge_at }),
+    );
+    if let Err(e) = outbox::enqueue_event(&txn, &state.webhooks, event).await {
+        txn.rollback().await.ok();
+        warn!("Unable to queue webhook event, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    if txn.commit().await.is_err() {
+        warn!("Unable to commit transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    audit::record(
+        &conn,
+        &meta,
+        Some(user_id),
+        "user.deletion_scheduled",
+        Outcome::Success,
+        None,
+    )
+    .await;
+    (
+        StatusCode::ACCEPTED,
+        Json(json!({ "requested_at": current_time, "purge_at": purge_at })),
+    )
+        .into_response()
+}
+
+/// Starts confirming a sensitive action (e.g. `DELETE`) with a second factor
+/// Responds with the factors of the user, along with a WebAuthn challenge if a credential is registered
+#[instrument(skip(state))]
+pub async fn step_up(
+    State(state): State<AppState>,
+    AuthUser { id: user_id, .. }: AuthUser,
+) -> impl IntoResponse {
+    let Ok(conn) = state.db.connect() else {
+        warn!("Unable to connect to the database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let factors = match login_mfa::available_factors(&conn, user_id).await {
+        Ok(factors) => factors,
+        Err(response) => return response,
+    };
+
+    let webauthn_options = if factors.contains(&Factor::Webauthn) {
+        match webauthn::authentication_options(&conn, Some(user_id)).await {
+            Ok(options) => options,
+            Err(response) => return response,
+        }
+    } else {
+        None
+    };
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "factors": factors,
+            "webauthn": webauthn_options,
+        })),
+    )
+        .into_response()
+}
+
+/// Mails a one-time code to confirm a sensitive action with the email factor
+#[instrument(skip(state, meta))]
+pub async fn step_up_email(
+    State(state): State<AppState>,
+    AuthUser { id: user_id, .. }: AuthUser,
+    meta: RequestMeta,
+) -> impl IntoResponse {
+    if !*email_otp::ENABLED {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let Ok(conn) = state.db.connect() else {
+        warn!("Unable to connect to the database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    login_mfa_email::send_code(&conn, &meta, user_id, "user.step_up_email_sent").await
+}
+
+/// Cancels the pending deletion of the authenticated user's account
+/// Sessions are revoked when the deletion is requested, so holding a valid token means having logged in since
+#[instrument(skip(state, meta))]
+pub async fn cancel_deletion(
+    State(state): State<AppState>,
+    AuthUser { id: user_id, .. }: AuthUser,
+    meta: RequestMeta,
+) -> impl IntoResponse {
+    let Ok(conn) = state.db.connect() else {
+        warn!("Unable to connect to the database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(txn) = conn.transaction().await else {
+        warn!("Unable to initialize a transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let cancelled = match txn
+        .execute(
+            "UPDATE \"users\" SET deletion_requested_at = NULL WHERE id = ? AND deletion_requested_at IS NOT NULL",
+            params![user_id],
+        )
+        .await
+    {
+        Ok(updated) => updated == 1,
+        Err(e) => {
+            txn.rollback().await.ok();
+            warn!("Unable to cancel user deletion, {e}");
+            return DATABASE_BUSY_RESPONSE.clone().into_response();
+        }
+    };
+
+    if !cancelled {
+        txn.rollback().await.ok();
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "Account is not pending deletion" })),
+        )
+            .into_response();
+    }
+
+    let event = Event::new(
+        "user.deletion_cancelled",
+        json!({ "user_id": user_id.to_string() }),
+    );
+    if let Err(e) = outbox::enqueue_event(&txn, &state.webhooks, event).await {
+        txn.rollback().await.ok();
+        warn!("Unable to queue webhook event, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    if txn.commit().await.is_err() {
+        warn!("Unable to commit transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    audit::record(
+        &conn,
+        &meta,
+        Some(user_id),
+        "user.deletion_cancelled",
+        Outcome::Success,
+        None,
+    )
+    .await;
+    StatusCode::NO_CONTENT.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::test_state;
+
+    #[tokio::test]
+    async fn recasing_the_email_keeps_it_verified() {
+        let state = test_state().await;
+        let conn = state.db.connect().unwrap();
+        conn.execute(
+            "INSERT INTO \"users\" (id, username, username_canonical, password, email, email_normalized, email_verified_at) VALUES (1, 'alice', 'alice', '', 'alice@example.com', 'alice@example.com', 1)",
+            (),
+        )
+        .await
+        .unwrap();
+
+        // No password given - it is only required to change the address
+        let dto = serde_json::from_value(json!({ "email": "Alice@Example.com" })).unwrap();
+        let response = update(&state, &RequestMeta::default(), 1, dto).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let mut rows = conn
+            .query(
+                "SELECT email, email_verified_at FROM \"users\" WHERE id = 1",
+                (),
+            )
+            .await
+            .unwrap();
+        let user = rows.next().await.unwrap().unwrap();
+        assert_eq!(user.get::<String>(0).unwrap(), "Alice@Example.com");
+        assert_eq!(user.get::<Option<u64>>(1).unwrap(), Some(1));
+    }
+}
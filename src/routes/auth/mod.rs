@@ -1,24 +1,97 @@
 use axum::{
     Router,
-    routing::{get, post, put},
+    middleware::from_fn_with_state,
+    routing::{get, post},
 };
 
-use crate::AppState;
+use crate::{AppState, rate_limit, timing};
 
 pub mod forgot_password;
 pub mod login;
+pub mod login_mfa;
+pub mod login_mfa_email;
 pub mod logout_from_all;
+pub mod magic_link;
 pub mod me;
+pub mod password;
+pub mod recovery_codes;
 pub mod register;
+pub mod verify_email;
+pub mod webauthn;
 
 pub fn router() -> Router<AppState> {
     Router::new()
-        .route("/me", put(me::put))
-        .route("/login", post(login::post))
-        .route("/register", post(register::post))
-        .route("/forgot_password", post(forgot_password::post))
+        .route(
+            "/me",
+            get(me::get)
+                .put(me::put)
+                .patch(me::patch)
+                .delete(me::delete),
+        )
+        .route("/me/cancel_deletion", post(me::cancel_deletion))
+        .route("/me/step_up", post(me::step_up))
+        .route("/me/step_up/email", post(me::step_up_email))
+        // Enumeration-sensitive routes are padded to a minimum response time, whether the user exists or not
+        .route(
+            "/login",
+            post(login::post)
+                .layer(from_fn_with_state(*login::LOGIN_MINIMUM_TIME, timing::pad))
+                .layer(from_fn_with_state(&*rate_limit::LOGIN, rate_limit::limit)),
+        )
+        .route("/login/mfa", post(login_mfa::post))
+        .route("/login/mfa/email", post(login_mfa_email::post))
+        .route(
+            "/register",
+            post(register::post).layer(from_fn_with_state(
+                &*rate_limit::REGISTER,
+                rate_limit::limit,
+            )),
+        )
+        .route("/password", post(password::post))
+        .route("/logout_from_all", post(logout_from_all::post))
+        .route("/recovery_codes", post(recovery_codes::post))
+        .route(
+            "/forgot_password",
+            post(forgot_password::post)
+                .layer(from_fn_with_state(
+                    *forgot_password::FORGOT_PASSWORD_MINIMUM_TIME,
+                    timing::pad,
+                ))
+                .layer(from_fn_with_state(
+                    &*rate_limit::FORGOT_PASSWORD,
+                    rate_limit::limit,
+                )),
+        )
         .route(
             "/forgot_password/{token}",
             get(forgot_password::get).put(forgot_password::put),
         )
+        .route(
+            "/magic_link",
+            post(magic_link::post).layer(from_fn_with_state(
+                *magic_link::MAGIC_LINK_MINIMUM_TIME,
+                timing::pad,
+            )),
+        )
+        .route("/magic_link/{token}", post(magic_link::redeem))
+        .route(
+            "/verify_email",
+            post(verify_email::post)
+                .layer(from_fn_with_state(
+                    *verify_email::VERIFY_EMAIL_MINIMUM_TIME,
+                    timing::pad,
+                ))
+                .layer(from_fn_with_state(
+                    &*rate_limit::VERIFY_EMAIL,
+                    rate_limit::limit,
+                )),
+        )
+        .route(
+            "/verify_email/{token}",
+            get(verify_email::redeem).post(verify_email::redeem),
+        )
+        .route("/webauthn/register/start", post(webauthn::register_start))
+        .route("/webauthn/register/finish", post(webauthn::register_finish))
+        .route("/webauthn/login/start", post(webauthn::login_start))
+        .route("/webauthn/login/finish", post(webauthn::login_finish))
 }
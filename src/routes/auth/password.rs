@@ -0,0 +1,227 @@
+// Password change of the authenticated user
+// * Requires the current password, so that a stolen access token cannot be used to take over the account
+//   * Checked before the new password, wrong guesses count towards the lockout of the account, see `lockout.rs`
+// * Every other session is revoked by bumping `sessions_revoked_at` - tokens issued until then are rejected
+//   * The caller is handed a fresh token pair, keeping the `auth_time` of its session
+
+use std::time::UNIX_EPOCH;
+
+use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use libsql::{TransactionBehavior, params};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{instrument, warn};
+
+use crate::{
+    AppState,
+    audit::{self, Outcome, RequestMeta},
+    common::{BREACHED_PASSWORD_RESPONSE, DATABASE_BUSY_RESPONSE},
+    hashing, hibp, lockout, outbox, password, password_policy,
+    session::{self, AuthUser},
+    webhook::Event,
+};
+
+#[derive(Deserialize)]
+pub struct ChangePasswordDto {
+    current_password: String,
+    new_password: String,
+}
+
+/// Changes the password of the authenticated user & revokes all of their other sessions
+#[instrument(skip(state, meta, dto))]
+pub async fn post(
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    AuthUser {
+        id: user_id,
+        auth_time,
+    }: AuthUser,
+    Json(dto): Json<ChangePasswordDto>,
+) -> impl IntoResponse {
+    let Ok(conn) = state.db.connect() else {
+        warn!("Unable to connect to the database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(mut query) = conn
+        .query(
+            "SELECT password, username, email, password_pepper, locked_until FROM \"users\" WHERE id = ?",
+            params![user_id],
+        )
+        .await
+    else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(user) = query.next().await else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Some(user) = user else {
+        // May be invalid if user has been deleted off of database
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let db_password = user.get::<String>(0).unwrap();
+    let db_username = user.get::<String>(1).unwrap();
+    let db_email = user.get::<Option<String>>(2).unwrap();
+    let db_pepper = user.get::<Option<String>>(3).unwrap();
+    let db_locked_until = user.get::<Option<u64>>(4).unwrap();
+
+    // Locked accounts answer like a wrong password
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
+    let is_locked = lockout::is_locked(db_locked_until, current_time);
+    let current_password = dto.current_password;
+    // Over-length passwords cannot match, no need to hash them
+    let is_password_match = if is_locked || password_policy::exceeds_max_length(&current_password) {
+        false
+    } else {
+        match hashing::run(move || {
+            password::verify(&current_password, &db_password, db_pepper.as_deref())
+        })
+        .await
+        {
+            Ok(is_password_match) => is_password_match,
+            Err(busy) => return busy.into_response(),
+        }
+    };
+
+    if !is_password_match {
+        if !is_locked && let Err(e) = lockout::record_failure(&conn, user_id, current_time).await {
+            warn!("Unable to record failed password confirmation, {e}");
+        }
+        audit::record(
+            &conn,
+            &meta,
+            Some(user_id),
+            "password.changed",
+            Outcome::Failure,
+            Some(if is_locked {
+                "locked"
+            } else {
+                "invalid_password"
+            }),
+        )
+        .await;
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Invalid password - Current password is incorrect" })),
+        )
+            .into_response();
+    }
+
+    if let Err(violations) =
+        password_policy::check(&dto.new_password, &db_username, db_email.as_deref())
+    {
+        return password_policy::rejection(&violations);
+    }
+    if hibp::is_breached(&dto.new_password).await {
+        return BREACHED_PASSWORD_RESPONSE.clone().into_response();
+    }
+
+    let new_password = dto.new_password;
+    let hashed = match hashing::run(move || password::hash(&new_password)).await {
+        Ok(hashed) => hashed,
+        Err(busy) => return busy.into_response(),
+    };
+
+    let Ok(txn) = conn
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .await
+    else {
+        warn!("Unable to initialize a transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    if let Err(e) = txn
+        .execute(
+            "UPDATE \"users\" SET password = ?, password_pepper = ?, sessions_revoked_at = ? WHERE id = ?",
+            params![hashed.hash, hashed.pepper, current_time, user_id],
+        )
+        .await
+    {
+        txn.rollback().await.ok();
+        warn!("Unable to update user password, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    if let Err(e) = outbox::enqueue_event(
+        &txn,
+        &state.webhooks,
+        Event::new(
+            "password.changed",
+            json!({ "user_id": user_id.to_string() }),
+        ),
+    )
+    .await
+    {
+        txn.rollback().await.ok();
+        warn!("Unable to queue webhook event, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    if txn.commit().await.is_err() {
+        warn!("Unable to commit transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    audit::record(
+        &conn,
+        &meta,
+        Some(user_id),
+        "password.changed",
+        Outcome::Success,
+        None,
+    )
+    .await;
+    session::reissue_tokens(&conn, user_id, auth_time).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::test_state;
+
+    /// `correct horse`, as a Django hash - cheaper to verify than Argon2
+    const PASSWORD_HASH: &str =
+        "pbkdf2_sha256$1000$Yd1r3PbHg2sG$LG313HUk8beQCEXKtopjacTVOKWDEgmDkcQXCwJmTS8=";
+
+    #[tokio::test]
+    async fn checks_current_password_first() {
+        let state = test_state().await;
+        let conn = state.db.connect().unwrap();
+        conn.execute(
+            "INSERT INTO \"users\" (id, username, username_canonical, password) VALUES (1, 'alice', 'alice', ?)",
+            params![PASSWORD_HASH],
+        )
+        .await
+        .unwrap();
+
+        // The new password breaks the policy, the wrong current password must still be what is answered
+        let dto = ChangePasswordDto {
+            current_password: "wrong horse".to_string(),
+            new_password: "alice".to_string(),
+        };
+        let auth_user = AuthUser {
+            id: 1,
+            auth_time: 0,
+        };
+        let response = post(
+            State(state.clone()),
+            RequestMeta::default(),
+            auth_user,
+            Json(dto),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let mut rows = conn
+            .query("SELECT failed_login_attempts FROM \"users\"", ())
+            .await
+            .unwrap();
+        let failures = rows.next().await.unwrap().unwrap().get::<u32>(0).unwrap();
+        assert_eq!(failures, 1);
+    }
+}
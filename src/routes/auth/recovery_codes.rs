@@ -0,0 +1,150 @@
+// Recovery codes of the authenticated user, see `recovery_codes.rs`
+// * Requires the password, so that a stolen access token cannot be turned into a lasting second factor
+// * Responds with the codes in plain text - this is the only time they are shown
+
+use std::time::UNIX_EPOCH;
+
+use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use libsql::{TransactionBehavior, params};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{instrument, warn};
+
+use crate::{
+    AppState,
+    audit::{self, Outcome, RequestMeta},
+    common::DATABASE_BUSY_RESPONSE,
+    hashing, password, password_policy, recovery_codes,
+    session::AuthUser,
+};
+
+#[derive(Deserialize)]
+pub struct GenerateRecoveryCodesDto {
+    password: String,
+}
+
+/// Generates a new batch of recovery codes for the authenticated user, discarding the previous one
+#[instrument(skip(state, meta, dto))]
+pub async fn post(
+    State(state): State<AppState>,
+    AuthUser { id: user_id, .. }: AuthUser,
+    meta: RequestMeta,
+    Json(dto): Json<GenerateRecoveryCodesDto>,
+) -> impl IntoResponse {
+    let Ok(conn) = state.db.connect() else {
+        warn!("Unable to connect to the database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(mut query) = conn
+        .query(
+            "SELECT password, password_pepper FROM \"users\" WHERE id = ?",
+            params![user_id],
+        )
+        .await
+    else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(user) = query.next().await else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Some(user) = user else {
+        // May be invalid if user has been deleted off of database
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let db_password = user.get::<String>(0).unwrap();
+    let db_pepper = user.get::<Option<String>>(1).unwrap();
+
+    let confirm_password = dto.password;
+    // Over-length passwords cannot match, no need to hash them
+    let is_password_match = if password_policy::exceeds_max_length(&confirm_password) {
+        false
+    } else {
+        match hashing::run(move || {
+            password::verify(&confirm_password, &db_password, db_pepper.as_deref())
+        })
+        .await
+        {
+            Ok(is_password_match) => is_password_match,
+            Err(busy) => return busy.into_response(),
+        }
+    };
+
+    if !is_password_match {
+        audit::record(
+            &conn,
+            &meta,
+            Some(user_id),
+            "recovery_codes.generated",
+            Outcome::Failure,
+            Some("invalid_password"),
+        )
+        .await;
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Invalid password - Password is incorrect" })),
+        )
+            .into_response();
+    }
+
+    let codes = recovery_codes::generate_codes();
+
+    let Ok(txn) = conn
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .await
+    else {
+        warn!("Unable to initialize a transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    if let Err(e) = txn
+        .execute(
+            "DELETE FROM \"recovery_codes\" WHERE user_id = ?",
+            params![user_id],
+        )
+        .await
+    {
+        txn.rollback().await.ok();
+        warn!("Unable to discard previous recovery codes, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
+    for code in &codes {
+        if let Err(e) = txn
+            .execute(
+                "INSERT INTO \"recovery_codes\" (user_id, code_hash, created_at) VALUES (?, ?, ?)",
+                params![
+                    user_id,
+                    recovery_codes::hash_code(user_id, code),
+                    current_time
+                ],
+            )
+            .await
+        {
+            txn.rollback().await.ok();
+            warn!("Unable to store recovery code, {e}");
+            return DATABASE_BUSY_RESPONSE.clone().into_response();
+        }
+    }
+
+    if txn.commit().await.is_err() {
+        warn!("Unable to commit transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    audit::record(
+        &conn,
+        &meta,
+        Some(user_id),
+        "recovery_codes.generated",
+        Outcome::Success,
+        None,
+    )
+    .await;
+    (StatusCode::OK, Json(json!({ "codes": codes }))).into_response()
+}
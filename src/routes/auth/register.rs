@@ -4,7 +4,18 @@ use serde::Deserialize;
 use serde_json::json;
 use tracing::{instrument, warn};
 
-use crate::{AppState, common::USERNAME_REGEX, password};
+use crate::{
+    AppState,
+    audit::{self, Outcome, RequestMeta},
+    clients::LinkOptionsDto,
+    common::{BREACHED_PASSWORD_RESPONSE, is_unique_violation},
+    email, hashing, hibp, notification, outbox, password, password_policy,
+    routes::auth::verify_email,
+    session,
+    templates::LOCALE_REGEX,
+    username,
+    webhook::Event,
+};
 
 #[derive(Deserialize)]
 pub struct RegisterUserDto {
@@ -13,88 +24,217 @@ pub struct RegisterUserDto {
 
     email: Option<String>,
     display_name: Option<String>,
+    /// Preferred language of the user, e.g. `en` or `pt-BR`
+    locale: Option<String>,
+    /// Client & redirect of the verification link
+    #[serde(flatten)]
+    link: LinkOptionsDto,
 }
 
-#[instrument(skip(state, dto))]
+#[instrument(skip(state, meta, dto))]
 pub async fn post(
     State(state): State<AppState>,
+    meta: RequestMeta,
     Json(dto): Json<RegisterUserDto>,
 ) -> impl IntoResponse {
-    let username = dto.username;
     let password = dto.password;
 
-    if !USERNAME_REGEX.is_match(&username) {
+    let username = match username::parse(&dto.username) {
+        Ok(username) => username,
+        Err(error) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response();
+        }
+    };
+    // Users without an email could never log in
+    if *session::REQUIRE_VERIFIED_EMAIL && dto.email.is_none() {
         return (
             StatusCode::BAD_REQUEST,
-            Json(
-                json!({ "error": "Invalid username - Username may only alphanumeric characters, dashes (-) and underscores (_)" }),
-            ),
-        );
+            Json(json!({ "error": "Email is required - It must be verified before logging in" })),
+        )
+            .into_response();
     }
-    if password.len() < 8 {
+    if dto
+        .email
+        .as_deref()
+        .is_some_and(|email| !email::is_valid(email))
+    {
         return (
             StatusCode::BAD_REQUEST,
-            Json(json!({ "error": "Insecure password - Password must be 8 characters or longer" })),
-        );
+            Json(json!({ "error": "Invalid email - Email must be an address, e.g. `name@example.com`" })),
+        )
+            .into_response();
+    }
+    if let Err(violations) =
+        password_policy::check(&password, &username.display, dto.email.as_deref())
+    {
+        return password_policy::rejection(&violations);
+    }
+    if hibp::is_breached(&password).await {
+        return BREACHED_PASSWORD_RESPONSE.clone().into_response();
+    }
+    if dto
+        .locale
+        .as_ref()
+        .is_some_and(|locale| !LOCALE_REGEX.is_match(locale))
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(
+                json!({ "error": "Invalid locale - Locale must be a language tag, e.g. `en` or `pt-BR`" }),
+            ),
+        )
+            .into_response();
     }
 
+    let (client, redirect_to) = match dto.link.resolve() {
+        Ok(resolved) => resolved,
+        Err(response) => return response,
+    };
+
     let Ok(db) = state.db.connect() else {
         warn!("Unable to connect to database");
         return (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!({ "error": "Database is busy - Please try again in a couple of seconds" })),
-        );
+        )
+            .into_response();
     };
-    // TODO: HIBP password check?
 
-    // Check for duplicate username
-    let mut username_count = db
-        .query(
-            "SELECT COUNT(*) FROM \"users\" WHERE username = ? COLLATE NOCASE",
-            params![username.as_str()],
-        )
+    // Check for duplicate (or confusable) username
+    if username::is_taken(&db, &username)
         .await
-        .expect("Unable to query database");
-    let username_count = username_count.next().await.unwrap().unwrap();
-    if username_count.get::<u32>(0).unwrap() > 0 {
+        .expect("Unable to query database")
+    {
         return (
             StatusCode::CONFLICT,
             Json(json!({ "error": "Username already taken" })),
-        );
+        )
+            .into_response();
+    }
+    if let Some(email) = &dto.email
+        && email::is_taken(&db, email, None)
+            .await
+            .expect("Unable to query database")
+    {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "Email already in use" })),
+        )
+            .into_response();
     }
 
-    // TODO: Email verification
+    let hashed = match hashing::run(move || password::hash(&password)).await {
+        Ok(hashed) => hashed,
+        Err(busy) => return busy.into_response(),
+    };
 
-    let password_hash = tokio::task::spawn_blocking(move || password::hash(&password))
-        .await
-        .unwrap();
+    let Ok(txn) = db.transaction().await else {
+        warn!("Unable to initialize a transaction");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Database is busy - Please try again in a couple of seconds" })),
+        )
+            .into_response();
+    };
 
     // Insert user into database
-    let insert_result = db
-        .execute(
-            "INSERT INTO \"users\" (username, password, email, display_name) VALUES (?, ?, ?, ?)",
+    let insert_result = txn
+        .query(
+            "INSERT INTO \"users\" (username, username_canonical, username_skeleton, password, password_pepper, email, email_normalized, display_name, locale) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?) RETURNING id",
             params![
-                username.as_str(),
-                password_hash,
-                dto.email,
-                dto.display_name
+                username.display.clone(),
+                username.canonical,
+                username.skeleton,
+                hashed.hash,
+                hashed.pepper,
+                dto.email.clone(),
+                dto.email.as_deref().map(email::normalize),
+                dto.display_name.clone(),
+                dto.locale
             ],
         )
         .await;
 
-    match insert_result {
-        Ok(_) => {
-            return (StatusCode::OK, Json(json!({ "success": true })));
-        }
+    // Constraints are checked as the row is returned
+    let inserted = match insert_result {
+        Ok(mut rows) => rows.next().await,
+        Err(e) => Err(e),
+    };
+    let user_id = match inserted {
+        Ok(row) => row.map(|row| row.get::<u64>(0).unwrap()),
 
+        // Taken by a concurrent registration since it was checked
+        Err(e) if is_unique_violation(&e) => {
+            txn.rollback().await.ok();
+            return (
+                StatusCode::CONFLICT,
+                Json(json!({ "error": "Username already taken" })),
+            )
+                .into_response();
+        }
         Err(e) => {
             warn!("Unable to insert user into database: {:?}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(
-                    json!({ "error": "Database is busy - Please try again in a couple of seconds" }),
-                ),
-            );
+            None
         }
+    };
+
+    let Some(user_id) = user_id else {
+        txn.rollback().await.ok();
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Database is busy - Please try again in a couple of seconds" })),
+        )
+            .into_response();
+    };
+
+    let event = Event::new(
+        "user.registered",
+        json!({
+            "user_id": user_id.to_string(),
+            "username": username.display,
+            "email": dto.email,
+            "display_name": dto.display_name,
+        }),
+    );
+    let mut queued: Result<(), notification::Error> =
+        outbox::enqueue_event(&txn, &state.webhooks, event)
+            .await
+            .map_err(Into::into);
+    if queued.is_ok()
+        && let Some(email) = &dto.email
+    {
+        queued =
+            verify_email::queue_verification(&txn, user_id, email, client, redirect_to.as_deref())
+                .await;
+    }
+
+    if let Err(e) = queued {
+        txn.rollback().await.ok();
+        warn!("Unable to queue registration side-effects: {e}");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Database is busy - Please try again in a couple of seconds" })),
+        )
+            .into_response();
     }
+
+    if txn.commit().await.is_err() {
+        warn!("Unable to commit transaction");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Database is busy - Please try again in a couple of seconds" })),
+        )
+            .into_response();
+    }
+
+    audit::record(
+        &db,
+        &meta,
+        Some(user_id),
+        "register",
+        Outcome::Success,
+        None,
+    )
+    .await;
+    (StatusCode::OK, Json(json!({ "success": true }))).into_response()
 }
@@ -0,0 +1,410 @@
+// Considerations to be made:
+// * Tokens are bound to the email they were sent to - changing email invalidates previously sent tokens
+// * Token can be redeemed with a GET so that the link may be opened directly without a frontend
+//   * Mail scanners opening the link is fine, they can only do so by having access to the inbox
+//   * The browser is redirected to `redirect_to` if one has been given
+// * Verified emails are unique - verifying an email another user has verified first is a conflict, see `email.rs`
+// * Verification emails can be resent without being logged in (by username or email), as `REQUIRE_VERIFIED_EMAIL`
+//   keeps unverified users from logging in
+//   * Same as forgot password - Response & response time are the same for existent / non-existent accounts
+
+use std::{
+    sync::LazyLock,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::{Method, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::{
+    TypedHeader,
+    headers::{Authorization, authorization::Bearer},
+};
+use libsql::{Connection, TransactionBehavior, params};
+use minijinja::context;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{instrument, warn};
+
+use crate::{
+    AppState,
+    audit::{self, Outcome, RequestMeta},
+    clients::{self, Action, Client, LinkOptionsDto},
+    common::{DATABASE_BUSY_RESPONSE, generate_url_token, is_unique_violation},
+    email, notification, outbox,
+    session::{self, AuthUser},
+    templates, timing, username,
+    webhook::Event,
+};
+
+/// The amount of time that an email verification token is redeemable.
+///
+/// Defaults to 24 Hours
+const VERIFY_EMAIL_TOKEN_DURATION: Duration = Duration::from_secs(24 * 3600);
+/// The minimum amount of time in-between resending verification emails.
+/// Used to prevent spam on the same user
+///
+/// Defaults to 5 minutes
+pub const VERIFY_EMAIL_TIME_BETWEEN: Duration = Duration::from_secs(5 * 60);
+/// The minimum amount of time for an unauthenticated resend request to respond to, in milliseconds.
+/// Configured with the `VERIFY_EMAIL_MINIMUM_TIME` environment variable.
+/// Used to prevent probing / timing attack.
+///
+/// Defaults to 200ms
+pub static VERIFY_EMAIL_MINIMUM_TIME: LazyLock<Duration> =
+    LazyLock::new(|| timing::from_env("VERIFY_EMAIL_MINIMUM_TIME", Duration::from_millis(200)));
+
+/// Creates a verification token for the given email and queues it to be mailed
+pub async fn queue_verification(
+    conn: &Connection,
+    user_id: u64,
+    email: &str,
+    client: &Client,
+    redirect_to: Option<&str>,
+) -> Result<(), notification::Error> {
+    let mut query = conn
+        .query(
+            "SELECT username, display_name, locale FROM \"users\" WHERE id = ?",
+            params![user_id],
+        )
+        .await?;
+    let user = query.next().await?.ok_or("User does not exist")?;
+    let username = user.get::<String>(0)?;
+    let display_name = user.get::<Option<String>>(1)?;
+    let locale = user.get::<Option<String>>(2)?;
+
+    let token = generate_url_token();
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
+
+    let message = templates::render(
+        templates::VERIFY_EMAIL,
+        locale.as_deref(),
+        email.to_string(),
+        &context! {
+            username,
+            display_name,
+            action_url => client.url(Action::VerifyEmail, &token, redirect_to),
+            expires_in_minutes => VERIFY_EMAIL_TOKEN_DURATION.as_secs() / 60,
+        },
+    )?;
+
+    conn.execute(
+        "INSERT INTO \"email_verification_token\" (token, user_id, email, expires_at, created_at, redirect_to) VALUES (?, ?, ?, ?, ?, ?)",
+        params![
+            token,
+            user_id,
+            email,
+            current_time + VERIFY_EMAIL_TOKEN_DURATION.as_secs(),
+            current_time,
+            redirect_to
+        ],
+    )
+    .await?;
+
+    outbox::enqueue_message(conn, &message).await?;
+    Ok(())
+}
+
+#[derive(Deserialize, Default)]
+pub struct ResendVerificationDto {
+    /// Username, or email - see `email.rs`. Identifies the user if the request is not authenticated
+    username: Option<String>,
+    /// Client & redirect of the verification link
+    #[serde(flatten)]
+    link: LinkOptionsDto,
+}
+
+/// Resends a verification email, either to the authenticated user or to the given user
+///
+/// Users cannot log in before verifying their email with `REQUIRE_VERIFIED_EMAIL`, so they may not have an access token.
+/// Unauthenticated requests always succeed & are padded to `VERIFY_EMAIL_MINIMUM_TIME`, see `routes/auth/mod.rs`
+#[instrument(skip(state, bearer, dto))]
+pub async fn post(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    dto: Option<Json<ResendVerificationDto>>,
+) -> impl IntoResponse {
+    let dto = dto.map(|Json(dto)| dto).unwrap_or_default();
+    let (client, redirect_to) = match dto.link.resolve() {
+        Ok(resolved) => resolved,
+        Err(response) => return response,
+    };
+
+    let Ok(conn) = state.db.connect() else {
+        warn!("Unable to connect to the database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    if let Some(TypedHeader(Authorization(bearer))) = bearer {
+        return match session::authenticate(&conn, bearer.token()).await {
+            Ok(AuthUser { id: user_id, .. }) => {
+                resend(&conn, user_id, client, redirect_to.as_deref()).await
+            }
+            Err(response) => response,
+        };
+    }
+
+    let Some(username) = dto.username else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "Either an access token or a username is required" })),
+        )
+            .into_response();
+    };
+
+    // Unverified emails are accepted, verifying them is the whole point
+    let Ok(mut rows) = conn
+        .query(
+            "SELECT id FROM \"users\" WHERE username_canonical = ? OR email_normalized = ?",
+            params![
+                username::canonicalize(&username),
+                email::normalize(&username)
+            ],
+        )
+        .await
+    else {
+        warn!("Unable to query for user existence");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let mut user_ids = Vec::new();
+    loop {
+        match rows.next().await {
+            Ok(Some(row)) => user_ids.push(row.get::<u64>(0).unwrap()),
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Unable to query for user existence, {e}");
+                return DATABASE_BUSY_RESPONSE.clone().into_response();
+            }
+        }
+    }
+
+    // Whether the user exists, has an email left to verify or has been sent one recently is not told
+    for user_id in user_ids {
+        let response = resend(&conn, user_id, client, redirect_to.as_deref()).await;
+        if response.status().is_server_error() {
+            return response;
+        }
+    }
+
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Resends a verification email to the given user, unless one has been sent recently
+async fn resend(
+    conn: &Connection,
+    user_id: u64,
+    client: &Client,
+    redirect_to: Option<&str>,
+) -> Response {
+    let Ok(mut query) = conn
+        .query(
+            "SELECT email, email_verified_at, (SELECT MAX(created_at) FROM \"email_verification_token\" WHERE user_id = \"users\".id) FROM \"users\" WHERE id = ?",
+            params![user_id],
+        )
+        .await
+    else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(Some(user)) = query.next().await else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+    let db_email = user.get::<Option<String>>(0).unwrap();
+    let db_email_verified_at = user.get::<Option<u64>>(1).unwrap();
+    let db_last_sent_at = user.get::<Option<u64>>(2).unwrap();
+
+    let Some(email) = db_email else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "User does not have an email" })),
+        )
+            .into_response();
+    };
+
+    if db_email_verified_at.is_some() {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "Email has already been verified" })),
+        )
+            .into_response();
+    }
+
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
+    if db_last_sent_at
+        .is_some_and(|sent_at| current_time < sent_at + VERIFY_EMAIL_TIME_BETWEEN.as_secs())
+    {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(json!({ "error": "A verification email has been sent recently - Please wait before requesting another one" })),
+        )
+            .into_response();
+    }
+
+    let Ok(txn) = conn.transaction().await else {
+        warn!("Unable to initialize a transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    if let Err(e) = queue_verification(&txn, user_id, &email, client, redirect_to).await {
+        txn.rollback().await.ok();
+        warn!("Unable to queue verification email, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    if txn.commit().await.is_err() {
+        warn!("Unable to commit transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Redeems an email verification token, marking the email as verified
+/// Does not need to contain deadline as user will be probing for a CSPRNG generated token
+#[instrument(skip(state, meta, token))]
+pub async fn redeem(
+    State(state): State<AppState>,
+    method: Method,
+    meta: RequestMeta,
+    Path(token): Path<String>,
+) -> impl IntoResponse {
+    let Ok(conn) = state.db.connect() else {
+        warn!("Unable to connect to the database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(txn) = conn
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .await
+    else {
+        warn!("Unable to initialize a transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(mut rows) = txn
+        .query(
+            "SELECT user_id, email, expires_at, used_at, redirect_to FROM \"email_verification_token\" WHERE token = ?",
+            params![token.clone()],
+        )
+        .await
+    else {
+        txn.rollback().await.ok();
+        warn!("Unable to query for token existence");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(row) = rows.next().await else {
+        txn.rollback().await.ok();
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Some(row) = row else {
+        txn.rollback().await.ok();
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let user_id = row.get::<u64>(0).unwrap();
+    let email = row.get::<String>(1).unwrap();
+    let expires_at = row.get::<u64>(2).unwrap();
+    let used_at = row.get::<Option<u64>>(3).unwrap();
+    let redirect_to = row.get::<Option<String>>(4).unwrap();
+
+    // Check if token has been used or is expired
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
+    if used_at.is_some() || current_time > expires_at {
+        txn.rollback().await.ok();
+        return StatusCode::GONE.into_response();
+    }
+
+    // Only verify if the user still has the same email
+    let verified = match txn
+        .execute(
+            "UPDATE \"users\" SET email_verified_at = ? WHERE id = ? AND email = ?",
+            params![current_time, user_id, email.clone()],
+        )
+        .await
+    {
+        Ok(updated) => updated == 1,
+        // Verified by another user in the meantime
+        Err(e) if is_unique_violation(&e) => {
+            txn.rollback().await.ok();
+            return (
+                StatusCode::CONFLICT,
+                Json(json!({ "error": "Email already in use" })),
+            )
+                .into_response();
+        }
+        Err(e) => {
+            txn.rollback().await.ok();
+            warn!("Unable to mark email as verified, {e}");
+            return DATABASE_BUSY_RESPONSE.clone().into_response();
+        }
+    };
+
+    if !verified {
+        txn.rollback().await.ok();
+        return StatusCode::GONE.into_response();
+    }
+
+    // Mark token as used
+    if let Err(e) = txn
+        .execute(
+            "UPDATE \"email_verification_token\" SET used_at = ? WHERE token = ?",
+            params![current_time, token],
+        )
+        .await
+    {
+        txn.rollback().await.ok();
+        warn!("Unable to mark token as used, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    if let Err(e) = outbox::enqueue_event(
+        &txn,
+        &state.webhooks,
+        Event::new(
+            "user.email_verified",
+            json!({ "user_id": user_id.to_string(), "email": email }),
+        ),
+    )
+    .await
+    {
+        txn.rollback().await.ok();
+        warn!("Unable to queue webhook event, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    if txn.commit().await.is_err() {
+        warn!("Unable to commit transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    audit::record(
+        &conn,
+        &meta,
+        Some(user_id),
+        "email.verified",
+        Outcome::Success,
+        None,
+    )
+    .await;
+
+    // Link has been opened directly in the browser
+    if method == Method::GET
+        && let Some(redirect_to) = redirect_to
+    {
+        return Redirect::to(&redirect_to).into_response();
+    }
+
+    (
+        StatusCode::NO_CONTENT,
+        clients::redirect_header(redirect_to),
+    )
+        .into_response()
+}
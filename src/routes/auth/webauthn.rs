@@ -0,0 +1,440 @@
+// WebAuthn / passkey ceremonies
+// * Registration requires an authenticated user (bearer access token)
+//   * Credentials only enable 2FA when registered with `second_factor` - passkeys alone leave the login as is
+// * Login may either be used as a second factor (see `login_mfa.rs`) or as a passwordless,
+//   username-less first factor using discoverable credentials
+// * Ceremony tokens are single-use - they are added to `revoked_jwt` once redeemed
+
+use std::time::UNIX_EPOCH;
+
+use axum::{
+    Json,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use libsql::{Connection, TransactionBehavior, params};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::{instrument, warn};
+
+use crate::{
+    AppState,
+    audit::{self, Outcome, RequestMeta},
+    common::DATABASE_BUSY_RESPONSE,
+    jwt,
+    session::{self, AuthUser},
+    webauthn::{
+        self, AUTHENTICATION_CEREMONY, AuthenticationResponse, COSE_ALG_ES256,
+        REGISTRATION_CEREMONY, RegistrationResponse,
+    },
+};
+
+/// Timeout hint given to the client for completing a ceremony, in milliseconds
+const CEREMONY_TIMEOUT_MS: u64 = 300_000;
+
+#[derive(Deserialize)]
+pub struct RegistrationDto {
+    ceremony_token: String,
+    name: Option<String>,
+    /// Whether to require a second factor on login from now on
+    #[serde(default)]
+    second_factor: bool,
+    credential: RegistrationResponse,
+}
+
+#[derive(Deserialize)]
+pub struct AssertionDto {
+    ceremony_token: String,
+    credential: AuthenticationResponse,
+}
+
+/// Builds `PublicKeyCredentialRequestOptions` along with its ceremony token.
+///
+/// When a user is given, the allowed credentials are restricted to the ones owned by the user;
+/// `None` is returned if the user does not own any credential.
+pub async fn authentication_options(
+    conn: &Connection,
+    user_id: Option<u64>,
+) -> Result<Option<Value>, Response> {
+    let mut allow_credentials = Vec::new();
+
+    if let Some(user_id) = user_id {
+        let Ok(mut rows) = conn
+            .query(
+                "SELECT id FROM \"webauthn_credentials\" WHERE user_id = ?",
+                params![user_id],
+            )
+            .await
+        else {
+            warn!("Unable to query for WebAuthn credentials");
+            return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+        };
+
+        while let Ok(Some(row)) = rows.next().await {
+            allow_credentials.push(json!({
+                "type": "public-key",
+                "id": row.get::<String>(0).unwrap(),
+            }));
+        }
+
+        if allow_credentials.is_empty() {
+            return Ok(None);
+        }
+    }
+
+    let challenge = webauthn::generate_challenge();
+    let ceremony_token = jwt::issue_ceremony_token(AUTHENTICATION_CEREMONY, user_id, &challenge);
+
+    Ok(Some(json!({
+        "ceremony_token": ceremony_token,
+        "options": {
+            "publicKey": {
+                "challenge": challenge,
+                "rpId": *webauthn::RP_ID,
+                "timeout": CEREMONY_TIMEOUT_MS,
+                "allowCredentials": allow_credentials,
+                // Passwordless login needs to prove user verification by itself
+                "userVerification": if user_id.is_some() { "preferred" } else { "required" },
+            }
+        }
+    })))
+}
+
+/// Verifies an assertion, returning the ID of the authenticated user.
+///
+/// If `expected_user` is given, the credential must be owned by said user.
+pub async fn verify_authentication(
+    conn: &Connection,
+    dto: &AssertionDto,
+    expected_user: Option<u64>,
+) -> Result<u64, Response> {
+    let Ok(token_data) = jwt::verify_ceremony_token(&dto.ceremony_token, AUTHENTICATION_CEREMONY)
+    else {
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    };
+    let claims = token_data.claims;
+
+    // Ceremony token must have been issued for the same user (or for nobody, when passwordless)
+    if claims.sub != expected_user.map(|id| id.to_string()) {
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    if !session::redeem_token(conn, &dto.ceremony_token).await? {
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let Ok(mut rows) = conn
+        .query(
+            "SELECT user_id, public_key, sign_count FROM \"webauthn_credentials\" WHERE id = ?",
+            params![dto.credential.id.trim_end_matches('=')],
+        )
+        .await
+    else {
+        warn!("Unable to query for WebAuthn credential");
+        return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+    };
+
+    let Ok(row) = rows.next().await else {
+        warn!("Unable to query for WebAuthn credential");
+        return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+    };
+
+    let Some(row) = row else {
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    };
+    let db_user_id = row.get::<u64>(0).unwrap();
+    let db_public_key = row.get::<Vec<u8>>(1).unwrap();
+    let db_sign_count = row.get::<u32>(2).unwrap();
+
+    if expected_user.is_some_and(|id| id != db_user_id) {
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    // User handle is always returned for discoverable credentials, and must match the owner
+    if let Some(user_handle) = &dto.credential.response.user_handle
+        && webauthn::user_id_from_handle(user_handle) != Some(db_user_id)
+    {
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let sign_count = match webauthn::verify_assertion(
+        &dto.credential,
+        &claims.challenge,
+        &db_public_key,
+        db_sign_count,
+        expected_user.is_none(),
+    ) {
+        Ok(sign_count) => sign_count,
+        Err(webauthn::Error::SignCountRegression) => {
+            warn!(
+                credential_id = dto.credential.id,
+                "WebAuthn signature counter did not increase - authenticator might have been cloned"
+            );
+            return Err(StatusCode::UNAUTHORIZED.into_response());
+        }
+        Err(_) => return Err(StatusCode::UNAUTHORIZED.into_response()),
+    };
+
+    if let Err(e) = conn
+        .execute(
+            "UPDATE \"webauthn_credentials\" SET sign_count = ?, last_used_at = ? WHERE id = ?",
+            params![
+                sign_count,
+                UNIX_EPOCH.elapsed().unwrap().as_secs(),
+                dto.credential.id.trim_end_matches('=')
+            ],
+        )
+        .await
+    {
+        warn!("Unable to update WebAuthn credential usage, {e}");
+        return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+    }
+
+    Ok(db_user_id)
+}
+
+/// Starts registering a new credential for the authenticated user
+#[instrument(skip(state))]
+pub async fn register_start(
+    State(state): State<AppState>,
+    AuthUser { id: user_id, .. }: AuthUser,
+) -> impl IntoResponse {
+    let Ok(conn) = state.db.connect() else {
+        warn!("Unable to connect to the database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(mut query) = conn
+        .query(
+            "SELECT username, display_name FROM \"users\" WHERE id = ?",
+            params![user_id],
+        )
+        .await
+    else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(user) = query.next().await else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Some(user) = user else {
+        // May be invalid if user has been deleted off of database
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let db_username = user.get::<String>(0).unwrap();
+    let db_display_name = user.get::<Option<String>>(1).unwrap();
+
+    // Prevent registering the same authenticator twice
+    let Ok(mut rows) = conn
+        .query(
+            "SELECT id FROM \"webauthn_credentials\" WHERE user_id = ?",
+            params![user_id],
+        )
+        .await
+    else {
+        warn!("Unable to query for WebAuthn credentials");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let mut exclude_credentials = Vec::new();
+    while let Ok(Some(row)) = rows.next().await {
+        exclude_credentials.push(json!({
+            "type": "public-key",
+            "id": row.get::<String>(0).unwrap(),
+        }));
+    }
+
+    let challenge = webauthn::generate_challenge();
+    let ceremony_token =
+        jwt::issue_ceremony_token(REGISTRATION_CEREMONY, Some(user_id), &challenge);
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "ceremony_token": ceremony_token,
+            "options": {
+                "publicKey": {
+                    "challenge": challenge,
+                    "rp": {
+                        "id": *webauthn::RP_ID,
+                        "name": *webauthn::RP_NAME,
+                    },
+                    "user": {
+                        "id": webauthn::user_handle(user_id),
+                        "name": db_username,
+                        "displayName": db_display_name.unwrap_or_else(|| db_username.clone()),
+                    },
+                    "pubKeyCredParams": [{ "type": "public-key", "alg": COSE_ALG_ES256 }],
+                    "timeout": CEREMONY_TIMEOUT_MS,
+                    "excludeCredentials": exclude_credentials,
+                    "authenticatorSelection": {
+                        "residentKey": "preferred",
+                        "userVerification": "preferred",
+                    },
+                    "attestation": "none",
+                }
+            }
+        })),
+    )
+        .into_response()
+}
+
+/// Finishes registering a new credential for the authenticated user
+/// Enables 2FA for the user if asked to with `second_factor`
+#[instrument(skip(state, meta, dto))]
+pub async fn register_finish(
+    State(state): State<AppState>,
+    AuthUser { id: user_id, .. }: AuthUser,
+    meta: RequestMeta,
+    Json(dto): Json<RegistrationDto>,
+) -> impl IntoResponse {
+    let Ok(conn) = state.db.connect() else {
+        warn!("Unable to connect to the database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(token_data) = jwt::verify_ceremony_token(&dto.ceremony_token, REGISTRATION_CEREMONY)
+    else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    if token_data.claims.sub != Some(user_id.to_string()) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let credential =
+        match webauthn::verify_registration(&dto.credential, &token_data.claims.challenge) {
+            Ok(credential) => credential,
+            Err(e) => {
+                return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": format!("Invalid WebAuthn registration response ({e:?})") })),
+            )
+                .into_response();
+            }
+        };
+
+    let Ok(txn) = conn
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .await
+    else {
+        warn!("Unable to initialize a transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    match session::redeem_token(&txn, &dto.ceremony_token).await {
+        Ok(true) => {}
+        Ok(false) => {
+            txn.rollback().await.ok();
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        Err(response) => {
+            txn.rollback().await.ok();
+            return response;
+        }
+    }
+
+    if let Err(e) = txn
+        .execute(
+            "INSERT INTO \"webauthn_credentials\" (id, user_id, public_key, sign_count, name) VALUES (?, ?, ?, ?, ?)",
+            params![
+                credential.id.clone(),
+                user_id,
+                credential.public_key,
+                credential.sign_count,
+                dto.name
+            ],
+        )
+        .await
+    {
+        txn.rollback().await.ok();
+        warn!("Unable to store WebAuthn credential, {e}");
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "Credential is already registered" })),
+        )
+            .into_response();
+    }
+
+    if dto.second_factor
+        && let Err(e) = txn
+            .execute(
+                "UPDATE \"users\" SET requires_second_factor = 1 WHERE id = ?",
+                params![user_id],
+            )
+            .await
+    {
+        txn.rollback().await.ok();
+        warn!("Unable to enable 2FA for user, {e}");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    if txn.commit().await.is_err() {
+        warn!("Unable to commit transaction");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    }
+
+    audit::record(
+        &conn,
+        &meta,
+        Some(user_id),
+        "webauthn.registered",
+        Outcome::Success,
+        None,
+    )
+    .await;
+    (StatusCode::OK, Json(json!({ "id": credential.id }))).into_response()
+}
+
+/// Starts a passwordless login using a discoverable credential
+#[instrument(skip(state))]
+pub async fn login_start(State(state): State<AppState>) -> impl IntoResponse {
+    let Ok(conn) = state.db.connect() else {
+        warn!("Unable to connect to the database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    match authentication_options(&conn, None).await {
+        Ok(options) => (StatusCode::OK, Json(options)).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// Finishes a passwordless login using a discoverable credential
+#[instrument(skip(state, meta, dto))]
+pub async fn login_finish(
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Json(dto): Json<AssertionDto>,
+) -> impl IntoResponse {
+    let Ok(conn) = state.db.connect() else {
+        warn!("Unable to connect to the database");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let user_id = match verify_authentication(&conn, &dto, None).await {
+        Ok(user_id) => user_id,
+        Err(response) => {
+            if response.status() == StatusCode::UNAUTHORIZED {
+                audit::record(&conn, &meta, None, "login.webauthn", Outcome::Failure, None).await;
+            }
+            return response;
+        }
+    };
+
+    audit::record(
+        &conn,
+        &meta,
+        Some(user_id),
+        "login.webauthn",
+        Outcome::Success,
+        None,
+    )
+    .await;
+
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs() as usize;
+    session::issue_tokens(&conn, user_id, current_time).await
+}
@@ -1,6 +1,6 @@
-use axum::{Router, routing::post};
+use axum::{Router, middleware::from_fn_with_state, routing::post};
 
-use crate::AppState;
+use crate::{AppState, rate_limit};
 
 pub mod refresh;
 pub mod validate;
@@ -8,5 +8,11 @@ pub mod validate;
 pub fn router() -> Router<AppState> {
     Router::new()
         .route("/validate", post(validate::post))
-        .route("/refresh", post(refresh::post))
+        .route(
+            "/refresh",
+            post(refresh::post).layer(from_fn_with_state(
+                &*rate_limit::JWT_REFRESH,
+                rate_limit::limit,
+            )),
+        )
 }
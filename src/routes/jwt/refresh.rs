@@ -9,12 +9,18 @@ use libsql::params;
 use serde_json::{Map, Value};
 use tracing::{error, instrument, warn};
 
-use crate::{AppState, common::DATABASE_BUSY_RESPONSE, jwt};
+use crate::{
+    AppState,
+    audit::{self, Outcome, RequestMeta},
+    common::DATABASE_BUSY_RESPONSE,
+    jwt, session,
+};
 
 /// Refreshes an access token (and optionally, a refresh token) using a valid refresh token
-#[instrument(skip(state, authorization, body))]
+#[instrument(skip(state, meta, authorization, body))]
 pub async fn post(
     State(state): State<AppState>,
+    meta: RequestMeta,
     authorization: Option<TypedHeader<Authorization<Bearer>>>,
     body: String,
 ) -> impl IntoResponse {
@@ -30,6 +36,18 @@ pub async fn post(
         return DATABASE_BUSY_RESPONSE.clone().into_response();
     };
 
+    let record_failure = async |user_id, reason| {
+        audit::record(
+            &conn,
+            &meta,
+            user_id,
+            "token.refreshed",
+            Outcome::Failure,
+            Some(reason),
+        )
+        .await;
+    };
+
     // Check if JWT has been revoked
     let Ok(mut revoked_jwt_query) = conn
         .query(
@@ -49,11 +67,13 @@ pub async fn post(
 
     if revoked_jwt_row.is_some() {
         // Revoked token found
+        record_failure(None, "revoked").await;
         return (StatusCode::UNAUTHORIZED).into_response();
     }
 
     let Ok(token_data) = jwt::verify_refresh_token(&refresh_token) else {
         // Invalid refresh token
+        record_failure(None, "invalid_token").await;
         return (StatusCode::UNAUTHORIZED).into_response();
     };
     let claims = token_data.claims;
@@ -67,7 +87,7 @@ pub async fn post(
     // Query for up-to-date user data
     let Ok(mut query) = conn
         .query(
-            "SELECT username, display_name, email, email_verified_at FROM \"users\" WHERE id = ?",
+            "SELECT username, display_name, email, email_verified_at, sessions_revoked_at FROM \"users\" WHERE id = ?",
             params![user_id],
         )
         .await
@@ -80,18 +100,27 @@ pub async fn post(
 
     let Some(user) = user else {
         // May be invalid if user has been deleted off of database
+        record_failure(Some(user_id), "unknown_user").await;
         return (StatusCode::UNAUTHORIZED).into_response();
     };
     let db_username = user.get::<String>(0).unwrap();
     let db_display_name = user.get::<Option<String>>(1).unwrap();
     let db_email = user.get::<Option<String>>(2).unwrap();
     let db_email_verified = user.get::<Option<i64>>(3).unwrap();
+    let db_sessions_revoked_at = user.get::<Option<u64>>(4).unwrap();
+
+    if session::is_session_revoked(claims.iat, db_sessions_revoked_at) {
+        record_failure(Some(user_id), "session_revoked").await;
+        return (StatusCode::UNAUTHORIZED).into_response();
+    }
+
     let email_verified = match db_email_verified {
         Some(_) => Some(true),
         None if db_email.is_some() => Some(false),
         None => None,
     };
 
+    let issued_at = session::issued_at(db_sessions_revoked_at);
     let access_token = jwt::issue_access_token(
         user_id,
         &db_username,
@@ -99,6 +128,7 @@ pub async fn post(
         db_email.as_deref(),
         email_verified,
         claims.auth_time,
+        issued_at,
     )
     .to_string();
     let mut response_data = Map::new();
@@ -110,7 +140,7 @@ pub async fn post(
     // Generate & include new refresh token if token will expire in less than 1 day
     if current_time > refresh_token_expirity_time - Duration::from_secs(24 * 3600).as_secs() {
         let new_refresh_token =
-            jwt::issue_refresh_token(user_id, Some(claims.auth_time)).to_string();
+            jwt::issue_refresh_token(user_id, Some(claims.auth_time), issued_at).to_string();
         response_data.insert(
             "refresh_token".to_string(),
             Value::String(new_refresh_token),
@@ -119,5 +149,94 @@ pub async fn post(
 
     // All guards / checks above
     // Renew JWT
+    audit::record(
+        &conn,
+        &meta,
+        Some(user_id),
+        "token.refreshed",
+        Outcome::Success,
+        None,
+    )
+    .await;
     (StatusCode::OK, Json(response_data)).into_response()
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::response::Response;
+
+    use super::*;
+    use crate::common::test_state;
+
+    async fn refresh(state: &AppState, token: &str) -> Response {
+        post(
+            State(state.clone()),
+            RequestMeta::default(),
+            None,
+            token.to_string(),
+        )
+        .await
+        .into_response()
+    }
+
+    #[tokio::test]
+    async fn refreshes_with_refresh_token() {
+        let state = test_state().await;
+        state
+            .db
+            .connect()
+            .unwrap()
+            .execute(
+                "INSERT INTO \"users\" (id, username, username_canonical, password) VALUES (1, 'alice', 'alice', '')",
+                (),
+            )
+            .await
+            .unwrap();
+        let now = UNIX_EPOCH.elapsed().unwrap().as_secs() as usize;
+
+        let token = jwt::issue_refresh_token(1, None, now);
+        assert_eq!(refresh(&state, &token).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn refuses_other_tokens() {
+        let state = test_state().await;
+        state
+            .db
+            .connect()
+            .unwrap()
+            .execute(
+                "INSERT INTO \"users\" (id, username, username_canonical, password) VALUES (1, 'alice', 'alice', '')",
+                (),
+            )
+            .await
+            .unwrap();
+        let now = UNIX_EPOCH.elapsed().unwrap().as_secs() as usize;
+
+        // Only proves the password, the second factor is still to be given
+        let mfa_token = jwt::issue_mfa_token(1, now);
+        assert_eq!(
+            refresh(&state, &mfa_token).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        let access_token = jwt::issue_access_token(1, "alice", None, None, None, now, now);
+        assert_eq!(
+            refresh(&state, &access_token).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        let mut rows = state
+            .db
+            .connect()
+            .unwrap()
+            .query(
+                "SELECT COUNT(*) FROM \"auth_events\" WHERE event = 'token.refreshed' AND outcome = 'failure' AND reason = 'invalid_token'",
+                (),
+            )
+            .await
+            .unwrap();
+        let failures = rows.next().await.unwrap().unwrap().get::<u64>(0).unwrap();
+        assert_eq!(failures, 2);
+    }
+}
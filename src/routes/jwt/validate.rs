@@ -3,10 +3,9 @@ use axum_extra::{
     TypedHeader,
     headers::{Authorization, authorization::Bearer},
 };
-use libsql::params;
-use tracing::{instrument, warn};
+use tracing::instrument;
 
-use crate::{AppState, common::DATABASE_BUSY_RESPONSE, jwt};
+use crate::{AppState, common::DATABASE_BUSY_RESPONSE, session};
 
 #[instrument(skip(state, authorization, body))]
 pub async fn post(
@@ -26,32 +25,10 @@ pub async fn post(
         return DATABASE_BUSY_RESPONSE.clone().into_response();
     };
 
-    let Ok(mut query) = conn
-        .query(
-            "SELECT 1 FROM \"revoked_jwt\" WHERE token = ?",
-            params![access_token.clone()],
-        )
-        .await
-    else {
-        warn!("Unable to query for revoked JWT!");
-        return DATABASE_BUSY_RESPONSE.clone().into_response();
-    };
-
-    let Ok(row) = query.next().await else {
-        warn!("Unable to query for revoked JWT!");
-        return DATABASE_BUSY_RESPONSE.clone().into_response();
-    };
-
-    if row.is_some() {
-        return StatusCode::UNAUTHORIZED.into_response();
+    // Checks for revoked tokens & sessions alongside the signature
+    if let Err(response) = session::authenticate(&conn, &access_token).await {
+        return response;
     }
 
-    let Ok(token_data) = jwt::verify_access_token(&access_token) else {
-        return StatusCode::UNAUTHORIZED.into_response();
-    };
-
-    // TODO: Validate on last_logout_all time
-    // if token_data.claims.auth_time
-
     StatusCode::NO_CONTENT.into_response()
 }
@@ -0,0 +1,267 @@
+use std::{
+    sync::LazyLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    Json,
+    extract::FromRequestParts,
+    http::{StatusCode, request::Parts},
+    response::{IntoResponse, Response},
+};
+use axum_extra::{
+    TypedHeader,
+    headers::{Authorization, authorization::Bearer},
+};
+use libsql::{Connection, params};
+use minijinja::context;
+use serde_json::json;
+use tracing::{error, warn};
+
+use crate::{AppState, common::DATABASE_BUSY_RESPONSE, deletion, jwt, outbox, templates};
+
+/// Whether users must verify their email before being able to log in.
+/// Opt-in by setting the `REQUIRE_VERIFIED_EMAIL` environment variable.
+pub static REQUIRE_VERIFIED_EMAIL: LazyLock<bool> =
+    LazyLock::new(|| std::env::var("REQUIRE_VERIFIED_EMAIL").is_ok());
+/// Whether users are notified on their verified email every time they log in.
+/// Opt-in by setting the `NEW_LOGIN_ALERT` environment variable.
+static NEW_LOGIN_ALERT: LazyLock<bool> = LazyLock::new(|| std::env::var("NEW_LOGIN_ALERT").is_ok());
+
+/// Whether a token issued at `issued_at` belongs to a session that has since been revoked,
+/// e.g. because the password of the user has been changed
+///
+/// Tokens issued in the same second as the revocation are revoked as well, see `issued_at`
+pub fn is_session_revoked(issued_at: usize, sessions_revoked_at: Option<u64>) -> bool {
+    sessions_revoked_at.is_some_and(|revoked_at| (issued_at as u64) <= revoked_at)
+}
+
+/// Issue time of new tokens of the user (as UTC timestamp seconds)
+///
+/// Timestamps only have a precision of a second - tokens issued in the same second as a revocation (e.g. the ones
+/// handed back by `/auth/password`) are dated one second later, so that they are not revoked themselves
+pub fn issued_at(sessions_revoked_at: Option<u64>) -> usize {
+    let current_time = UNIX_EPOCH.elapsed().unwrap().as_secs();
+    sessions_revoked_at.map_or(current_time, |revoked_at| current_time.max(revoked_at + 1)) as usize
+}
+
+/// Resolves the user of a valid, non-revoked access token
+pub async fn authenticate(conn: &Connection, access_token: &str) -> Result<AuthUser, Response> {
+    let Ok(mut query) = conn
+        .query(
+            "SELECT 1 FROM \"revoked_jwt\" WHERE token = ?",
+            params![access_token],
+        )
+        .await
+    else {
+        warn!("Unable to query for revoked JWT!");
+        return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+    };
+
+    let Ok(row) = query.next().await else {
+        warn!("Unable to query for revoked JWT!");
+        return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+    };
+
+    if row.is_some() {
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let Ok(token_data) = jwt::verify_access_token(access_token) else {
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    };
+
+    let Ok(user_id) = token_data.claims.sub.parse::<u64>() else {
+        error!(
+            "Signed Access token contains an invalid user ID! Unless frontend is provided the same JWT key, JWT key has been compromised!"
+        );
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    };
+
+    let Ok(mut query) = conn
+        .query(
+            "SELECT sessions_revoked_at FROM \"users\" WHERE id = ?",
+            params![user_id],
+        )
+        .await
+    else {
+        warn!("Database query failed!");
+        return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+    };
+
+    let Ok(user) = query.next().await else {
+        warn!("Database query failed!");
+        return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+    };
+
+    // May be invalid if user has been deleted off of database
+    let Some(user) = user else {
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    };
+
+    if is_session_revoked(token_data.claims.iat, user.get::<Option<u64>>(0).unwrap()) {
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    Ok(AuthUser {
+        id: user_id,
+        auth_time: token_data.claims.auth_time,
+    })
+}
+
+/// Extracts the user authenticated by the `Authorization: Bearer <access token>` header
+/// Rejects with `401 Unauthorized` if the header is missing or the token is invalid / revoked
+pub struct AuthUser {
+    pub id: u64,
+    /// Time when the authentication of the session occurred (as UTC timestamp seconds)
+    pub auth_time: usize,
+}
+
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Ok(TypedHeader(Authorization(bearer))) =
+            TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state).await
+        else {
+            return Err(StatusCode::UNAUTHORIZED.into_response());
+        };
+
+        let Ok(conn) = state.db.connect() else {
+            warn!("Unable to connect to the database");
+            return Err(DATABASE_BUSY_RESPONSE.clone().into_response());
+        };
+
+        authenticate(&conn, bearer.token()).await
+    }
+}
+
+/// Issues a new access & refresh token pair for the given user and responds with both of them
+///
+/// `auth_time` is the time when the authentication occurred (as UTC timestamp seconds)
+pub async fn issue_tokens(conn: &Connection, user_id: u64, auth_time: usize) -> Response {
+    respond_with_tokens(conn, user_id, auth_time, *NEW_LOGIN_ALERT).await
+}
+
+/// Issues a fresh token pair for an already established session, e.g. after its other sessions got revoked
+/// Unlike `issue_tokens`, does not alert the user of a new login
+pub async fn reissue_tokens(conn: &Connection, user_id: u64, auth_time: usize) -> Response {
+    respond_with_tokens(conn, user_id, auth_time, false).await
+}
+
+async fn respond_with_tokens(
+    conn: &Connection,
+    user_id: u64,
+    auth_time: usize,
+    alert: bool,
+) -> Response {
+    let Ok(mut query) = conn
+        .query(
+            "SELECT username, display_name, email, email_verified_at, locale, deletion_requested_at, sessions_revoked_at FROM \"users\" WHERE id = ?",
+            params![user_id],
+        )
+        .await
+    else {
+        warn!("Database query failed!");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+
+    let Ok(Some(user)) = query.next().await else {
+        warn!("Unable to fetch user to issue tokens for");
+        return DATABASE_BUSY_RESPONSE.clone().into_response();
+    };
+    let db_username = user.get::<String>(0).unwrap();
+    let db_display_name = user.get::<Option<String>>(1).unwrap();
+    let db_email = user.get::<Option<String>>(2).unwrap();
+    let db_email_verified = user.get::<Option<i64>>(3).unwrap();
+    let db_locale = user.get::<Option<String>>(4).unwrap();
+    let db_deletion_requested_at = user.get::<Option<u64>>(5).unwrap();
+    let db_sessions_revoked_at = user.get::<Option<u64>>(6).unwrap();
+
+    if *REQUIRE_VERIFIED_EMAIL && db_email_verified.is_none() {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Email has not been verified - Please verify your email before logging in", "code": "email_not_verified" })),
+        )
+            .into_response();
+    }
+
+    let email_verified = match db_email_verified {
+        Some(_) => Some(true),
+        None if db_email.is_some() => Some(false),
+        None => None,
+    };
+
+    // Failing to alert should not prevent the user from logging in
+    if alert
+        && db_email_verified.is_some()
+        && let Some(email) = db_email.clone()
+    {
+        let message = templates::render(
+            templates::NEW_LOGIN,
+            db_locale.as_deref(),
+            email,
+            &context! {
+                username => db_username,
+                display_name => db_display_name,
+                login_time => httpdate::fmt_http_date(SystemTime::now()),
+            },
+        );
+
+        match message {
+            Ok(message) => {
+                if let Err(e) = outbox::enqueue_message(conn, &message).await {
+                    warn!("Unable to queue new login alert, {e}");
+                }
+            }
+            Err(e) => error!("Unable to render new login alert, {e}"),
+        }
+    }
+
+    let issued_at = issued_at(db_sessions_revoked_at);
+    let refresh_token = jwt::issue_refresh_token(user_id, Some(auth_time), issued_at);
+    let access_token = jwt::issue_access_token(
+        user_id,
+        &db_username,
+        db_display_name.as_deref(),
+        db_email.as_deref(),
+        email_verified,
+        auth_time,
+        issued_at,
+    );
+
+    let mut body = json!({
+        "refresh_token": refresh_token,
+        "access_token": access_token,
+    });
+    // Offer the user to cancel the deletion of their account, see `/auth/me/cancel_deletion`
+    if let Some(requested_at) = db_deletion_requested_at {
+        body["pending_deletion"] = json!({
+            "requested_at": requested_at,
+            "purge_at": deletion::purge_at(requested_at),
+        });
+    }
+
+    (StatusCode::OK, Json(body)).into_response()
+}
+
+/// Marks a single-use token (e.g. a ceremony token) as redeemed by revoking it.
+/// Returns `false` if the token has already been redeemed before.
+pub async fn redeem_token(conn: &Connection, token: &str) -> Result<bool, Response> {
+    match conn
+        .execute(
+            "INSERT OR IGNORE INTO \"revoked_jwt\" (token) VALUES (?)",
+            params![token],
+        )
+        .await
+    {
+        Ok(inserted) => Ok(inserted == 1),
+        Err(e) => {
+            warn!("Unable to redeem token, {e}");
+            Err(DATABASE_BUSY_RESPONSE.clone().into_response())
+        }
+    }
+}
@@ -0,0 +1,146 @@
+// Localized message templates
+// * Every message is made of `{locale}/{name}.subject`, `{locale}/{name}.txt` & an optional `{locale}/{name}.html`
+//   * Built-in English templates are found in `templates/`
+//   * Any of them may be overridden (or new locales added) by placing files in `TEMPLATE_DIRECTORY`
+// * Templates are rendered with minijinja; `.html` templates are auto-escaped
+// * The locale is picked from the user's `locale` column, falling back through
+//   `pt-br` -> `pt` -> `DEFAULT_LOCALE` -> `en`
+
+use std::{path::PathBuf, sync::LazyLock};
+
+use minijinja::{Environment, Error, ErrorKind, Value};
+use regex::Regex;
+
+use crate::notification::Message;
+
+/// Directory holding template overrides, laid out as `{locale}/{name}.{subject,txt,html}`
+static TEMPLATE_DIRECTORY: LazyLock<Option<PathBuf>> =
+    LazyLock::new(|| std::env::var("TEMPLATE_DIRECTORY").ok().map(PathBuf::from));
+/// Locale used for users without a (supported) locale
+static DEFAULT_LOCALE: LazyLock<String> = LazyLock::new(|| {
+    std::env::var("DEFAULT_LOCALE")
+        .map_or_else(|_| FALLBACK_LOCALE.to_string(), |l| l.to_lowercase())
+});
+/// Name of the application, available to every template as `app_name`
+static APP_NAME: LazyLock<String> =
+    LazyLock::new(|| std::env::var("APP_NAME").unwrap_or_else(|_| "picoauth".to_string()));
+
+/// BCP 47-ish language tag, e.g. `en`, `pt-BR` or `zh-Hant-TW`
+pub static LOCALE_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[A-Za-z]{2,3}([-_][A-Za-z0-9]{2,8}){0,3}$").unwrap());
+
+/// Locale of the built-in templates, always last in the fallback chain
+const FALLBACK_LOCALE: &str = "en";
+
+pub const PASSWORD_RESET: &str = "password_reset";
+pub const VERIFY_EMAIL: &str = "verify_email";
+pub const MAGIC_LINK: &str = "magic_link";
+pub const LOGIN_CODE: &str = "login_code";
+pub const NEW_LOGIN: &str = "new_login";
+
+macro_rules! builtin {
+    ($($name:literal),* $(,)?) => {
+        &[$(
+            (concat!("en/", $name, ".subject"), include_str!(concat!("../templates/en/", $name, ".subject"))),
+            (concat!("en/", $name, ".txt"), include_str!(concat!("../templates/en/", $name, ".txt"))),
+            (concat!("en/", $name, ".html"), include_str!(concat!("../templates/en/", $name, ".html"))),
+        )*]
+    };
+}
+
+const BUILTIN_TEMPLATES: &[(&str, &str)] = builtin!(
+    "password_reset",
+    "verify_email",
+    "magic_link",
+    "login_code",
+    "new_login",
+);
+
+static ENVIRONMENT: LazyLock<Environment<'static>> = LazyLock::new(|| {
+    let mut env = Environment::new();
+    env.add_global("app_name", APP_NAME.as_str());
+    env.set_loader(|name| {
+        if let Some(directory) = TEMPLATE_DIRECTORY.as_ref() {
+            match std::fs::read_to_string(directory.join(name)) {
+                Ok(source) => return Ok(Some(source)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(
+                        Error::new(ErrorKind::InvalidOperation, "Unable to read template")
+                            .with_source(e),
+                    );
+                }
+            }
+        }
+
+        Ok(BUILTIN_TEMPLATES
+            .iter()
+            .find(|(builtin, _)| *builtin == name)
+            .map(|(_, source)| (*source).to_string()))
+    });
+
+    env
+});
+
+/// Locales to look templates up in, most specific first
+fn locale_chain(locale: Option<&str>) -> Vec<String> {
+    let mut chain = Vec::new();
+
+    if let Some(locale) = locale.filter(|locale| LOCALE_REGEX.is_match(locale)) {
+        let locale = locale.to_lowercase().replace('_', "-");
+        let mut subtags: Vec<&str> = locale.split('-').collect();
+        while !subtags.is_empty() {
+            chain.push(subtags.join("-"));
+            subtags.pop();
+        }
+    }
+
+    for locale in [DEFAULT_LOCALE.as_str(), FALLBACK_LOCALE] {
+        if !chain.iter().any(|l| l == locale) {
+            chain.push(locale.to_string());
+        }
+    }
+
+    chain
+}
+
+/// Renders a template if it exists
+fn render_optional(name: &str, context: &Value) -> Result<Option<String>, Error> {
+    match ENVIRONMENT.get_template(name) {
+        Ok(template) => template.render(context).map(Some),
+        Err(e) if e.kind() == ErrorKind::TemplateNotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Renders the message `name` in the most appropriate locale for the recipient
+pub fn render(
+    name: &str,
+    locale: Option<&str>,
+    to: String,
+    context: &Value,
+) -> Result<Message, Error> {
+    for locale in locale_chain(locale) {
+        let Some(body) = render_optional(&format!("{locale}/{name}.txt"), context)? else {
+            continue;
+        };
+        let Some(subject) = render_optional(&format!("{locale}/{name}.subject"), context)? else {
+            return Err(Error::new(
+                ErrorKind::TemplateNotFound,
+                format!("Template `{locale}/{name}.subject` is missing"),
+            ));
+        };
+
+        return Ok(Message {
+            to,
+            subject: subject.trim().to_string(),
+            body,
+            html: render_optional(&format!("{locale}/{name}.html"), context)?,
+        });
+    }
+
+    Err(Error::new(
+        ErrorKind::TemplateNotFound,
+        format!("No `{name}` template found"),
+    ))
+}
@@ -0,0 +1,35 @@
+// Minimum response time
+// * Endpoints doing more work for existing accounts than for unknown ones leak which accounts exist through their
+//   response time
+// * `pad` is a middleware holding every response of a route back until a fixed deadline, hiding how long the work took
+//   * The deadline should be above the slowest path (e.g. a full Argon2 verify), otherwise it is not hidden
+//   * Work should still be kept similar on every path where possible, e.g. verifying a dummy hash for unknown users
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use tokio::time::Instant;
+
+/// Reads a minimum response time in milliseconds from the given environment variable
+///
+/// Panics if the variable is set but cannot be parsed
+pub fn from_env(name: &str, default: Duration) -> Duration {
+    std::env::var(name).map_or(default, |t| {
+        Duration::from_millis(t.parse().unwrap_or_else(|_| panic!("Invalid {name}")))
+    })
+}
+
+/// Middleware delaying the response until at least `minimum_time` has passed since the request came in
+///
+/// Used with `axum::middleware::from_fn_with_state(minimum_time, timing::pad)`
+pub async fn pad(State(minimum_time): State<Duration>, request: Request, next: Next) -> Response {
+    let deadline = Instant::now() + minimum_time;
+    let response = next.run(request).await;
+
+    tokio::time::sleep_until(deadline).await;
+    response
+}
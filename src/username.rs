@@ -0,0 +1,198 @@
+// Usernames
+// * By default, usernames are ASCII - `[a-zA-Z0-9_]{3,32}`
+// * Setting `UNICODE_USERNAMES` allows native-script usernames, following a PRECIS-like profile (RFC 8265):
+//   * Width-mapped & NFKC-normalized, e.g. `ｐａｙｐａｌ` is `paypal`
+//   * 3 to 32 characters, allowed for identifiers by UTS #39 - letters, marks, digits, `_` & `-`
+//   * At most highly restrictive mixing of scripts (UTS #39), e.g. Latin & Han is fine, Latin & Cyrillic is not
+//   * Rejected if confusable with an existing username, i.e. both share the same UTS #39 skeleton (`pаypal`
+//     with a Cyrillic `а` cannot coexist with `paypal`)
+// * Usernames are stored as entered (`username`) & in their canonical, case-folded form (`username_canonical`)
+//   * The canonical form is what is unique & looked up, making usernames case-insensitive in every script
+//   * The skeleton (`username_skeleton`) of users predating it is filled in at startup, see `backfill`
+
+use std::sync::LazyLock;
+
+use libsql::{Connection, Database, params};
+use regex::Regex;
+use tracing::{info, warn};
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::{GeneralSecurityProfile, RestrictionLevel, RestrictionLevelDetection};
+
+/// Whether native-script usernames are allowed.
+/// Opt-in by setting the `UNICODE_USERNAMES` environment variable.
+pub static UNICODE_USERNAMES: LazyLock<bool> =
+    LazyLock::new(|| std::env::var("UNICODE_USERNAMES").is_ok());
+
+pub static USERNAME_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[a-zA-Z0-9_]{3,32}$").unwrap());
+
+/// Length bounds of Unicode usernames, in characters
+const MIN_LENGTH: usize = 3;
+const MAX_LENGTH: usize = 32;
+
+pub struct Username {
+    /// As entered by the user (normalized), shown back to them
+    pub display: String,
+    /// Case-folded form, unique across users
+    pub canonical: String,
+    /// Confusable skeleton of the canonical form
+    pub skeleton: String,
+}
+
+/// Canonical form of a username, to be compared with `username_canonical`
+pub fn canonicalize(username: &str) -> String {
+    let normalized: String = username.nfkc().collect();
+    caseless::default_case_fold_str(&normalized)
+        .nfkc()
+        .collect()
+}
+
+/// UTS #39 skeleton of a canonical username - confusable usernames share the same skeleton
+pub fn skeleton(canonical: &str) -> String {
+    unicode_security::skeleton(canonical).collect()
+}
+
+fn is_allowed_char(c: char) -> bool {
+    c.identifier_allowed() && (c == '_' || c == '-' || !c.is_ascii_punctuation())
+}
+
+/// Validates a username against the configured profile
+pub fn parse(username: &str) -> Result<Username, &'static str> {
+    parse_as(username, *UNICODE_USERNAMES)
+}
+
+fn parse_as(username: &str, unicode: bool) -> Result<Username, &'static str> {
+    let display: String = username.nfkc().collect();
+
+    if unicode {
+        let length = display.chars().count();
+        if !(MIN_LENGTH..=MAX_LENGTH).contains(&length) {
+            return Err("Invalid username - Username must be 3 to 32 characters long");
+        }
+        if !display.chars().all(is_allowed_char) {
+            return Err(
+                "Invalid username - Username may only contain letters, digits, dashes (-) and underscores (_)",
+            );
+        }
+        if !display
+            .as_str()
+            .check_restriction_level(RestrictionLevel::HighlyRestrictive)
+        {
+            return Err("Invalid username - Username may not mix these scripts");
+        }
+    } else if !USERNAME_REGEX.is_match(username) {
+        return Err(
+            "Invalid username - Username may only contain 3 to 32 alphanumeric characters and underscores (_)",
+        );
+    }
+
+    let canonical = canonicalize(&display);
+    let skeleton = skeleton(&canonical);
+    Ok(Username {
+        display,
+        canonical,
+        skeleton,
+    })
+}
+
+/// Whether the username, or one confusable with it, is already taken
+pub async fn is_taken(conn: &Connection, username: &Username) -> Result<bool, libsql::Error> {
+    // ASCII usernames are only compared by their canonical form, as they have always been
+    let mut rows = conn
+        .query(
+            "SELECT COUNT(*) FROM \"users\" WHERE username_canonical = ? OR (? AND username_skeleton = ?)",
+            params![
+                username.canonical.clone(),
+                *UNICODE_USERNAMES,
+                username.skeleton.clone()
+            ],
+        )
+        .await?;
+
+    let count = match rows.next().await? {
+        Some(row) => row.get::<u64>(0)?,
+        None => 0,
+    };
+    Ok(count > 0)
+}
+
+/// Fills in the canonical form & skeleton of users that predate them
+pub async fn backfill(db: &Database) {
+    let conn = db.connect().expect("Unable to connect to database");
+
+    let mut users = Vec::new();
+    match conn
+        .query(
+            "SELECT id, username FROM \"users\" WHERE username_skeleton IS NULL",
+            (),
+        )
+        .await
+    {
+        Ok(mut rows) => {
+            while let Ok(Some(row)) = rows.next().await {
+                users.push((row.get::<u64>(0).unwrap(), row.get::<String>(1).unwrap()));
+            }
+        }
+        Err(e) => {
+            warn!("Unable to query users to backfill usernames of, {e}");
+            return;
+        }
+    }
+    if users.is_empty() {
+        return;
+    }
+
+    for (user_id, username) in &users {
+        let canonical = canonicalize(username);
+        if let Err(e) = conn
+            .execute(
+                "UPDATE \"users\" SET username_canonical = ?, username_skeleton = ? WHERE id = ?",
+                params![canonical.clone(), skeleton(&canonical), *user_id],
+            )
+            .await
+        {
+            warn!(user_id, "Unable to backfill canonical username, {e}");
+        }
+    }
+    info!("Backfilled canonical usernames of {} user(s)", users.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ascii_usernames() {
+        let username = parse_as("Alice_42", false).unwrap();
+        assert_eq!(username.display, "Alice_42");
+        assert_eq!(username.canonical, "alice_42");
+
+        assert!(parse_as("al", false).is_err());
+        assert!(parse_as(&"a".repeat(33), false).is_err());
+        assert!(parse_as("alice-42", false).is_err());
+        assert!(parse_as("алиса", false).is_err());
+    }
+
+    #[test]
+    fn parses_unicode_usernames() {
+        let username = parse_as("Ａｌｉｃｅ", true).unwrap();
+        assert_eq!(username.display, "Alice");
+        assert_eq!(username.canonical, "alice");
+
+        assert_eq!(parse_as("Straße", true).unwrap().canonical, "strasse");
+        assert!(parse_as("алиса", true).is_ok());
+        assert!(parse_as("alice-42", true).is_ok());
+        // Latin & Han is fine, Latin & Cyrillic is not
+        assert!(parse_as("alice山田", true).is_ok());
+        assert!(parse_as("pаypal", true).is_err());
+        assert!(parse_as("alice!", true).is_err());
+        assert!(parse_as("al", true).is_err());
+    }
+
+    #[test]
+    fn confusable_usernames_share_skeleton() {
+        assert_eq!(skeleton("paypal"), skeleton("pаypal"));
+        assert_eq!(skeleton("paypa1"), skeleton("paypal"));
+        assert_ne!(skeleton("paypal"), skeleton("alice"));
+    }
+}
@@ -0,0 +1,538 @@
+// WebAuthn ceremony verification
+// * Only ES256 (ECDSA P-256 w/ SHA-256) credentials are supported - this covers virtually every passkey & FIDO2 key
+// * Attestation statements are NOT verified (we request `attestation: "none"`); we trust the credential on first use
+// * Challenges are carried inside a signed ceremony token (see `jwt::issue_ceremony_token`) to stay stateless
+
+use std::sync::LazyLock;
+
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use ciborium::Value;
+use p256::ecdsa::{Signature, VerifyingKey, signature::Verifier};
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// Relying party ID - the domain (or a registrable suffix of it) the frontend is served from
+pub static RP_ID: LazyLock<String> =
+    LazyLock::new(|| std::env::var("WEBAUTHN_RP_ID").unwrap_or_else(|_| "localhost".to_string()));
+/// Human-readable relying party name, shown by some authenticators
+pub static RP_NAME: LazyLock<String> =
+    LazyLock::new(|| std::env::var("WEBAUTHN_RP_NAME").unwrap_or_else(|_| "picoauth".to_string()));
+/// Comma-separated list of origins that are allowed to perform WebAuthn ceremonies
+pub static ALLOWED_ORIGINS: LazyLock<Vec<String>> = LazyLock::new(|| {
+    std::env::var("WEBAUTHN_ORIGINS")
+        .unwrap_or_else(|_| "http://localhost:3000".to_string())
+        .split(',')
+        .map(|origin| origin.trim().to_string())
+        .filter(|origin| !origin.is_empty())
+        .collect()
+});
+
+/// Ceremony token type for credential registration
+pub const REGISTRATION_CEREMONY: &str = "WebauthnRegistration";
+/// Ceremony token type for credential assertion (login)
+pub const AUTHENTICATION_CEREMONY: &str = "WebauthnAuthentication";
+
+/// COSE algorithm identifier for ES256
+pub const COSE_ALG_ES256: i64 = -7;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Response could not be decoded / parsed
+    Malformed,
+    /// `clientDataJSON.type` does not match the ceremony
+    CeremonyMismatch,
+    /// `clientDataJSON.challenge` does not match the issued challenge
+    ChallengeMismatch,
+    /// `clientDataJSON.origin` is not in the allowed origins
+    OriginMismatch,
+    /// Authenticator data was produced for a different relying party
+    RpIdMismatch,
+    UserNotPresent,
+    UserNotVerified,
+    /// Credential public key is not an ES256 key
+    UnsupportedKey,
+    InvalidSignature,
+    /// Signature counter did not increase - the authenticator might have been cloned
+    SignCountRegression,
+}
+
+/// `PublicKeyCredential` returned by `navigator.credentials.create()`, JSON-serialized by the client
+#[derive(Deserialize)]
+pub struct RegistrationResponse {
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Deserialize)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+}
+
+/// `PublicKeyCredential` returned by `navigator.credentials.get()`, JSON-serialized by the client
+#[derive(Deserialize)]
+pub struct AuthenticationResponse {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Deserialize)]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String,
+    #[serde(rename = "userHandle")]
+    pub user_handle: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CollectedClientData {
+    #[serde(rename = "type")]
+    typ: String,
+    challenge: String,
+    origin: String,
+}
+
+struct AuthenticatorData<'a> {
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    sign_count: u32,
+    attested_credential_data: &'a [u8],
+}
+
+/// A credential that has passed the registration ceremony
+pub struct RegisteredCredential {
+    /// Credential ID (base64url)
+    pub id: String,
+    /// SEC1-encoded (uncompressed) P-256 public key
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+pub fn generate_challenge() -> String {
+    // Initialize new RNG every time function gets called
+    // This hopefully ensures that forward secrecy is maintained
+    let mut rng = rand::rngs::StdRng::from_os_rng();
+    let challenge: [u8; 32] = rng.random();
+
+    BASE64_URL_SAFE_NO_PAD.encode(challenge)
+}
+
+/// Encodes a user ID into a WebAuthn user handle
+pub fn user_handle(user_id: u64) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(user_id.to_be_bytes())
+}
+
+/// Decodes a WebAuthn user handle back into a user ID
+pub fn user_id_from_handle(handle: &str) -> Option<u64> {
+    let bytes = decode(handle).ok()?;
+    Some(u64::from_be_bytes(bytes.try_into().ok()?))
+}
+
+fn decode(data: &str) -> Result<Vec<u8>, Error> {
+    // Some clients pad their base64url output, strip it before decoding
+    BASE64_URL_SAFE_NO_PAD
+        .decode(data.trim_end_matches('='))
+        .map_err(|_| Error::Malformed)
+}
+
+fn verify_client_data(
+    client_data_json: &[u8],
+    ceremony: &str,
+    challenge: &str,
+) -> Result<(), Error> {
+    let client_data: CollectedClientData =
+        serde_json::from_slice(client_data_json).map_err(|_| Error::Malformed)?;
+
+    if client_data.typ != ceremony {
+        return Err(Error::CeremonyMismatch);
+    }
+    if decode(&client_data.challenge)? != decode(challenge)? {
+        return Err(Error::ChallengeMismatch);
+    }
+    if !ALLOWED_ORIGINS.contains(&client_data.origin) {
+        return Err(Error::OriginMismatch);
+    }
+
+    Ok(())
+}
+
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData<'_>, Error> {
+    // rpIdHash (32) | flags (1) | signCount (4) | attestedCredentialData / extensions (variable)
+    if data.len() < 37 {
+        return Err(Error::Malformed);
+    }
+
+    let auth_data = AuthenticatorData {
+        rp_id_hash: &data[..32],
+        flags: data[32],
+        sign_count: u32::from_be_bytes(data[33..37].try_into().unwrap()),
+        attested_credential_data: &data[37..],
+    };
+
+    if auth_data.rp_id_hash != Sha256::digest(RP_ID.as_bytes()).as_slice() {
+        return Err(Error::RpIdMismatch);
+    }
+    if auth_data.flags & FLAG_USER_PRESENT == 0 {
+        return Err(Error::UserNotPresent);
+    }
+
+    Ok(auth_data)
+}
+
+fn cose_field(map: &[(Value, Value)], key: i64) -> Option<&Value> {
+    map.iter()
+        .find(|(k, _)| {
+            k.as_integer()
+                .is_some_and(|k| i128::from(k) == i128::from(key))
+        })
+        .map(|(_, v)| v)
+}
+
+/// Converts a COSE_Key into a SEC1-encoded public key
+fn parse_cose_key(cose_key: &[u8]) -> Result<Vec<u8>, Error> {
+    let key: Value = ciborium::from_reader(cose_key).map_err(|_| Error::Malformed)?;
+    let map = key.as_map().ok_or(Error::Malformed)?;
+
+    let int_field = |k| {
+        cose_field(map, k)
+            .and_then(Value::as_integer)
+            .map(i128::from)
+    };
+    let bytes_field = |k| cose_field(map, k).and_then(Value::as_bytes);
+
+    // kty = EC2, alg = ES256, crv = P-256
+    if int_field(1) != Some(2)
+        || int_field(3) != Some(COSE_ALG_ES256.into())
+        || int_field(-1) != Some(1)
+    {
+        return Err(Error::UnsupportedKey);
+    }
+
+    let (Some(x), Some(y)) = (bytes_field(-2), bytes_field(-3)) else {
+        return Err(Error::Malformed);
+    };
+
+    let mut public_key = Vec::with_capacity(65);
+    public_key.push(0x04);
+    public_key.extend_from_slice(x);
+    public_key.extend_from_slice(y);
+
+    // Make sure the point is actually on the curve
+    VerifyingKey::from_sec1_bytes(&public_key).map_err(|_| Error::UnsupportedKey)?;
+
+    Ok(public_key)
+}
+
+/// Verifies the response of a registration ceremony against the issued challenge
+pub fn verify_registration(
+    response: &RegistrationResponse,
+    challenge: &str,
+) -> Result<RegisteredCredential, Error> {
+    let client_data_json = decode(&response.response.client_data_json)?;
+    verify_client_data(&client_data_json, "webauthn.create", challenge)?;
+
+    let attestation_object = decode(&response.response.attestation_object)?;
+    let attestation: Value =
+        ciborium::from_reader(attestation_object.as_slice()).map_err(|_| Error::Malformed)?;
+    let auth_data = attestation
+        .as_map()
+        .and_then(|map| {
+            map.iter()
+                .find(|(k, _)| k.as_text() == Some("authData"))
+                .and_then(|(_, v)| v.as_bytes())
+        })
+        .ok_or(Error::Malformed)?;
+
+    let auth_data = parse_authenticator_data(auth_data)?;
+    if auth_data.flags & FLAG_ATTESTED_CREDENTIAL_DATA == 0 {
+        return Err(Error::Malformed);
+    }
+
+    // aaguid (16) | credentialIdLength (2) | credentialId (variable) | credentialPublicKey (COSE)
+    let attested = auth_data.attested_credential_data;
+    if attested.len() < 18 {
+        return Err(Error::Malformed);
+    }
+    let credential_id_length = u16::from_be_bytes([attested[16], attested[17]]) as usize;
+    let Some(credential_id) = attested.get(18..18 + credential_id_length) else {
+        return Err(Error::Malformed);
+    };
+    if decode(&response.id)? != credential_id {
+        return Err(Error::Malformed);
+    }
+
+    let public_key = parse_cose_key(&attested[18 + credential_id_length..])?;
+
+    Ok(RegisteredCredential {
+        id: BASE64_URL_SAFE_NO_PAD.encode(credential_id),
+        public_key,
+        sign_count: auth_data.sign_count,
+    })
+}
+
+/// Verifies the response of an authentication ceremony against the issued challenge and
+/// the stored credential. Returns the new signature counter to be stored.
+pub fn verify_assertion(
+    response: &AuthenticationResponse,
+    challenge: &str,
+    public_key: &[u8],
+    stored_sign_count: u32,
+    require_user_verification: bool,
+) -> Result<u32, Error> {
+    let client_data_json = decode(&response.response.client_data_json)?;
+    verify_client_data(&client_data_json, "webauthn.get", challenge)?;
+
+    let raw_auth_data = decode(&response.response.authenticator_data)?;
+    let auth_data = parse_authenticator_data(&raw_auth_data)?;
+    if require_user_verification && auth_data.flags & FLAG_USER_VERIFIED == 0 {
+        return Err(Error::UserNotVerified);
+    }
+
+    // Signature is made over `authenticatorData || SHA-256(clientDataJSON)`
+    let verifying_key =
+        VerifyingKey::from_sec1_bytes(public_key).map_err(|_| Error::UnsupportedKey)?;
+    let signature = Signature::from_der(&decode(&response.response.signature)?)
+        .map_err(|_| Error::InvalidSignature)?;
+    let mut signed_data = raw_auth_data.clone();
+    signed_data.extend_from_slice(&Sha256::digest(&client_data_json));
+
+    verifying_key
+        .verify(&signed_data, &signature)
+        .map_err(|_| Error::InvalidSignature)?;
+
+    // Authenticators that do not implement a counter (most passkeys) always return 0
+    if (auth_data.sign_count != 0 || stored_sign_count != 0)
+        && auth_data.sign_count <= stored_sign_count
+    {
+        return Err(Error::SignCountRegression);
+    }
+
+    Ok(auth_data.sign_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use p256::ecdsa::{SigningKey, signature::Signer};
+    use serde_json::json;
+
+    use super::*;
+
+    const CHALLENGE: &str = "dGVzdC1jaGFsbGVuZ2UtMzItYnl0ZXMtbG9uZy4uLi4";
+    const CREDENTIAL_ID: &[u8] = b"software-authenticator";
+
+    /// Software authenticator holding a single ES256 credential
+    struct Authenticator {
+        key: SigningKey,
+        rp_id: String,
+    }
+
+    impl Authenticator {
+        fn new() -> Self {
+            Self {
+                key: SigningKey::from_slice(&[7; 32]).unwrap(),
+                rp_id: RP_ID.clone(),
+            }
+        }
+
+        fn client_data(ceremony: &str) -> Vec<u8> {
+            serde_json::to_vec(&json!({
+                "type": ceremony,
+                "challenge": CHALLENGE,
+                "origin": ALLOWED_ORIGINS[0],
+            }))
+            .unwrap()
+        }
+
+        fn authenticator_data(&self, flags: u8, sign_count: u32) -> Vec<u8> {
+            let mut data = Sha256::digest(self.rp_id.as_bytes()).to_vec();
+            data.push(flags);
+            data.extend_from_slice(&sign_count.to_be_bytes());
+            data
+        }
+
+        fn cose_key(&self) -> Vec<u8> {
+            let point = self.key.verifying_key().to_encoded_point(false);
+            let key = Value::Map(vec![
+                (Value::from(1), Value::from(2)),
+                (Value::from(3), Value::from(COSE_ALG_ES256)),
+                (Value::from(-1), Value::from(1)),
+                (Value::from(-2), Value::from(point.x().unwrap().to_vec())),
+                (Value::from(-3), Value::from(point.y().unwrap().to_vec())),
+            ]);
+            let mut cose_key = Vec::new();
+            ciborium::into_writer(&key, &mut cose_key).unwrap();
+            cose_key
+        }
+
+        fn register(&self) -> RegistrationResponse {
+            let mut auth_data =
+                self.authenticator_data(FLAG_USER_PRESENT | FLAG_ATTESTED_CREDENTIAL_DATA, 0);
+            auth_data.extend_from_slice(&[0; 16]);
+            auth_data.extend_from_slice(&u16::try_from(CREDENTIAL_ID.len()).unwrap().to_be_bytes());
+            auth_data.extend_from_slice(CREDENTIAL_ID);
+            auth_data.extend_from_slice(&self.cose_key());
+
+            let attestation = Value::Map(vec![
+                (Value::from("fmt"), Value::from("none")),
+                (Value::from("attStmt"), Value::Map(Vec::new())),
+                (Value::from("authData"), Value::from(auth_data)),
+            ]);
+            let mut attestation_object = Vec::new();
+            ciborium::into_writer(&attestation, &mut attestation_object).unwrap();
+
+            RegistrationResponse {
+                id: BASE64_URL_SAFE_NO_PAD.encode(CREDENTIAL_ID),
+                response: AttestationResponse {
+                    client_data_json: BASE64_URL_SAFE_NO_PAD
+                        .encode(Self::client_data("webauthn.create")),
+                    attestation_object: BASE64_URL_SAFE_NO_PAD.encode(attestation_object),
+                },
+            }
+        }
+
+        fn assert(&self, sign_count: u32) -> AuthenticationResponse {
+            let client_data_json = Self::client_data("webauthn.get");
+            let auth_data =
+                self.authenticator_data(FLAG_USER_PRESENT | FLAG_USER_VERIFIED, sign_count);
+
+            let mut signed_data = auth_data.clone();
+            signed_data.extend_from_slice(&Sha256::digest(&client_data_json));
+            let signature: Signature = self.key.sign(&signed_data);
+
+            AuthenticationResponse {
+                id: BASE64_URL_SAFE_NO_PAD.encode(CREDENTIAL_ID),
+                response: AssertionResponse {
+                    client_data_json: BASE64_URL_SAFE_NO_PAD.encode(client_data_json),
+                    authenticator_data: BASE64_URL_SAFE_NO_PAD.encode(auth_data),
+                    signature: BASE64_URL_SAFE_NO_PAD.encode(signature.to_der()),
+                    user_handle: None,
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn registers_and_asserts() {
+        let authenticator = Authenticator::new();
+        let credential = verify_registration(&authenticator.register(), CHALLENGE).unwrap();
+        assert_eq!(credential.id, BASE64_URL_SAFE_NO_PAD.encode(CREDENTIAL_ID));
+        assert_eq!(credential.sign_count, 0);
+
+        let sign_count = verify_assertion(
+            &authenticator.assert(1),
+            CHALLENGE,
+            &credential.public_key,
+            credential.sign_count,
+            true,
+        )
+        .unwrap();
+        assert_eq!(sign_count, 1);
+    }
+
+    #[test]
+    fn rejects_other_challenge() {
+        let authenticator = Authenticator::new();
+        let other_challenge = generate_challenge();
+        assert!(matches!(
+            verify_registration(&authenticator.register(), &other_challenge),
+            Err(Error::ChallengeMismatch)
+        ));
+
+        let credential = verify_registration(&authenticator.register(), CHALLENGE).unwrap();
+        assert!(matches!(
+            verify_assertion(
+                &authenticator.assert(1),
+                &other_challenge,
+                &credential.public_key,
+                0,
+                true
+            ),
+            Err(Error::ChallengeMismatch)
+        ));
+    }
+
+    #[test]
+    fn rejects_sign_count_regression() {
+        let authenticator = Authenticator::new();
+        let credential = verify_registration(&authenticator.register(), CHALLENGE).unwrap();
+
+        for sign_count in [4, 5] {
+            assert!(matches!(
+                verify_assertion(
+                    &authenticator.assert(sign_count),
+                    CHALLENGE,
+                    &credential.public_key,
+                    5,
+                    true
+                ),
+                Err(Error::SignCountRegression)
+            ));
+        }
+
+        // Authenticators without a counter always report 0
+        assert_eq!(
+            verify_assertion(
+                &authenticator.assert(0),
+                CHALLENGE,
+                &credential.public_key,
+                0,
+                true
+            )
+            .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn rejects_other_rp_id() {
+        let mut authenticator = Authenticator::new();
+        let credential = verify_registration(&authenticator.register(), CHALLENGE).unwrap();
+
+        authenticator.rp_id = "evil.example".to_string();
+        assert!(matches!(
+            verify_registration(&authenticator.register(), CHALLENGE),
+            Err(Error::RpIdMismatch)
+        ));
+        assert!(matches!(
+            verify_assertion(
+                &authenticator.assert(1),
+                CHALLENGE,
+                &credential.public_key,
+                0,
+                true
+            ),
+            Err(Error::RpIdMismatch)
+        ));
+    }
+
+    #[test]
+    fn rejects_signature_of_other_key() {
+        let authenticator = Authenticator::new();
+        let other = Authenticator {
+            key: SigningKey::from_slice(&[9; 32]).unwrap(),
+            rp_id: RP_ID.clone(),
+        };
+        let credential = verify_registration(&other.register(), CHALLENGE).unwrap();
+
+        assert!(matches!(
+            verify_assertion(
+                &authenticator.assert(1),
+                CHALLENGE,
+                &credential.public_key,
+                0,
+                true
+            ),
+            Err(Error::InvalidSignature)
+        ));
+    }
+}
@@ -0,0 +1,134 @@
+// Webhook notifications for auth events
+// * Endpoints are configured with `WEBHOOK_URLS` (comma-separated), signed with `WEBHOOK_SECRET`
+// * Every request carries:
+//   * `X-Picoauth-Event` - Event type, e.g. `user.registered`
+//   * `X-Picoauth-Delivery` - Unique ID of the event, receivers may use it to de-duplicate deliveries
+//   * `X-Picoauth-Timestamp` - UTC timestamp seconds of when the request has been signed
+//   * `X-Picoauth-Signature` - `sha256=<hex HMAC-SHA256 of "{timestamp}.{body}" using WEBHOOK_SECRET>`
+// * Deliveries go through the outbox (see `outbox.rs`) & are retried on failure - receivers may see the same delivery twice
+// * Receivers SHOULD verify the signature (in constant time) & reject timestamps that are too old to prevent replays
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::Sha256;
+use tracing::info;
+
+use crate::common::{generate_url_token, to_hex};
+
+/// Maximum amount of time to wait for a webhook receiver to respond
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An auth event to be delivered to webhook receivers
+pub struct Event {
+    /// Event type, e.g. `user.registered`
+    pub kind: &'static str,
+    pub data: Value,
+}
+
+impl Event {
+    pub const fn new(kind: &'static str, data: Value) -> Self {
+        Self { kind, data }
+    }
+}
+
+/// An event serialized for a single endpoint.
+/// Retries of the same delivery keep the same body & ID.
+#[derive(Serialize, Deserialize)]
+pub struct Delivery {
+    pub endpoint: String,
+    /// Event type, e.g. `user.registered`
+    pub event: String,
+    pub id: String,
+    pub body: String,
+}
+
+pub struct Webhooks {
+    client: reqwest::Client,
+    endpoints: Vec<String>,
+    secret: Vec<u8>,
+}
+
+impl Webhooks {
+    /// Configures webhooks through environment variables.
+    /// Events are silently ignored if no `WEBHOOK_URLS` is provided.
+    pub fn from_env() -> Self {
+        let endpoints: Vec<String> = std::env::var("WEBHOOK_URLS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .collect();
+
+        let secret = if endpoints.is_empty() {
+            Vec::new()
+        } else {
+            info!("Delivering auth events to {} webhook(s)", endpoints.len());
+            std::env::var("WEBHOOK_SECRET")
+                .expect("No WEBHOOK_SECRET provided while WEBHOOK_URLS is set!")
+                .into_bytes()
+        };
+
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()
+                .expect("Unable to create webhook HTTP client"),
+            endpoints,
+            secret,
+        }
+    }
+
+    /// Signs `{timestamp}.{body}` with the webhook secret
+    pub fn sign(&self, timestamp: u64, body: &str) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(format!("{timestamp}.{body}").as_bytes());
+
+        format!("sha256={}", to_hex(&mac.finalize().into_bytes()))
+    }
+
+    /// Prepares the deliveries of an event, one for each configured endpoint.
+    /// Deliveries are stored in the outbox & sent by its worker, see `outbox::enqueue_event`.
+    pub fn deliveries(&self, event: &Event) -> Vec<Delivery> {
+        let id = generate_url_token();
+        let body = json!({
+            "id": id,
+            "type": event.kind,
+            "created_at": UNIX_EPOCH.elapsed().unwrap().as_secs(),
+            "data": event.data,
+        })
+        .to_string();
+
+        self.endpoints
+            .iter()
+            .map(|endpoint| Delivery {
+                endpoint: endpoint.clone(),
+                event: event.kind.to_string(),
+                id: id.clone(),
+                body: body.clone(),
+            })
+            .collect()
+    }
+
+    /// Sends a single delivery, signing it at the time of sending
+    pub async fn deliver(&self, delivery: &Delivery) -> Result<(), reqwest::Error> {
+        let timestamp = UNIX_EPOCH.elapsed().unwrap().as_secs();
+
+        self.client
+            .post(&delivery.endpoint)
+            .header("Content-Type", "application/json")
+            .header("X-Picoauth-Event", &delivery.event)
+            .header("X-Picoauth-Delivery", &delivery.id)
+            .header("X-Picoauth-Timestamp", timestamp)
+            .header("X-Picoauth-Signature", self.sign(timestamp, &delivery.body))
+            .body(delivery.body.clone())
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}